@@ -0,0 +1,322 @@
+//! Capture of the packets entering and leaving the mangler to a pcapng file
+
+use core::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Direction;
+use crate::sink::FileSink;
+
+/// Block type of the section header block
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+
+/// Block type of the interface description block
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+
+/// Block type of the enhanced packet block
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+
+/// Link type of raw IPv4 and IPv6 packets, without a link layer header
+pub(crate) const LINKTYPE_RAW: u16 = 101;
+
+/// Option code of a comment, valid in every block
+const OPT_COMMENT: u16 = 1;
+
+/// Option code of the name of the application that wrote the file, in the section header block
+const SHB_USERAPPL: u16 = 4;
+
+/// Option code of the name of an interface
+const IF_NAME: u16 = 2;
+
+/// Option code of the flags of an enhanced packet block, which hold the direction of the packet
+const EPB_FLAGS: u16 = 2;
+
+/// The [EPB_FLAGS] of packets entering the mangler
+const EPB_INBOUND: u32 = 0b01;
+
+/// The [EPB_FLAGS] of packets leaving the mangler
+const EPB_OUTBOUND: u32 = 0b10;
+
+/// Writes every packet entering and leaving the mangler to a pcapng file, with synthesized IP and UDP headers.
+/// Packets entering the mangler are annotated with a comment on what the mangler did to them
+#[derive(Debug)]
+pub(crate) struct Capture {
+    /// The file being written
+    writer: FileSink,
+
+    /// The address the mangler listens on for clients
+    listen: SocketAddr,
+
+    /// The address of the forward target
+    forward: SocketAddr,
+
+    /// The local address of the session of every client
+    sessions: Mutex<HashMap<SocketAddr, SocketAddr>>,
+
+    /// A moment in both system time and monotonic time, to convert between the two
+    epoch: (SystemTime, Instant),
+}
+
+impl Capture {
+    /// Creates the capture file at `path`, for a mangler listening on `listen` and forwarding to `forward`
+    pub(crate) fn create(
+        path: &Path,
+        listen: SocketAddr,
+        forward: SocketAddr,
+    ) -> std::io::Result<Self> {
+        let writer = FileSink::create(path, "capture", |mut writer| {
+            let mut header = Vec::new();
+            header.extend_from_slice(&0x1A2B_3C4D_u32.to_le_bytes());
+            header.extend_from_slice(&1_u16.to_le_bytes());
+            header.extend_from_slice(&0_u16.to_le_bytes());
+            // The length of the section is not known up front
+            header.extend_from_slice(&(-1_i64).to_le_bytes());
+            push_option(
+                &mut header,
+                SHB_USERAPPL,
+                concat!("udp_mangler ", env!("CARGO_PKG_VERSION")).as_bytes(),
+            );
+            push_end_of_options(&mut header);
+            write_block(&mut writer, SECTION_HEADER_BLOCK, &header)?;
+
+            let mut interface = Vec::new();
+            interface.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            interface.extend_from_slice(&0_u16.to_le_bytes());
+            // No limit on the captured size of packets
+            interface.extend_from_slice(&0_u32.to_le_bytes());
+            push_option(&mut interface, IF_NAME, b"udp_mangler");
+            push_end_of_options(&mut interface);
+            write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, &interface)?;
+
+            Ok(writer)
+        })?;
+
+        Ok(Self {
+            writer,
+            listen,
+            forward,
+            sessions: Mutex::new(HashMap::new()),
+            epoch: (SystemTime::now(), Instant::now()),
+        })
+    }
+
+    /// Registers the local address of the session of a client, which is the source of its upstream
+    /// packets to the forward target
+    pub(crate) fn session_opened(&self, client: SocketAddr, local: SocketAddr) {
+        self.sessions.lock().unwrap().insert(client, local);
+    }
+
+    /// Forgets the session of a client
+    pub(crate) fn session_closed(&self, client: SocketAddr) {
+        self.sessions.lock().unwrap().remove(&client);
+    }
+
+    /// Writes a packet of `client` that entered the mangler at `at`
+    pub(crate) fn received(
+        &self,
+        content: &[u8],
+        client: SocketAddr,
+        direction: Direction,
+        at: Instant,
+        comment: &str,
+    ) {
+        let (src, dst) = match direction {
+            Direction::Upstream => (client, self.listen),
+            Direction::Downstream => (self.forward, self.session(client)),
+        };
+
+        self.write(content, src, dst, at, EPB_INBOUND, Some(comment));
+    }
+
+    /// Writes a packet for `client` that left the mangler at `at`
+    pub(crate) fn sent(
+        &self,
+        content: &[u8],
+        client: SocketAddr,
+        direction: Direction,
+        at: Instant,
+    ) {
+        let (src, dst) = match direction {
+            Direction::Upstream => (self.session(client), self.forward),
+            Direction::Downstream => (self.listen, client),
+        };
+
+        self.write(content, src, dst, at, EPB_OUTBOUND, None);
+    }
+
+    /// Writes everything that is buffered to the file
+    pub(crate) fn flush(&self) {
+        self.writer.flush();
+    }
+
+    /// Returns the local address of the session of a client, or an unspecified address if it is not known
+    fn session(&self, client: SocketAddr) -> SocketAddr {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&client)
+            .copied()
+            .unwrap_or_else(|| SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+    }
+
+    /// Writes a single packet as an enhanced packet block
+    fn write(
+        &self,
+        content: &[u8],
+        src: SocketAddr,
+        dst: SocketAddr,
+        at: Instant,
+        flags: u32,
+        comment: Option<&str>,
+    ) {
+        let packet = ip_packet(content, src, dst);
+
+        let time = self.epoch.0 + at.saturating_duration_since(self.epoch.1)
+            - self.epoch.1.saturating_duration_since(at);
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut block = Vec::with_capacity(packet.len() + 64);
+        block.extend_from_slice(&0_u32.to_le_bytes());
+        block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(micros as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&packet);
+        pad(&mut block);
+        push_option(&mut block, EPB_FLAGS, &flags.to_le_bytes());
+        if let Some(comment) = comment {
+            push_option(&mut block, OPT_COMMENT, comment.as_bytes());
+        }
+        push_end_of_options(&mut block);
+
+        self.writer
+            .write(|writer| write_block(writer, ENHANCED_PACKET_BLOCK, &block));
+    }
+}
+
+/// Writes a block with the given type and body, which must be padded to 32 bits
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+/// Appends an option to the body of a block
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Appends the option that ends the list of options
+fn push_end_of_options(body: &mut Vec<u8>) {
+    body.extend_from_slice(&[0; 4]);
+}
+
+/// Pads the body of a block to 32 bits
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// Wraps a payload in UDP and IP headers. If both addresses are IPv4 this is an IPv4 packet,
+/// otherwise it is an IPv6 packet with any IPv4 address mapped to IPv6. A payload that does not
+/// fit in a single IP packet is truncated
+pub(crate) fn ip_packet(mut content: &[u8], src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // The length of an IPv4 packet includes its header, while the length of an IPv6 packet does not
+    let max_udp_len = if src.ip().to_canonical().is_ipv4() && dst.ip().to_canonical().is_ipv4() {
+        u16::MAX - 20
+    } else {
+        u16::MAX
+    };
+
+    let udp_len = match u16::try_from(content.len() + 8) {
+        Ok(udp_len) if udp_len <= max_udp_len => udp_len,
+        _ => {
+            log::warn!(
+                "Truncating packet of {} bytes, it does not fit in a single IP packet",
+                content.len()
+            );
+            content = &content[..usize::from(max_udp_len) - 8];
+            max_udp_len
+        }
+    };
+
+    let mut packet = match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&(udp_len + 20).to_be_bytes());
+            // ID, don't fragment, time to live and protocol
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+
+            let checksum = !sum_words(&header, 0);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            // Next header and hop limit
+            header.extend_from_slice(&[17, 64]);
+            header.extend_from_slice(&to_v6(src_ip).octets());
+            header.extend_from_slice(&to_v6(dst_ip).octets());
+            header
+        }
+    };
+
+    let udp_start = packet.len();
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(content);
+
+    // The checksum covers a pseudo header of the addresses, the protocol and the length
+    let addresses = if packet[0] >> 4 == 4 {
+        &packet[12..20]
+    } else {
+        &packet[8..40]
+    };
+    let pseudo_header = sum_words(addresses, u32::from(udp_len) + 17);
+    let checksum = match !sum_words(&packet[udp_start..], u32::from(pseudo_header)) {
+        // A checksum of zero means there is no checksum, so it is sent as all ones instead
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    packet[udp_start + 6..udp_start + 8].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Adds up the 16 bit words of `data` to `initial` in ones' complement arithmetic
+fn sum_words(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+
+    for word in data.chunks(2) {
+        let high = u32::from(word[0]) << 8;
+        let low = word.get(1).copied().map_or(0, u32::from);
+        sum += high | low;
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
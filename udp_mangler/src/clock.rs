@@ -0,0 +1,68 @@
+//! Sources of the current time, so that time can be controlled from the outside
+
+use core::fmt::Debug;
+use core::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A source of the current time for the [Mangler](crate::Mangler), passed to
+/// [Mangler::with_clock](crate::Mangler::with_clock). The arrival of every packet and the moment it is
+/// due are taken from the clock, as are the session timeouts, the statistics, and the playback of scenarios,
+/// network models and recorded traffic. Waiting for packets still happens in real time, and a clock that is
+/// moved forward by hand is noticed within 100 ms.
+///
+/// The clock is shared by all threads of the mangler
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time. This must never go backwards
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, which is the clock used by [Mangler::new](crate::Mangler::new)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is told to, for tests that need to control time exactly.
+/// It starts at the moment it is created
+#[derive(Debug)]
+pub struct ManualClock {
+    /// The current time
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// Creates a clock that stands still at the current moment
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Moves the clock forward to `to`. Does nothing if the clock is already past it
+    pub fn advance_to(&self, to: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(to);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,55 @@
+//! Payload corruption by random bit errors
+
+use rand::{Rng, RngExt};
+
+use crate::ImpairmentConfig;
+
+/// Flips random bits in the configured range of `content`, according to the configured bit error rate.
+/// Every bit error starts a burst of flipped bits of the configured length.
+/// Returns the number of flipped bits
+pub(crate) fn corrupt(content: &mut [u8], config: &ImpairmentConfig, rng: &mut impl Rng) -> usize {
+    if config.bit_error_rate <= 0.0 {
+        return 0;
+    }
+
+    let start = config.corrupt_offset.min(content.len());
+    let end = match config.corrupt_length {
+        Some(length) => start.saturating_add(length).min(content.len()),
+        None => content.len(),
+    };
+
+    let range = &mut content[start..end];
+    let num_bits = range.len() * 8;
+    let burst = config.corrupt_burst_bits.max(1);
+
+    let mut flipped = 0;
+    let mut bit = next_error_gap(config.bit_error_rate, rng);
+
+    while bit < num_bits {
+        let burst_end = bit.saturating_add(burst).min(num_bits);
+
+        for b in bit..burst_end {
+            range[b / 8] ^= 1 << (b % 8);
+        }
+
+        flipped += burst_end - bit;
+
+        // Errors only start again after the current burst has ended
+        bit = burst_end.saturating_add(next_error_gap(config.bit_error_rate, rng));
+    }
+
+    flipped
+}
+
+/// Samples the number of correct bits before the next bit error, which follows
+/// a geometric distribution. This avoids drawing a random number for every bit
+fn next_error_gap(bit_error_rate: f64, rng: &mut impl Rng) -> usize {
+    if bit_error_rate >= 1.0 {
+        return 0;
+    }
+
+    // In (0, 1], so that the logarithm stays finite
+    let uniform = 1.0 - rng.random::<f64>();
+
+    (uniform.ln() / (1.0 - bit_error_rate).ln()).floor() as usize
+}
@@ -0,0 +1,408 @@
+//! A log with a record of the decisions on every packet, for offline analysis
+
+use core::fmt::Write as _;
+use core::net::SocketAddr;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::mangle::Fate;
+use crate::sink::FileSink;
+use crate::stats::DropReason;
+use crate::{Direction, Packet};
+
+/// The columns of a decision log in CSV format
+const CSV_HEADER: &str = "id,direction,client,size,arrival_secs,decision,drop_reason,copies,flipped_bits,reordered,delay_ms,scheduled_secs,sent_secs";
+
+/// Where and how to write a decision log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionLogConfig {
+    /// The file to write, which is replaced if it exists
+    pub path: PathBuf,
+
+    /// The format of the records
+    pub format: DecisionLogFormat,
+}
+
+/// The format of a decision log. Both formats have the same fields:
+///
+/// - `id`: The order in which the packet arrived at the mangler, counted separately for each direction.
+///   Empty or `null` for packets that might be truncated, which are dropped before they get an ID
+/// - `direction`: `upstream` or `downstream`
+/// - `client`: The client that sent the packet upstream, or that the packet is sent to downstream
+/// - `size`: The size of the payload in bytes when it arrived
+/// - `arrival_secs`: When the packet arrived, in seconds since the mangler started
+/// - `decision`: `forwarded`, `dropped`, or `unsent` for a copy that was still waiting when the mangler stopped
+/// - `drop_reason`: Why the packet was dropped, like `random_loss`
+/// - `copies`: The number of copies the packet was duplicated into, including the original
+/// - `flipped_bits`: The number of bits flipped in all copies together
+/// - `reordered`: Whether any copy was explicitly reordered
+/// - `delay_ms`: The delay the mangler gave this copy
+/// - `scheduled_secs`: When this copy was scheduled to be sent, in seconds since the mangler started
+/// - `sent_secs`: When this copy was actually sent, or found to be undeliverable, in seconds since the mangler started
+///
+/// A dropped packet has a single record, written when it arrives. A forwarded packet has a record for every copy,
+/// written when the copy is sent. A copy that could not be sent is written as `dropped` with the reason `undeliverable`,
+/// and the copies that were still waiting when the mangler stopped are written as `unsent` at the end of the log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecisionLogFormat {
+    /// Comma separated values, with a header line
+    #[default]
+    Csv,
+
+    /// A JSON object on every line
+    Ndjson,
+}
+
+/// What is known about a packet that was scheduled, until all its copies are sent
+#[derive(Debug)]
+struct Scheduled {
+    /// The client of the packet
+    client: SocketAddr,
+
+    /// The size of the packet when it arrived
+    size: usize,
+
+    /// When the packet arrived
+    arrival: Instant,
+
+    /// What the mangler did to the packet
+    fate: Fate,
+
+    /// The number of copies that have not been sent yet
+    remaining: usize,
+}
+
+/// A single record of a decision log
+#[derive(Debug)]
+struct Record<'a> {
+    /// The ID of the packet
+    id: Option<u64>,
+
+    /// The direction of the packet
+    direction: Direction,
+
+    /// The client of the packet
+    client: SocketAddr,
+
+    /// The size of the packet when it arrived
+    size: usize,
+
+    /// When the packet arrived
+    arrival: Instant,
+
+    /// What the mangler did to the packet
+    fate: &'a Fate,
+
+    /// What became of the packet, or of the copy the record is about
+    outcome: Outcome,
+}
+
+/// What became of a packet, or of a single copy of it
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    /// The packet was dropped when it arrived
+    Dropped,
+
+    /// The copy was scheduled at the first moment, and sent at the second
+    Sent(Instant, Instant),
+
+    /// The copy was scheduled at the first moment, and could not be sent at the second
+    Undelivered(Instant, Instant, DropReason),
+
+    /// The copy was still waiting to be sent when the mangler stopped
+    Unsent,
+}
+
+impl Record<'_> {
+    /// Returns the decision on the packet
+    fn decision(&self) -> &'static str {
+        match self.outcome {
+            Outcome::Sent(..) => "forwarded",
+            Outcome::Dropped | Outcome::Undelivered(..) => "dropped",
+            Outcome::Unsent => "unsent",
+        }
+    }
+
+    /// Returns why the packet was dropped, if it was
+    fn drop_reason(&self) -> Option<DropReason> {
+        match self.outcome {
+            Outcome::Undelivered(_, _, reason) => Some(reason),
+            _ => self.fate.dropped,
+        }
+    }
+
+    /// Returns when the copy was scheduled to be sent, and when it was sent or found to be undeliverable
+    fn times(&self) -> Option<(Instant, Instant)> {
+        match self.outcome {
+            Outcome::Sent(scheduled, at) | Outcome::Undelivered(scheduled, at, _) => {
+                Some((scheduled, at))
+            }
+            Outcome::Dropped | Outcome::Unsent => None,
+        }
+    }
+}
+
+/// Writes a [decision log](DecisionLogConfig) while the mangler is running
+#[derive(Debug)]
+pub(crate) struct DecisionLog {
+    /// The file being written
+    writer: FileSink,
+
+    /// The format of the records
+    format: DecisionLogFormat,
+
+    /// The packets of each direction that have copies waiting to be sent, by ID
+    scheduled: [Mutex<HashMap<u64, Scheduled>>; 2],
+
+    /// The moment the mangler started, from which all times are counted
+    started: Instant,
+}
+
+impl DecisionLog {
+    /// Creates the decision log file, for a mangler that started at `started`
+    pub(crate) fn create(config: &DecisionLogConfig, started: Instant) -> std::io::Result<Self> {
+        let writer = FileSink::create(&config.path, "decision log", |mut writer| {
+            if config.format == DecisionLogFormat::Csv {
+                writeln!(writer, "{CSV_HEADER}")?;
+            }
+
+            Ok(writer)
+        })?;
+
+        Ok(Self {
+            writer,
+            format: config.format,
+            scheduled: [Mutex::new(HashMap::new()), Mutex::new(HashMap::new())],
+            started,
+        })
+    }
+
+    /// Logs a packet of `client` that arrived at `arrival`, with what the mangler did to it. Dropped packets are written
+    /// right away, while scheduled packets are written once their copies are sent
+    pub(crate) fn received(
+        &self,
+        id: Option<u64>,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        arrival: Instant,
+        fate: &Fate,
+    ) {
+        match id {
+            Some(id) if !fate.delays.is_empty() => {
+                self.scheduled[direction as usize].lock().unwrap().insert(
+                    id,
+                    Scheduled {
+                        client,
+                        size,
+                        arrival,
+                        fate: fate.clone(),
+                        remaining: fate.delays.len(),
+                    },
+                );
+            }
+            _ => self.write(&Record {
+                id,
+                direction,
+                client,
+                size,
+                arrival,
+                fate,
+                outcome: Outcome::Dropped,
+            }),
+        }
+    }
+
+    /// Logs a copy of a scheduled packet that was sent at `at`
+    pub(crate) fn sent(&self, packet: &Packet, direction: Direction, at: Instant) {
+        self.copy_done(packet, direction, Outcome::Sent(packet.send_timestamp, at));
+    }
+
+    /// Logs a copy of a scheduled packet that could not be sent at `at`
+    pub(crate) fn undelivered(
+        &self,
+        packet: &Packet,
+        direction: Direction,
+        at: Instant,
+        reason: DropReason,
+    ) {
+        self.copy_done(
+            packet,
+            direction,
+            Outcome::Undelivered(packet.send_timestamp, at, reason),
+        );
+    }
+
+    /// Logs the copies that are still waiting to be sent as unsent, and writes everything that is buffered to the file.
+    /// Called once the mangler stopped
+    pub(crate) fn finish(&self) {
+        for (direction, scheduled) in [Direction::Upstream, Direction::Downstream]
+            .into_iter()
+            .zip(&self.scheduled)
+        {
+            let mut pending: Vec<(u64, Scheduled)> = scheduled.lock().unwrap().drain().collect();
+            pending.sort_unstable_by_key(|(id, _)| *id);
+
+            for (id, entry) in pending {
+                for _ in 0..entry.remaining {
+                    self.write(&Record {
+                        id: Some(id),
+                        direction,
+                        client: entry.client,
+                        size: entry.size,
+                        arrival: entry.arrival,
+                        fate: &entry.fate,
+                        outcome: Outcome::Unsent,
+                    });
+                }
+            }
+        }
+
+        self.flush();
+    }
+
+    /// Writes everything that is buffered to the file
+    pub(crate) fn flush(&self) {
+        self.writer.flush();
+    }
+
+    /// Logs a copy of a scheduled packet that is done, one way or another
+    fn copy_done(&self, packet: &Packet, direction: Direction, outcome: Outcome) {
+        let mut scheduled = self.scheduled[direction as usize].lock().unwrap();
+
+        let Some(entry) = scheduled.get_mut(&packet.id) else {
+            return;
+        };
+
+        entry.remaining = entry.remaining.saturating_sub(1);
+
+        self.write(&Record {
+            id: Some(packet.id),
+            direction,
+            client: entry.client,
+            size: entry.size,
+            arrival: entry.arrival,
+            fate: &entry.fate,
+            outcome,
+        });
+
+        if entry.remaining == 0 {
+            scheduled.remove(&packet.id);
+        }
+    }
+
+    /// Writes a single record
+    fn write(&self, record: &Record<'_>) {
+        let line = match self.format {
+            DecisionLogFormat::Csv => self.csv(record),
+            DecisionLogFormat::Ndjson => self.ndjson(record),
+        };
+
+        self.writer.line(&line);
+    }
+
+    /// Formats a record as a CSV line
+    fn csv(&self, record: &Record<'_>) -> String {
+        let mut line = String::new();
+
+        if let Some(id) = record.id {
+            _ = write!(line, "{id}");
+        }
+
+        _ = write!(
+            line,
+            ",{},{},{},{:.6},{},{},{},{},{}",
+            record.direction,
+            record.client,
+            record.size,
+            self.secs(record.arrival),
+            record.decision(),
+            record.drop_reason().map_or("", |reason| reason.label()),
+            record.fate.delays.len(),
+            record.fate.flipped_bits,
+            record.fate.reordered,
+        );
+
+        match record.times() {
+            Some((scheduled, sent)) => {
+                _ = write!(
+                    line,
+                    ",{:.3},{:.6},{:.6}",
+                    ms(scheduled.saturating_duration_since(record.arrival)),
+                    self.secs(scheduled),
+                    self.secs(sent),
+                )
+            }
+            None => line.push_str(",,,"),
+        }
+
+        line
+    }
+
+    /// Formats a record as a JSON object
+    fn ndjson(&self, record: &Record<'_>) -> String {
+        let mut line = String::from("{");
+
+        match record.id {
+            Some(id) => _ = write!(line, "\"id\":{id}"),
+            None => line.push_str("\"id\":null"),
+        }
+
+        _ = write!(
+            line,
+            ",\"direction\":\"{}\",\"client\":\"{}\",\"size\":{},\"arrival_secs\":{:.6},\"decision\":\"{}\"",
+            record.direction,
+            record.client,
+            record.size,
+            self.secs(record.arrival),
+            record.decision(),
+        );
+
+        match record.drop_reason() {
+            Some(reason) => _ = write!(line, ",\"drop_reason\":\"{}\"", reason.label()),
+            None => line.push_str(",\"drop_reason\":null"),
+        }
+
+        _ = write!(
+            line,
+            ",\"copies\":{},\"flipped_bits\":{},\"reordered\":{}",
+            record.fate.delays.len(),
+            record.fate.flipped_bits,
+            record.fate.reordered,
+        );
+
+        match record.times() {
+            Some((scheduled, sent)) => {
+                _ = write!(
+                    line,
+                    ",\"delay_ms\":{:.3},\"scheduled_secs\":{:.6},\"sent_secs\":{:.6}}}",
+                    ms(scheduled.saturating_duration_since(record.arrival)),
+                    self.secs(scheduled),
+                    self.secs(sent),
+                )
+            }
+            None => line.push_str(",\"delay_ms\":null,\"scheduled_secs\":null,\"sent_secs\":null}"),
+        }
+
+        line
+    }
+
+    /// Returns the seconds from the start of the mangler until `at`
+    fn secs(&self, at: Instant) -> f64 {
+        at.saturating_duration_since(self.started).as_secs_f64()
+    }
+}
+
+impl Drop for DecisionLog {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Returns a duration in milliseconds
+fn ms(duration: core::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,217 @@
+//! Time-varying link capacity from Mahimahi delivery traces
+
+use core::time::Duration;
+use std::time::Instant;
+
+/// A trace of delivery opportunities, in the format of the Mahimahi link emulator. Every opportunity
+/// allows [DeliveryTrace::BYTES_PER_OPPORTUNITY] bytes to leave the link, which may be shared by multiple
+/// small packets or span multiple opportunities for large ones. Opportunities that no packet is waiting for
+/// are wasted. The trace repeats after its last opportunity, and starts with the first packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryTrace {
+    /// The moments of the opportunities in milliseconds since the start of the trace, sorted
+    opportunities_ms: Vec<u64>,
+
+    /// The length of the trace in milliseconds, after which it repeats
+    period_ms: u64,
+}
+
+/// Error while creating a [DeliveryTrace]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum DeliveryTraceErr {
+    /// The trace contains no opportunities after time zero, so it can not repeat
+    #[display("The trace contains no delivery opportunities after 0 ms")]
+    Empty,
+
+    /// A line could not be parsed as a timestamp in milliseconds
+    #[display("Invalid timestamp on line {}", _0)]
+    InvalidLine(#[error(not(source))] usize),
+
+    /// An opportunity is earlier than the one before it
+    #[display("Opportunity {} is earlier than the one before it", _0)]
+    Unsorted(#[error(not(source))] usize),
+}
+
+impl DeliveryTrace {
+    /// The number of bytes that may leave the link at every opportunity. This is the MTU-sized packet of Mahimahi
+    pub const BYTES_PER_OPPORTUNITY: usize = 1504;
+
+    /// Creates a trace from the moments of the delivery opportunities in milliseconds, sorted.
+    /// The same moment may appear multiple times for multiple opportunities at once
+    pub fn new(opportunities_ms: Vec<u64>) -> Result<Self, DeliveryTraceErr> {
+        if let Some(i) = opportunities_ms
+            .windows(2)
+            .position(|pair| pair[1] < pair[0])
+        {
+            return Err(DeliveryTraceErr::Unsorted(i + 2));
+        }
+
+        let period_ms = match opportunities_ms.last() {
+            Some(last) if *last > 0 => *last,
+            _ => return Err(DeliveryTraceErr::Empty),
+        };
+
+        Ok(Self {
+            opportunities_ms,
+            period_ms,
+        })
+    }
+
+    /// Parses a Mahimahi trace, which has the moment of a single delivery opportunity in milliseconds on every line.
+    /// Empty lines are ignored
+    pub fn parse(text: &str) -> Result<Self, DeliveryTraceErr> {
+        let mut opportunities_ms = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            opportunities_ms.push(
+                line.parse::<u64>()
+                    .map_err(|_| DeliveryTraceErr::InvalidLine(i + 1))?,
+            );
+        }
+
+        Self::new(opportunities_ms)
+    }
+
+    /// Returns the moment of the opportunity with the given index, counting on into later repetitions of the trace
+    fn opportunity(&self, start: Instant, index: u64) -> Instant {
+        let num_opportunities = self.opportunities_ms.len() as u64;
+        let repetition = index / num_opportunities;
+        let offset_ms = repetition * self.period_ms
+            + self.opportunities_ms[(index % num_opportunities) as usize];
+
+        start + Duration::from_millis(offset_ms)
+    }
+
+    /// Returns the index of the first opportunity at or after `now`
+    fn first_opportunity_after(&self, start: Instant, now: Instant) -> u64 {
+        let elapsed_ms = now.saturating_duration_since(start).as_secs_f64() * 1000.0;
+        let repetition = (elapsed_ms / self.period_ms as f64).floor() as u64;
+        let offset_ms = elapsed_ms - (repetition * self.period_ms) as f64;
+
+        let index = self
+            .opportunities_ms
+            .partition_point(|opportunity| (*opportunity as f64) < offset_ms);
+
+        repetition * self.opportunities_ms.len() as u64 + index as u64
+    }
+}
+
+/// Position of a link in its [DeliveryTrace]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TraceCursor {
+    /// The start of the trace
+    start: Instant,
+
+    /// The index of the last opportunity that was used, counting on into later repetitions of the trace,
+    /// and the number of bytes left in it
+    last: Option<(u64, usize)>,
+}
+
+impl TraceCursor {
+    /// Creates a cursor for a trace starting at `start`
+    pub(crate) fn new(start: Instant) -> Self {
+        Self { start, last: None }
+    }
+
+    /// Returns the moment a packet of `size` bytes arriving at `now` leaves the link, and the cursor after that packet
+    pub(crate) fn send(&self, trace: &DeliveryTrace, size: usize, now: Instant) -> (Instant, Self) {
+        let first = trace.first_opportunity_after(self.start, now);
+
+        // Continue with the bytes left in the last used opportunity if it has not passed yet,
+        // otherwise wait for the next opportunity
+        let (mut index, mut bytes_left) = match self.last {
+            Some((index, bytes_left))
+                if bytes_left > 0 && trace.opportunity(self.start, index) >= now =>
+            {
+                (index, bytes_left)
+            }
+            Some((index, _)) => (first.max(index + 1), DeliveryTrace::BYTES_PER_OPPORTUNITY),
+            None => (first, DeliveryTrace::BYTES_PER_OPPORTUNITY),
+        };
+
+        let mut remaining = size;
+
+        while remaining > bytes_left {
+            remaining -= bytes_left;
+            index += 1;
+            bytes_left = DeliveryTrace::BYTES_PER_OPPORTUNITY;
+        }
+
+        bytes_left -= remaining;
+
+        let cursor = Self {
+            start: self.start,
+            last: Some((index, bytes_left)),
+        };
+
+        (trace.opportunity(self.start, index), cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::time::Instant;
+
+    use super::{DeliveryTrace, DeliveryTraceErr, TraceCursor};
+
+    /// Every line is an opportunity, and the same moment may appear more than once
+    #[test]
+    fn parse() {
+        assert_eq!(
+            DeliveryTrace::parse("0\n5\n5\n\n12\n"),
+            DeliveryTrace::new(vec![0, 5, 5, 12]),
+            "The parsed trace matches the text"
+        );
+    }
+
+    /// Small packets share an opportunity, large packets span several, and the trace repeats after its last opportunity
+    #[test]
+    fn cursor() {
+        let trace = DeliveryTrace::parse("0\n10\n20").expect("The trace is valid");
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let cursor = TraceCursor::new(start);
+
+        let (first, cursor) = cursor.send(&trace, 500, start);
+        let (second, cursor) = cursor.send(&trace, 500, start);
+        assert_eq!(
+            (first, second),
+            (ms(0), ms(0)),
+            "Two small packets fit in the first opportunity"
+        );
+
+        let (large, cursor) = cursor.send(&trace, 2000, start);
+        assert_eq!(
+            large,
+            ms(10),
+            "A large packet takes the rest of the first and part of the second opportunity"
+        );
+
+        let (late, cursor) = cursor.send(&trace, 100, ms(15));
+        assert_eq!(late, ms(20), "A packet waits for the next opportunity");
+
+        let (repeated, _) = cursor.send(&trace, 100, ms(25));
+        assert_eq!(
+            repeated,
+            ms(30),
+            "The trace repeats every 20 ms, so the opportunity at 10 ms comes again at 30 ms"
+        );
+    }
+
+    /// A trace needs an opportunity after time zero to know when to repeat
+    #[test]
+    fn only_time_zero() {
+        assert_eq!(
+            DeliveryTrace::parse("0\n0"),
+            Err(DeliveryTraceErr::Empty),
+            "The trace can not repeat"
+        );
+    }
+}
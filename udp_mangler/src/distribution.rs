@@ -0,0 +1,402 @@
+//! Random distributions for the jitter added to packets
+
+use core::f64::consts::TAU;
+
+use rand::{Rng, RngExt};
+
+/// The largest [Pareto](JitterDistribution::Pareto) sample, as a multiple of its mean. Its tail is unbounded,
+/// so larger samples are clamped to this
+const MAX_PARETO_MEANS: f64 = 100.0;
+
+/// The smallest [Pareto](JitterDistribution::Pareto) shape. A Pareto distribution with a shape of 1.0 or less
+/// has no mean, so smaller shapes are raised to this
+const MIN_PARETO_SHAPE: f64 = 1.01;
+
+/// The distribution of the jitter added on top of the ping
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum JitterDistribution {
+    /// Uniformly distributed between zero and the jitter
+    #[default]
+    Uniform,
+
+    /// Normally distributed around the ping, with the jitter as standard deviation
+    Normal,
+
+    /// Heavy-tailed Pareto distribution with the given shape, with the jitter as mean.
+    /// Smaller shapes give heavier tails. Shapes below 1.01 are raised to 1.01, since the distribution has no mean
+    /// at 1.0 or below. Samples are clamped at 100 times the jitter
+    Pareto {
+        /// The shape parameter of the distribution
+        shape: f64,
+    },
+
+    /// A mix of 25% [Normal](Self::Normal) and 75% [Pareto](Self::Pareto), like netem's "paretonormal"
+    ParetoNormal {
+        /// The shape parameter of the Pareto part
+        shape: f64,
+    },
+
+    /// Exponentially distributed, with the jitter as mean
+    Exponential,
+
+    /// Sampled from a measured distribution. The jitter setting is ignored
+    Empirical(EmpiricalDistribution),
+}
+
+impl JitterDistribution {
+    /// Samples a jitter offset in seconds, given the configured jitter. Note that the
+    /// offset may be negative for distributions centered around the ping
+    pub(crate) fn sample(&self, jitter_secs: f64, rng: &mut impl Rng) -> f64 {
+        match self {
+            Self::Uniform => rng.random_range::<f64, _>(0.0..=jitter_secs),
+            Self::Normal => standard_normal(rng) * jitter_secs,
+            Self::Pareto { shape } => pareto(*shape, jitter_secs, rng),
+            Self::ParetoNormal { shape } => {
+                0.25 * standard_normal(rng) * jitter_secs + 0.75 * pareto(*shape, jitter_secs, rng)
+            }
+            Self::Exponential => -(1.0 - rng.random::<f64>()).ln() * jitter_secs,
+            Self::Empirical(empirical) => empirical.sample(rng),
+        }
+    }
+}
+
+/// Samples a standard normal distribution with the Box-Muller transform
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    // In (0, 1], so that the logarithm stays finite
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+
+    (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos()
+}
+
+/// Samples a Pareto distribution with the given shape, shifted to start at zero and scaled to the given mean,
+/// and clamped at [MAX_PARETO_MEANS] times the mean. The shape is at least [MIN_PARETO_SHAPE]
+fn pareto(shape: f64, mean: f64, rng: &mut impl Rng) -> f64 {
+    let shape = shape.max(MIN_PARETO_SHAPE);
+
+    // The mean of a Pareto distribution shifted to zero is `scale / (shape - 1)`
+    let scale = mean * (shape - 1.0);
+    let u = 1.0 - rng.random::<f64>();
+
+    (scale / u.powf(1.0 / shape) - scale).min(mean * MAX_PARETO_MEANS)
+}
+
+/// Error while creating an [EmpiricalDistribution]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum DistributionErr {
+    /// The distribution contains no points
+    #[display("The distribution contains no points")]
+    Empty,
+
+    /// A line could not be parsed as a delay and a probability
+    #[display("Invalid delay and probability on line {}", _0)]
+    InvalidLine(#[error(not(source))] usize),
+
+    /// A probability or weight was negative or not a number, or a cumulative
+    /// probability was lower than the one before it
+    #[display("Invalid probability for point {}", _0)]
+    InvalidProbability(#[error(not(source))] usize),
+
+    /// An offset was not finite, or an offset of a cumulative distribution function was lower than the one before it
+    #[display("Invalid offset for point {}", _0)]
+    InvalidOffset(#[error(not(source))] usize),
+}
+
+/// A measured distribution of jitter offsets
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmpiricalDistribution {
+    /// The cumulative probabilities, normalized to end at 1.0, and the offset in seconds at that probability
+    points: Vec<(f64, f64)>,
+
+    /// Whether to interpolate between points. If not, only the exact offsets of the points are sampled
+    interpolate: bool,
+}
+
+impl EmpiricalDistribution {
+    /// Creates a distribution from points of its cumulative distribution function, as
+    /// (offset in seconds, cumulative probability) pairs, sorted by offset.
+    /// Offsets between the points are linearly interpolated
+    pub fn from_cdf(points: &[(f64, f64)]) -> Result<Self, DistributionErr> {
+        let mut prev = 0.0;
+        let mut prev_offset = f64::NEG_INFINITY;
+
+        for (i, (offset, probability)) in points.iter().enumerate() {
+            if probability.is_nan() || *probability < prev {
+                return Err(DistributionErr::InvalidProbability(i + 1));
+            }
+
+            if !offset.is_finite() || *offset < prev_offset {
+                return Err(DistributionErr::InvalidOffset(i + 1));
+            }
+
+            prev = *probability;
+            prev_offset = *offset;
+        }
+
+        Self::normalized(points.to_vec(), true)
+    }
+
+    /// Creates a distribution from a histogram, as (offset in seconds, weight) pairs.
+    /// Only the given offsets are sampled, each with a probability proportional to its weight
+    pub fn from_histogram(bins: &[(f64, f64)]) -> Result<Self, DistributionErr> {
+        let mut cumulative = 0.0;
+        let mut points = Vec::with_capacity(bins.len());
+
+        for (i, (offset, weight)) in bins.iter().enumerate() {
+            if weight.is_nan() || *weight < 0.0 {
+                return Err(DistributionErr::InvalidProbability(i + 1));
+            }
+
+            if !offset.is_finite() {
+                return Err(DistributionErr::InvalidOffset(i + 1));
+            }
+
+            cumulative += weight;
+            points.push((*offset, cumulative));
+        }
+
+        Self::normalized(points, false)
+    }
+
+    /// Parses a cumulative distribution function, with one point per line. Each line has an offset
+    /// in milliseconds and a cumulative probability, separated by whitespace or a comma.
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn parse_cdf(text: &str) -> Result<Self, DistributionErr> {
+        Self::from_cdf(&parse_pairs(text)?)
+    }
+
+    /// Parses a histogram, with one bin per line. Each line has an offset in milliseconds
+    /// and a weight, separated by whitespace or a comma.
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn parse_histogram(text: &str) -> Result<Self, DistributionErr> {
+        Self::from_histogram(&parse_pairs(text)?)
+    }
+
+    /// Creates a distribution from (offset, cumulative probability) points,
+    /// normalizing the probabilities to end at 1.0
+    fn normalized(points: Vec<(f64, f64)>, interpolate: bool) -> Result<Self, DistributionErr> {
+        let total = match points.last() {
+            Some((_, total)) if *total > 0.0 => *total,
+            _ => return Err(DistributionErr::Empty),
+        };
+
+        Ok(Self {
+            points: points
+                .into_iter()
+                .map(|(offset, probability)| (probability / total, offset))
+                .collect(),
+            interpolate,
+        })
+    }
+
+    /// Samples an offset in seconds
+    fn sample(&self, rng: &mut impl Rng) -> f64 {
+        let u = rng.random::<f64>();
+
+        let i = self
+            .points
+            .partition_point(|(probability, _)| *probability < u);
+        let (probability, offset) = self.points[i.min(self.points.len() - 1)];
+
+        if !self.interpolate || i == 0 {
+            return offset;
+        }
+
+        let (prev_probability, prev_offset) = self.points[i - 1];
+        let t = (u - prev_probability) / (probability - prev_probability);
+
+        prev_offset + t * (offset - prev_offset)
+    }
+}
+
+/// Parses lines of (milliseconds, probability) pairs, and converts them to seconds
+fn parse_pairs(text: &str) -> Result<Vec<(f64, f64)>, DistributionErr> {
+    let mut pairs = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut values = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(str::parse::<f64>);
+
+        let (Some(Ok(millis)), Some(Ok(probability)), None) =
+            (values.next(), values.next(), values.next())
+        else {
+            return Err(DistributionErr::InvalidLine(i + 1));
+        };
+
+        pairs.push((millis / 1000.0, probability));
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::{DistributionErr, EmpiricalDistribution, JitterDistribution, MAX_PARETO_MEANS};
+
+    /// Offsets are given in milliseconds, and probabilities that do not end at 1.0 are normalized
+    #[test]
+    fn parse_cdf_normalizes() {
+        let distribution = EmpiricalDistribution::parse_cdf(
+            "# offset_ms, cumulative
+            10, 0.25
+
+            20 0.5",
+        )
+        .expect("The distribution is valid");
+
+        assert_eq!(
+            distribution.points,
+            [(0.5, 0.01), (1.0, 0.02)],
+            "The offsets are in seconds and the probabilities end at 1.0"
+        );
+    }
+
+    /// A CDF interpolates between its points, while a histogram only gives its exact offsets
+    #[test]
+    fn cdf_interpolates_histogram_does_not() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let cdf = EmpiricalDistribution::from_cdf(&[(0.01, 0.0), (0.02, 1.0)])
+            .expect("The distribution is valid");
+        let histogram = EmpiricalDistribution::from_histogram(&[(0.01, 1.0), (0.02, 3.0)])
+            .expect("The distribution is valid");
+
+        let cdf_samples: Vec<f64> = (0..1000).map(|_| cdf.sample(&mut rng)).collect();
+        assert!(
+            cdf_samples
+                .iter()
+                .all(|offset| (0.01..=0.02).contains(offset)),
+            "The CDF stays between its first and last offset"
+        );
+        assert!(
+            cdf_samples
+                .iter()
+                .any(|offset| *offset != 0.01 && *offset != 0.02),
+            "The CDF interpolates between its points"
+        );
+
+        let high = (0..1000)
+            .map(|_| histogram.sample(&mut rng))
+            .inspect(|offset| {
+                assert!(
+                    *offset == 0.01 || *offset == 0.02,
+                    "The histogram only gives its own offsets, not {offset}"
+                );
+            })
+            .filter(|offset| *offset == 0.02)
+            .count();
+        assert!(
+            (650..850).contains(&high),
+            "The histogram gives the offset with three times the weight about 75% of the time, not {high} of 1000"
+        );
+    }
+
+    /// A cumulative probability may not go down, and the error names the point in the file
+    #[test]
+    fn cdf_must_not_decrease() {
+        assert_eq!(
+            EmpiricalDistribution::parse_cdf("5 0.5\n\n10 0.25"),
+            Err(DistributionErr::InvalidProbability(2)),
+            "The second point has a lower probability than the first"
+        );
+        assert_eq!(
+            EmpiricalDistribution::parse_cdf("5 0.5\n10"),
+            Err(DistributionErr::InvalidLine(2)),
+            "A line needs both an offset and a probability"
+        );
+    }
+
+    /// A distribution needs some probability to sample from
+    #[test]
+    fn all_zero_weights_are_empty() {
+        assert_eq!(
+            EmpiricalDistribution::from_histogram(&[(0.01, 0.0), (0.02, 0.0)]),
+            Err(DistributionErr::Empty),
+            "Only zero weights"
+        );
+    }
+
+    /// The Pareto distribution has the jitter as its mean
+    #[test]
+    fn pareto_mean() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let distribution = JitterDistribution::Pareto { shape: 3.0 };
+
+        let mean = (0..100_000)
+            .map(|_| distribution.sample(0.01, &mut rng))
+            .sum::<f64>()
+            / 100_000.0;
+
+        assert!(
+            (0.009..0.011).contains(&mean),
+            "The mean of the samples is about the jitter, not {mean}"
+        );
+    }
+
+    /// Even the heaviest tails are clamped
+    #[test]
+    fn pareto_is_clamped() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for distribution in [
+            JitterDistribution::Pareto { shape: 1.001 },
+            JitterDistribution::ParetoNormal { shape: 1.001 },
+        ] {
+            let max = (0..100_000)
+                .map(|_| distribution.sample(0.01, &mut rng))
+                .fold(0.0, f64::max);
+
+            assert!(
+                max <= 0.01 * MAX_PARETO_MEANS + 0.01 * 10.0,
+                "{distribution:?} is clamped, but sampled {max}"
+            );
+        }
+    }
+
+    /// Shapes without a mean are raised to the smallest shape, so the jitter never goes below zero
+    #[test]
+    fn pareto_small_shapes() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for shape in [1.0, 0.5, -2.0, f64::NAN] {
+            let distribution = JitterDistribution::Pareto { shape };
+            let samples: Vec<f64> = (0..1000)
+                .map(|_| distribution.sample(0.01, &mut rng))
+                .collect();
+
+            assert!(
+                samples.iter().all(|sample| *sample >= 0.0),
+                "A shape of {shape} gives no negative jitter"
+            );
+            assert!(
+                samples.iter().any(|sample| *sample > 0.0),
+                "A shape of {shape} gives some jitter"
+            );
+        }
+    }
+
+    /// The offsets of a CDF must be finite and may not go down
+    #[test]
+    fn cdf_offsets_must_be_sorted() {
+        assert_eq!(
+            EmpiricalDistribution::parse_cdf("10 0.5\n5 1"),
+            Err(DistributionErr::InvalidOffset(2)),
+            "The second point has a lower offset than the first"
+        );
+        assert_eq!(
+            EmpiricalDistribution::parse_histogram("inf 1"),
+            Err(DistributionErr::InvalidOffset(1)),
+            "An infinite offset can not be sampled"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Events on what happens to every packet, pushed to subscribers
+
+use core::net::SocketAddr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use crate::Direction;
+use crate::mangle::Fate;
+use crate::stats::DropReason;
+
+/// The number of events a [Subscription] buffers before it starts lagging
+const BUFFER_SIZE: usize = 4096;
+
+/// Something that happened to a packet passing through the mangler
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketEvent {
+    /// The order in which the packet arrived at the mangler, counted separately for each [Direction].
+    /// Together with the direction it identifies the packet, and all its copies.
+    /// Packets that might be truncated are dropped before they get an ID
+    pub id: Option<u64>,
+
+    /// The direction the packet travels in
+    pub direction: Direction,
+
+    /// The client that sent the packet when travelling upstream, or the client it is destined for when travelling downstream
+    pub client: SocketAddr,
+
+    /// The size of the payload in bytes
+    pub size: usize,
+
+    /// The moment the event happened. The decisions on a packet are made when it arrives
+    pub at: Instant,
+
+    /// What happened
+    pub kind: PacketEventKind,
+}
+
+/// The kind of a [PacketEvent]. Every packet is first received, and then either dropped or scheduled.
+/// A packet that is duplicated is scheduled once for every copy, and every copy is forwarded separately,
+/// or dropped as [undeliverable](DropReason::Undeliverable) if it could not be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketEventKind {
+    /// The packet arrived at the mangler
+    Received,
+
+    /// The packet was dropped
+    Dropped(DropReason),
+
+    /// The packet was duplicated into the given number of extra copies
+    Duplicated {
+        /// The number of extra copies
+        copies: usize,
+    },
+
+    /// Bits of the packet were flipped
+    Corrupted {
+        /// The number of flipped bits, of all copies together
+        flipped_bits: usize,
+    },
+
+    /// A copy of the packet was scheduled to be sent after the given delay
+    Scheduled {
+        /// The delay, counted from the moment the packet arrived
+        delay: Duration,
+    },
+
+    /// A copy of the packet was sent
+    Forwarded,
+}
+
+/// Error while receiving from a [Subscription]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display, derive_more::Error)]
+pub enum SubscriptionErr {
+    /// The subscriber did not keep up, and the given number of events were skipped at this point
+    #[display("Missed {} packet events", _0)]
+    Lagged(#[error(not(source))] u64),
+
+    /// No event is available right now
+    #[display("No packet event available")]
+    Empty,
+
+    /// The mangler stopped, and all its events have been received
+    #[display("The mangler stopped")]
+    Closed,
+}
+
+/// An item in the buffer of a [Subscription]
+#[derive(Debug)]
+enum Item {
+    /// An event
+    Event(PacketEvent),
+
+    /// The number of events that were skipped because the buffer was full
+    Lagged(u64),
+}
+
+impl From<Item> for Result<PacketEvent, SubscriptionErr> {
+    fn from(item: Item) -> Self {
+        match item {
+            Item::Event(event) => Ok(event),
+            Item::Lagged(missed) => Err(SubscriptionErr::Lagged(missed)),
+        }
+    }
+}
+
+/// The buffer of a [Subscription], shared with the mangler threads
+#[derive(Debug, Default)]
+struct Buffer {
+    /// The buffered items, oldest first, and whether the mangler stopped
+    items: Mutex<(VecDeque<Item>, bool)>,
+
+    /// Signalled when an item is added or the mangler stopped
+    changed: Condvar,
+}
+
+impl Buffer {
+    /// Adds an event without blocking. If the buffer is full the event is counted as skipped instead
+    fn push(&self, event: PacketEvent) {
+        let mut items = self.items.lock().unwrap();
+        let (queue, _) = &mut *items;
+
+        if queue.len() < BUFFER_SIZE {
+            queue.push_back(Item::Event(event));
+        } else if let Some(Item::Lagged(missed)) = queue.back_mut() {
+            *missed += 1;
+        } else {
+            // Skipped events are always reported, even if that goes over the size of the buffer
+            queue.push_back(Item::Lagged(1));
+        }
+
+        self.changed.notify_one();
+    }
+
+    /// Marks that the mangler stopped
+    fn close(&self) {
+        self.items.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+}
+
+/// A stream of [PacketEvent]s, returned by [Mangler::subscribe](crate::Mangler::subscribe).
+/// The events are buffered, and if the subscriber does not keep up new events are skipped instead of slowing down
+/// the mangler. The receiving methods report how many events were skipped, at the point where they were skipped
+#[derive(Debug)]
+pub struct Subscription {
+    /// The buffer of events
+    buffer: Arc<Buffer>,
+}
+
+impl Subscription {
+    /// Blocks until the next event is available
+    pub fn recv(&self) -> Result<PacketEvent, SubscriptionErr> {
+        self.recv_until(None)
+    }
+
+    /// Returns the next event if one is available, without blocking
+    pub fn try_recv(&self) -> Result<PacketEvent, SubscriptionErr> {
+        self.recv_until(Some(Instant::now()))
+    }
+
+    /// Blocks until the next event is available, or until `timeout` has passed
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<PacketEvent, SubscriptionErr> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// Blocks until the next event is available, or until `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<PacketEvent, SubscriptionErr> {
+        let mut items = self.buffer.items.lock().unwrap();
+
+        loop {
+            let (queue, closed) = &mut *items;
+
+            if let Some(item) = queue.pop_front() {
+                return item.into();
+            }
+
+            if *closed {
+                return Err(SubscriptionErr::Closed);
+            }
+
+            items = match deadline {
+                None => self.buffer.changed.wait(items).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+
+                    if timeout.is_zero() {
+                        return Err(SubscriptionErr::Empty);
+                    }
+
+                    self.buffer.changed.wait_timeout(items, timeout).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+/// All subscribers of the events of a mangler
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    /// The buffers of the subscribers, which are removed once their subscription is dropped
+    subscribers: Mutex<Vec<Arc<Buffer>>>,
+
+    /// Whether the mangler stopped
+    closed: AtomicBool,
+}
+
+impl Subscribers {
+    /// Adds a new subscriber. If the mangler already stopped, its subscription is closed right away
+    pub(crate) fn subscribe(&self) -> Subscription {
+        let buffer = Arc::new(Buffer::default());
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        if self.closed.load(Ordering::Acquire) {
+            buffer.close();
+        } else {
+            subscribers.push(buffer.clone());
+        }
+
+        Subscription { buffer }
+    }
+
+    /// Removes all subscribers, which ends their subscriptions once they received the remaining events
+    pub(crate) fn close(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        self.closed.store(true, Ordering::Release);
+
+        for buffer in subscribers.drain(..) {
+            buffer.close();
+        }
+    }
+
+    /// Publishes the events of a packet that arrived at `arrival`, with what the mangler did to it
+    pub(crate) fn received(
+        &self,
+        id: Option<u64>,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        arrival: Instant,
+        fate: &Fate,
+    ) {
+        self.publish(|| {
+            let mut kinds = vec![PacketEventKind::Received];
+
+            if fate.delays.is_empty() {
+                kinds.extend(fate.dropped.map(PacketEventKind::Dropped));
+            }
+
+            if fate.delays.len() > 1 {
+                kinds.push(PacketEventKind::Duplicated {
+                    copies: fate.delays.len() - 1,
+                });
+            }
+
+            if fate.flipped_bits != 0 {
+                kinds.push(PacketEventKind::Corrupted {
+                    flipped_bits: fate.flipped_bits,
+                });
+            }
+
+            kinds.extend(
+                fate.delays
+                    .iter()
+                    .map(|delay| PacketEventKind::Scheduled { delay: *delay }),
+            );
+
+            kinds
+                .into_iter()
+                .map(|kind| PacketEvent {
+                    id,
+                    direction,
+                    client,
+                    size,
+                    at: arrival,
+                    kind,
+                })
+                .collect()
+        });
+    }
+
+    /// Publishes the event of a copy of a packet that was sent at `at`
+    pub(crate) fn sent(
+        &self,
+        id: u64,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        at: Instant,
+    ) {
+        self.publish(|| {
+            vec![PacketEvent {
+                id: Some(id),
+                direction,
+                client,
+                size,
+                at,
+                kind: PacketEventKind::Forwarded,
+            }]
+        });
+    }
+
+    /// Publishes the event of a copy of a packet that could not be sent at `at`
+    pub(crate) fn undelivered(
+        &self,
+        id: u64,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        at: Instant,
+        reason: DropReason,
+    ) {
+        self.publish(|| {
+            vec![PacketEvent {
+                id: Some(id),
+                direction,
+                client,
+                size,
+                at,
+                kind: PacketEventKind::Dropped(reason),
+            }]
+        });
+    }
+
+    /// Sends the events made by `events` to every subscriber. The events are only made if there are any subscribers
+    fn publish(&self, events: impl FnOnce() -> Vec<PacketEvent>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        // Only the buffer of a dropped subscription is not shared anymore
+        subscribers.retain(|buffer| Arc::strong_count(buffer) > 1);
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        for event in events() {
+            for buffer in subscribers.iter() {
+                buffer.push(event.clone());
+            }
+        }
+    }
+}
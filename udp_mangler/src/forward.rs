@@ -9,20 +9,59 @@ use std::sync::mpsc::{Receiver, RecvError, Sender};
 
 use arc_swap::ArcSwap;
 
-use crate::{ManglerConfig, Packet};
+use crate::clock::Clock;
+use crate::observe::Observers;
+use crate::reorder::ReorderTracker;
+use crate::session::SessionTable;
+use crate::stats::DropReason;
+use crate::{Direction, ManglerConfig, Packet};
+
+/// Where a forward thread sends its packets to
+#[derive(Debug)]
+pub(crate) enum Route {
+    /// Upstream, to the forward target through the session of the sending client
+    ToTarget(Arc<SessionTable>),
+
+    /// Downstream, back to the client through the listener socket
+    ToClient(UdpSocket),
+}
+
+impl Route {
+    /// Returns the direction of the packets sent along this route
+    fn direction(&self) -> Direction {
+        match self {
+            Self::ToTarget(_) => Direction::Upstream,
+            Self::ToClient(_) => Direction::Downstream,
+        }
+    }
+
+    /// Sends a single packet along this route. Returns `Ok(None)` if there was nowhere
+    /// to send the packet to
+    fn send(&self, packet: &Packet) -> std::io::Result<Option<usize>> {
+        match self {
+            Self::ToTarget(sessions) => match sessions.get(packet.client, packet.replayed) {
+                Some(session) => session.send(&packet.content).map(Some),
+                None => Ok(None),
+            },
+            Self::ToClient(socket) => socket.send_to(&packet.content, packet.client).map(Some),
+        }
+    }
+}
 
 /// The main function for the forward thread. The forward thread takes a stream of mangled
 /// packets from the [mangle thread](crate::mangle::mangle_main), and simply forwards them
-/// to the target address
+/// along its [route](Route)
+#[allow(clippy::too_many_arguments, reason = "Thread entry point")]
 pub(crate) fn forward_main(
     _config: Arc<ArcSwap<ManglerConfig>>,
+    clock: Arc<dyn Clock>,
     errs: Sender<Box<dyn Error + Send>>,
-    socket: UdpSocket,
+    route: Route,
+    reorder: Arc<ReorderTracker>,
     from_mangler: Receiver<Packet>,
+    observers: Arc<Observers>,
     quit: Arc<AtomicBool>,
 ) {
-    log::info!("Forwarding to address: {}", socket.peer_addr().unwrap());
-
     let mut packet: Option<Packet> = None;
 
     while !quit.load(Ordering::Acquire) {
@@ -38,12 +77,41 @@ pub(crate) fn forward_main(
 
         let cur_packet = packet.clone().unwrap();
 
-        let num_written = match socket.send(&cur_packet.content) {
-            Ok(num_written) => num_written,
+        let num_written = match route.send(&cur_packet) {
+            Ok(Some(num_written)) => num_written,
+            Ok(None) => {
+                log::debug!(
+                    "Dropping packet from {}, its session was already closed",
+                    cur_packet.client
+                );
+                observers.undelivered(
+                    &cur_packet,
+                    route.direction(),
+                    clock.now(),
+                    DropReason::Undeliverable,
+                );
+                packet = None;
+                continue;
+            }
             Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
                 // Retry loop
                 continue;
             }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                // The receiving side is not (yet) listening. Not fatal, it might come up later
+                log::debug!(
+                    "Dropping packet for {}, connection refused",
+                    cur_packet.client
+                );
+                observers.undelivered(
+                    &cur_packet,
+                    route.direction(),
+                    clock.now(),
+                    DropReason::Undeliverable,
+                );
+                packet = None;
+                continue;
+            }
             Err(e) => {
                 log::error!("Socket err: {e}");
                 _ = errs.send(Box::new(e));
@@ -53,6 +121,9 @@ pub(crate) fn forward_main(
 
         packet = None;
 
+        reorder.sent(cur_packet.id);
+        observers.sent(&cur_packet, route.direction(), clock.now());
+
         log::trace!("Forwarded {num_written} bytes");
     }
 }
@@ -0,0 +1,34 @@
+//! Virtual IP fragmentation of oversized packets
+
+/// The size of a UDP header, which travels in the first fragment of a datagram
+const UDP_HEADER_SIZE: usize = 8;
+
+/// What happens to packets larger than the [maximum payload size](crate::ImpairmentConfig::max_payload_size)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizeMode {
+    /// Oversized packets are dropped
+    #[default]
+    Drop,
+
+    /// Oversized packets are split into virtual fragments, like IP fragmentation would.
+    /// Every fragment is lost and delayed independently, and the packet is only delivered if all fragments
+    /// survive, at the moment the last fragment arrives
+    Fragment,
+}
+
+/// Returns the sizes of the virtual fragments a packet with `len` payload bytes is split into,
+/// given the maximum payload size of an unfragmented packet. Packets that fit are not split
+pub(crate) fn fragment_sizes(len: usize, max_payload_size: usize) -> Vec<usize> {
+    if len <= max_payload_size {
+        return vec![len];
+    }
+
+    // Fragments carry the UDP header and payload, in multiples of 8 bytes
+    let per_fragment = ((max_payload_size + UDP_HEADER_SIZE) / 8 * 8).max(8);
+    let total = len + UDP_HEADER_SIZE;
+
+    (0..total)
+        .step_by(per_fragment)
+        .map(|offset| per_fragment.min(total - offset))
+        .collect()
+}
@@ -0,0 +1,323 @@
+//! Replay of recorded delay and loss
+
+/// A recorded time series of delays and lost packets, for example from a ping log. While a trace is
+/// [configured](crate::ImpairmentConfig::latency_trace), packets get the delay recorded at the moment they
+/// arrive, counted from the start of the mangler, instead of the ping and jitter. Packets arriving while
+/// the trace records a loss are dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyTrace {
+    /// The samples, sorted by offset
+    samples: Vec<TraceSample>,
+
+    /// The length of the trace in seconds
+    duration_secs: f64,
+
+    /// Whether the trace starts over at the end. If not, the ping and jitter are used after the end
+    looping: bool,
+}
+
+/// A single sample of a [LatencyTrace], which holds until the next sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceSample {
+    /// The number of seconds since the start of the trace
+    pub offset_secs: f64,
+
+    /// The one-way delay of packets in seconds
+    pub delay_secs: f64,
+
+    /// Whether packets are lost
+    pub lost: bool,
+}
+
+/// Error while creating a [LatencyTrace]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum LatencyTraceErr {
+    /// The trace contains no samples
+    #[display("The trace contains no samples")]
+    Empty,
+
+    /// A line could not be parsed as a timestamp, delay, and lost flag
+    #[display("Invalid timestamp, delay or lost flag on line {}", _0)]
+    InvalidLine(#[error(not(source))] usize),
+
+    /// A sample is earlier than the one before it, or has a negative or infinite delay
+    #[display("Invalid offset or delay for sample {}", _0)]
+    InvalidSample(#[error(not(source))] usize),
+
+    /// The factor to scale the delays by is negative or not finite
+    #[display("Invalid delay scale {}", _0)]
+    InvalidScale(#[error(not(source))] f64),
+}
+
+impl LatencyTrace {
+    /// Creates a trace from samples sorted by offset. The trace lasts until the last sample plus the interval
+    /// between the last two samples, so that the last sample lasts as long as the one before it.
+    /// If `looping`, the trace starts over at the end
+    pub fn new(samples: Vec<TraceSample>, looping: bool) -> Result<Self, LatencyTraceErr> {
+        let mut prev_offset = 0.0;
+
+        for (i, sample) in samples.iter().enumerate() {
+            if !sample.offset_secs.is_finite()
+                || sample.offset_secs < prev_offset
+                || !sample.delay_secs.is_finite()
+                || sample.delay_secs < 0.0
+            {
+                return Err(LatencyTraceErr::InvalidSample(i + 1));
+            }
+
+            prev_offset = sample.offset_secs;
+        }
+
+        let duration_secs = match samples.as_slice() {
+            [] => return Err(LatencyTraceErr::Empty),
+            [.., prev, last] => 2.0 * last.offset_secs - prev.offset_secs,
+            // A single sample lasts forever
+            [_] => f64::INFINITY,
+        };
+
+        Ok(Self {
+            samples,
+            duration_secs,
+            looping,
+        })
+    }
+
+    /// Parses a trace from CSV, with one sample per line. Each line has a timestamp in seconds,
+    /// a delay in milliseconds, and optionally whether the packet was lost, as `1`/`0` or `true`/`false`.
+    /// The delay may be empty for lost packets. Timestamps are made relative to the first one, so both
+    /// absolute and relative timestamps work.
+    /// A header line, empty lines, and lines starting with `#` are ignored. The first line is taken as a header
+    /// if it does not start with a timestamp but does contain text
+    pub fn parse_csv(text: &str, looping: bool) -> Result<Self, LatencyTraceErr> {
+        let mut samples = Vec::new();
+        let mut first_timestamp = None;
+        let mut first_line = true;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let is_first_line = core::mem::replace(&mut first_line, false);
+
+            let Ok(timestamp) = fields[0].parse::<f64>() else {
+                if is_first_line && line.chars().any(char::is_alphabetic) {
+                    // Skip the header
+                    continue;
+                }
+
+                return Err(LatencyTraceErr::InvalidLine(i + 1));
+            };
+
+            let lost = match fields.get(2).copied() {
+                None | Some("" | "0" | "false") => false,
+                Some("1" | "true") => true,
+                Some(_) => return Err(LatencyTraceErr::InvalidLine(i + 1)),
+            };
+
+            let delay_millis = match fields.get(1).copied() {
+                None | Some("") if lost => 0.0,
+                Some(delay) => delay
+                    .parse::<f64>()
+                    .map_err(|_| LatencyTraceErr::InvalidLine(i + 1))?,
+                None => return Err(LatencyTraceErr::InvalidLine(i + 1)),
+            };
+
+            if fields.len() > 3 {
+                return Err(LatencyTraceErr::InvalidLine(i + 1));
+            }
+
+            let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+
+            samples.push(TraceSample {
+                offset_secs: timestamp - first_timestamp,
+                delay_secs: delay_millis / 1000.0,
+                lost,
+            });
+        }
+
+        Self::new(samples, looping)
+    }
+
+    /// Multiplies all delays by `factor`. For example, a factor of 0.5 turns round trip times into
+    /// one-way delays. The factor must be finite and not negative, and no delay may become infinite
+    pub fn scale_delays(&mut self, factor: f64) -> Result<(), LatencyTraceErr> {
+        if !factor.is_finite()
+            || factor < 0.0
+            || self
+                .samples
+                .iter()
+                .any(|sample| !(sample.delay_secs * factor).is_finite())
+        {
+            return Err(LatencyTraceErr::InvalidScale(factor));
+        }
+
+        for sample in &mut self.samples {
+            sample.delay_secs *= factor;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the sample at `elapsed_secs` since the start of the mangler,
+    /// or [None] if the trace has ended
+    pub(crate) fn sample_at(&self, elapsed_secs: f64) -> Option<&TraceSample> {
+        let offset = if self.looping && self.duration_secs > 0.0 {
+            elapsed_secs % self.duration_secs
+        } else {
+            elapsed_secs
+        };
+
+        if offset >= self.duration_secs {
+            return None;
+        }
+
+        let i = self
+            .samples
+            .partition_point(|sample| sample.offset_secs <= offset);
+
+        self.samples.get(i.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyTrace, LatencyTraceErr, TraceSample};
+
+    /// Returns a sample
+    fn sample(offset_secs: f64, delay_secs: f64, lost: bool) -> TraceSample {
+        TraceSample {
+            offset_secs,
+            delay_secs,
+            lost,
+        }
+    }
+
+    /// A header is skipped, timestamps are made relative, and lost samples may leave out the delay
+    #[test]
+    fn parse_csv() {
+        let trace = LatencyTrace::parse_csv(
+            "timestamp,delay_ms,lost
+            # A comment
+
+            100.0,20,0
+            100.5,,1
+            101.0,30.5,false
+            101.5,40",
+            true,
+        )
+        .expect("The trace is valid");
+
+        assert_eq!(
+            trace,
+            LatencyTrace::new(
+                vec![
+                    sample(0.0, 0.02, false),
+                    sample(0.5, 0.0, true),
+                    sample(1.0, 0.0305, false),
+                    sample(1.5, 0.04, false),
+                ],
+                true
+            )
+            .unwrap(),
+            "The parsed trace matches the text"
+        );
+        assert_eq!(
+            trace.sample_at(2.25),
+            Some(&sample(0.0, 0.02, false)),
+            "A looping trace starts over after the last sample has lasted as long as the one before it"
+        );
+    }
+
+    /// A trace that does not loop ends once its last sample has lasted as long as the one before it,
+    /// unless it has only a single sample
+    #[test]
+    fn end_of_trace() {
+        let trace = LatencyTrace::parse_csv("0,10\n1,20\n3,30", false).expect("The trace is valid");
+
+        assert_eq!(
+            trace.sample_at(0.5),
+            Some(&sample(0.0, 0.01, false)),
+            "The first sample holds until the second"
+        );
+        assert_eq!(
+            trace.sample_at(4.9),
+            Some(&sample(3.0, 0.03, false)),
+            "The last sample lasts two seconds"
+        );
+        assert_eq!(trace.sample_at(5.0), None, "The trace has ended");
+
+        let single = LatencyTrace::parse_csv("100,10", false).expect("The trace is valid");
+        assert_eq!(
+            single.sample_at(1e9),
+            Some(&sample(0.0, 0.01, false)),
+            "A single sample lasts forever"
+        );
+    }
+
+    /// Scaling turns round trip times into one-way delays
+    #[test]
+    fn scale_delays() {
+        let mut trace = LatencyTrace::parse_csv("0,10\n1,30", false).expect("The trace is valid");
+        trace.scale_delays(0.5).expect("The factor is valid");
+
+        assert_eq!(
+            trace.sample_at(1.5),
+            Some(&sample(1.0, 0.015, false)),
+            "The delay is halved"
+        );
+    }
+
+    /// Out of order timestamps are reported by the number of the sample, not of the line
+    #[test]
+    fn unsorted_timestamps() {
+        assert_eq!(
+            LatencyTrace::parse_csv("timestamp,delay_ms\n# Comment\n1,20\n0,20", false),
+            Err(LatencyTraceErr::InvalidSample(2)),
+            "The second sample is earlier than the first"
+        );
+    }
+
+    /// Only a first line with text in it is a header, a broken first sample is an error
+    #[test]
+    fn header_needs_text() {
+        assert_eq!(
+            LatencyTrace::parse_csv("0.0.1,20\n1,20", false),
+            Err(LatencyTraceErr::InvalidLine(1)),
+            "A mistyped timestamp is not a header"
+        );
+        assert_eq!(
+            LatencyTrace::parse_csv("# Comment\n-,20\n1,20", false),
+            Err(LatencyTraceErr::InvalidLine(2)),
+            "A missing timestamp is not a header either"
+        );
+    }
+
+    /// Delays must stay finite, both when parsed and when scaled
+    #[test]
+    fn infinite_delays() {
+        assert_eq!(
+            LatencyTrace::parse_csv("0,10\n1,inf", false),
+            Err(LatencyTraceErr::InvalidSample(2)),
+            "An infinite delay is rejected"
+        );
+
+        let mut trace =
+            LatencyTrace::parse_csv("0,10\n1,1e300", false).expect("The trace is valid");
+        let original = trace.clone();
+
+        for factor in [-1.0, f64::NAN, f64::INFINITY, 1e300] {
+            assert!(
+                matches!(
+                    trace.scale_delays(factor),
+                    Err(LatencyTraceErr::InvalidScale(_))
+                ),
+                "A factor of {factor} is rejected"
+            );
+        }
+        assert_eq!(trace, original, "Rejected factors leave the delays alone");
+    }
+}
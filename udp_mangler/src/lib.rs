@@ -1,24 +1,83 @@
 #![doc = include_str!("../README.md")]
 
 use core::error::Error;
-use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use core::net::SocketAddr;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::net::UdpSocket;
-use std::sync::mpsc::{Receiver, RecvError, channel};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvError, Sender, channel};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Instant;
 
 use arc_swap::ArcSwap;
-use forward::forward_main;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use decision_log::{DecisionLogConfig, DecisionLogFormat};
+pub use delivery_trace::{DeliveryTrace, DeliveryTraceErr};
+pub use distribution::{DistributionErr, EmpiricalDistribution, JitterDistribution};
+pub use events::{PacketEvent, PacketEventKind, Subscription, SubscriptionErr};
+use forward::{Route, forward_main};
+pub use fragment::OversizeMode;
+pub use latency_trace::{LatencyTrace, LatencyTraceErr, TraceSample};
 use listen::listen_main;
+pub use loss::{GilbertElliott, LossModel};
 use mangle::mangle_main;
-
+pub use mangle::{Datagram, MangleCore, ReadyDatagram};
+use network_model::network_model_main;
+pub use network_model::{DwellTime, NetworkModel, NetworkModelErr, NetworkState, Transition};
+use observe::Observers;
+pub use offline::OfflineStats;
+pub use pcap::PcapErr;
+use playback::Playback;
+use reorder::ReorderTracker;
+pub use reorder::{ReorderMode, ReorderStats};
+pub use replay::{DecisionRecording, DecisionRecordingErr, RecordedCopy, RecordedDecision};
+pub use rules::{BytePattern, Cidr, CidrErr, Rule, RuleAction, RuleErr};
+use scenario::scenario_main;
+pub use scenario::{Scenario, ScenarioErr, ScenarioStep, Setting};
+pub use script::{Script, ScriptErr};
+use session::SessionTable;
+pub use shaper::RateMode;
+pub use stage::{PipelineStage, Stage, StageContext, StagePacket, Verdict};
+pub use stats::{
+    ClientStats, Counters, DirectionStats, DropCounts, DropReason, Histogram, ManglerStats,
+    SecondStats,
+};
+use traffic::replay_main;
+pub use traffic::{RecordedPacket, ReplayTrafficErr, TrafficRecording};
+
+mod capture;
+mod clock;
+mod corrupt;
+mod decision_log;
+mod delivery_trace;
+mod distribution;
+mod events;
 mod forward;
+mod fragment;
+mod latency_trace;
 mod listen;
+mod loss;
 mod mangle;
+mod network_model;
+mod observe;
+mod offline;
+mod pcap;
+mod playback;
+mod reorder;
+mod replay;
+mod rules;
+mod scenario;
+mod script;
+mod session;
+mod shaper;
+mod sink;
+mod stage;
+mod stats;
+mod traffic;
 
 /// The main entrypoint for the [udp_mangler](crate) library. Create
 /// an instance with [Mangler::new]
@@ -27,14 +86,14 @@ pub struct Mangler {
     /// The current configuration
     config: Arc<ArcSwap<ManglerConfig>>,
 
-    /// Handle to the listen thread
-    listen_thread: Option<JoinHandle<()>>,
+    /// Handles to the listen thread, and the mangler and forward threads of both directions
+    threads: Vec<JoinHandle<()>>,
 
-    /// Handle to the mangler thread
-    mangler_thread: Option<JoinHandle<()>>,
+    /// Tracks the order of the packets sent upstream
+    upstream_reorder: Arc<ReorderTracker>,
 
-    /// Handle to the forward thread
-    forward_thread: Option<JoinHandle<()>>,
+    /// Tracks the order of the packets sent downstream
+    downstream_reorder: Arc<ReorderTracker>,
 
     /// Receiver that gets fatal errors encountered by the
     /// worker threads
@@ -42,6 +101,117 @@ pub struct Mangler {
 
     /// A flag that can be set to have the worker threads quit
     quit: Arc<AtomicBool>,
+
+    /// The seed of the random number generators
+    seed: u64,
+
+    /// The scenario or network model that is currently being played back, if any
+    playback: Mutex<Option<Playback>>,
+
+    /// The recorded traffic that is currently being replayed, if any
+    traffic_replay: Mutex<Option<Playback>>,
+
+    /// The sessions of the clients with the forward target
+    sessions: Arc<SessionTable>,
+
+    /// Sends packets to the upstream mangler thread, as if the listener received them
+    to_upstream_mangler: Sender<Packet>,
+
+    /// Sends packets to the downstream mangler thread, as if a session received them
+    to_downstream_mangler: Sender<Packet>,
+
+    /// Everything watching the packets, including the statistics
+    observers: Arc<Observers>,
+
+    /// The clock the arrival of the packets and the moments they are due are taken from
+    clock: Arc<dyn Clock>,
+}
+
+/// Opens the socket listening on `listen`, and a clone of it for sending replies to the clients.
+/// The downstream forward thread sends the replies from the listener socket, so that they arrive
+/// from the address the clients originally sent to
+fn open_listener(listen: SocketAddr) -> Result<(UdpSocket, UdpSocket), NewManglerErr> {
+    let listener_socket = UdpSocket::bind(listen).map_err(NewManglerErr::Listener)?;
+
+    listener_socket
+        .set_read_timeout(Some(Duration::from_secs_f64(0.1)))
+        .expect("Failed to set read timeout on listener socket");
+
+    let reply_socket = listener_socket
+        .try_clone()
+        .map_err(NewManglerErr::Listener)?;
+
+    reply_socket
+        .set_write_timeout(Some(Duration::from_secs_f64(0.1)))
+        .expect("Failed to set write timeout on reply socket");
+
+    Ok((listener_socket, reply_socket))
+}
+
+/// Spawns the mangler thread of `direction`, with its random decisions derived from `seed`.
+/// It mangles the packets from `from_listener`, and passes them on to `to_forward`
+fn spawn_mangle(
+    config: &Arc<ArcSwap<ManglerConfig>>,
+    (direction, seed): (Direction, u64),
+    clock: &Arc<dyn Clock>,
+    errs: &Sender<Box<dyn Error + Send>>,
+    (from_listener, to_forward): (Receiver<Packet>, Sender<Packet>),
+    observers: &Arc<Observers>,
+    quit: &Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let quit_cloned = quit.clone();
+    let cloned_config = config.clone();
+    let clock_cloned = clock.clone();
+    let err_send_cloned = errs.clone();
+    let observers_cloned = observers.clone();
+    std::thread::spawn(move || {
+        mangle_main(
+            cloned_config,
+            direction,
+            seed,
+            clock_cloned,
+            err_send_cloned,
+            from_listener,
+            to_forward,
+            observers_cloned,
+            quit_cloned,
+        )
+    })
+}
+
+/// Spawns a forward thread that sends the packets from `from_mangler` along `route`.
+/// Returns the handle of the thread, and the tracker of the order of the packets it sends
+fn spawn_forward(
+    config: &Arc<ArcSwap<ManglerConfig>>,
+    clock: &Arc<dyn Clock>,
+    errs: &Sender<Box<dyn Error + Send>>,
+    route: Route,
+    from_mangler: Receiver<Packet>,
+    observers: &Arc<Observers>,
+    quit: &Arc<AtomicBool>,
+) -> (JoinHandle<()>, Arc<ReorderTracker>) {
+    let reorder = Arc::new(ReorderTracker::default());
+
+    let quit_cloned = quit.clone();
+    let cloned_config = config.clone();
+    let clock_cloned = clock.clone();
+    let err_send_cloned = errs.clone();
+    let observers_cloned = observers.clone();
+    let reorder_cloned = reorder.clone();
+    let thread = std::thread::spawn(move || {
+        forward_main(
+            cloned_config,
+            clock_cloned,
+            err_send_cloned,
+            route,
+            reorder_cloned,
+            from_mangler,
+            observers_cloned,
+            quit_cloned,
+        )
+    });
+
+    (thread, reorder)
 }
 
 /// Error while constructing a new mangler
@@ -51,111 +221,328 @@ pub enum NewManglerErr {
     #[display("Error opening listener socket: {}", _0)]
     Listener(std::io::Error),
 
-    /// Could not open the UDP socket that is used for forwarding the mangled packets
-    #[display("Error opening forwarder socket: {}", _0)]
-    Forwarder(std::io::Error),
+    /// Could not create the capture file
+    #[display("Error creating capture file: {}", _0)]
+    Capture(std::io::Error),
+
+    /// Could not create the decision log file
+    #[display("Error creating decision log file: {}", _0)]
+    DecisionLog(std::io::Error),
+
+    /// Could not create the decision recording file
+    #[display("Error creating decision recording file: {}", _0)]
+    DecisionRecording(std::io::Error),
+
+    /// Could not create the traffic recording file
+    #[display("Error creating traffic recording file: {}", _0)]
+    TrafficRecording(std::io::Error),
 }
 
 impl Mangler {
     /// Creates a new mangler that listens for incoming packets on `listen`, then mangles them
-    /// according to the given `config`, and then finally forwards them to `forward`
+    /// according to the given `config`, and then finally forwards them to `forward`.
+    /// Any replies from `forward` are mangled as well, and then sent back to the client that
+    /// sent the original packets
     pub fn new(
         listen: SocketAddr,
         forward: SocketAddr,
         config: ManglerConfig,
     ) -> Result<Self, NewManglerErr> {
+        Self::with_clock(listen, forward, config, Arc::new(SystemClock))
+    }
+
+    /// Creates a new mangler like [new](Self::new), which takes the arrival of the packets and the moments they
+    /// are due from `clock` instead of the system clock
+    pub fn with_clock(
+        listen: SocketAddr,
+        forward: SocketAddr,
+        config: ManglerConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, NewManglerErr> {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        let observers = Arc::new(Observers::new(&config, listen, forward, &clock)?);
         let config = Arc::new(ArcSwap::from_pointee(config));
         let quit = Arc::new(AtomicBool::new(false));
 
-        let (to_mangler_send, to_mangler_recv) = channel::<Packet>();
-        let (to_forward_send, to_forward_recv) = channel::<Packet>();
+        let (to_upstream_mangler_send, to_upstream_mangler_recv) = channel::<Packet>();
+        let (to_downstream_mangler_send, to_downstream_mangler_recv) = channel::<Packet>();
+        let (to_upstream_forward_send, to_upstream_forward_recv) = channel::<Packet>();
+        let (to_downstream_forward_send, to_downstream_forward_recv) = channel::<Packet>();
         let (err_send, err_recv) = channel::<Box<dyn Error + Send>>();
 
-        let listener_socket = UdpSocket::bind(listen).map_err(NewManglerErr::Listener)?;
+        let (listener_socket, reply_socket) = open_listener(listen)?;
+        let sessions = Arc::new(SessionTable::new(forward, clock.clone(), observers.clone()));
 
-        listener_socket
-            .set_read_timeout(Some(Duration::from_secs_f64(0.1)))
-            .expect("Failed to set read timeout on listener socket");
-
-        let forwarder_socket = UdpSocket::bind(if forward.is_ipv4() {
-            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
-        } else {
-            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
-        })
-        .map_err(NewManglerErr::Forwarder)?;
-
-        forwarder_socket
-            .connect(forward)
-            .map_err(NewManglerErr::Forwarder)?;
-
-        forwarder_socket
-            .set_write_timeout(Some(Duration::from_secs_f64(0.1)))
-            .expect("Failed to set write timeout on forwarder socket");
+        log::info!("Forwarding to address: {forward}");
+        log::info!("Using random seed: {seed}");
 
         let quit_cloned = quit.clone();
         let cloned_config = config.clone();
+        let clock_cloned = clock.clone();
         let err_send_cloned = err_send.clone();
+        let sessions_cloned = sessions.clone();
+        let to_upstream_mangler_cloned = to_upstream_mangler_send.clone();
+        let to_downstream_mangler_cloned = to_downstream_mangler_send.clone();
+        let observers_cloned = observers.clone();
         let listen_thread = std::thread::spawn(move || {
             listen_main(
                 cloned_config,
+                clock_cloned,
                 err_send_cloned,
                 listener_socket,
-                to_mangler_send,
+                sessions_cloned,
+                to_upstream_mangler_cloned,
+                to_downstream_mangler_cloned,
+                observers_cloned,
                 quit_cloned,
             )
         });
 
-        let quit_cloned = quit.clone();
-        let cloned_config = config.clone();
-        let err_send_cloned = err_send.clone();
-        let mangler_thread = std::thread::spawn(move || {
-            mangle_main(
-                cloned_config,
-                err_send_cloned,
-                to_mangler_recv,
-                to_forward_send,
-                quit_cloned,
-            )
-        });
+        let mut threads = vec![listen_thread];
+
+        for (direction, from_listener, to_forward) in [
+            (
+                Direction::Upstream,
+                to_upstream_mangler_recv,
+                to_upstream_forward_send,
+            ),
+            (
+                Direction::Downstream,
+                to_downstream_mangler_recv,
+                to_downstream_forward_send,
+            ),
+        ] {
+            threads.push(spawn_mangle(
+                &config,
+                (direction, seed),
+                &clock,
+                &err_send,
+                (from_listener, to_forward),
+                &observers,
+                &quit,
+            ));
+        }
 
-        let quit_cloned = quit.clone();
-        let cloned_config = config.clone();
-        let err_send_cloned = err_send.clone();
-        let forward_thread = std::thread::spawn(move || {
-            forward_main(
-                cloned_config,
-                err_send_cloned,
-                forwarder_socket,
-                to_forward_recv,
-                quit_cloned,
-            )
-        });
+        let (upstream_forward, upstream_reorder) = spawn_forward(
+            &config,
+            &clock,
+            &err_send,
+            Route::ToTarget(sessions.clone()),
+            to_upstream_forward_recv,
+            &observers,
+            &quit,
+        );
+        let (downstream_forward, downstream_reorder) = spawn_forward(
+            &config,
+            &clock,
+            &err_send,
+            Route::ToClient(reply_socket),
+            to_downstream_forward_recv,
+            &observers,
+            &quit,
+        );
+        threads.extend([upstream_forward, downstream_forward]);
 
         Ok(Self {
             config,
-            listen_thread: Some(listen_thread),
-            mangler_thread: Some(mangler_thread),
-            forward_thread: Some(forward_thread),
+            threads,
+            upstream_reorder,
+            downstream_reorder,
             errs: Mutex::new(err_recv),
             quit,
+            seed,
+            playback: Mutex::new(None),
+            traffic_replay: Mutex::new(None),
+            sessions,
+            to_upstream_mangler: to_upstream_mangler_send,
+            to_downstream_mangler: to_downstream_mangler_send,
+            observers,
+            clock,
         })
     }
 
+    /// Mangles the UDP packets of the pcap file `input` according to `config` without any sockets, and writes the
+    /// result to the pcap file `output`. The timestamps of the capture are used as the clock, so this takes no longer
+    /// than reading and writing the files.
+    ///
+    /// Packets sent from `server` travel downstream, and all other packets travel upstream. Without a server all
+    /// packets travel upstream. The output has raw IP packets with the addresses of the input, and the timestamps at
+    /// which the mangler would have sent them. Packets that are not unfragmented UDP packets are left out.
+    /// Scenarios, network models and the observers in `config`, like the capture, are not used
+    pub fn mangle_pcap(
+        input: impl Read,
+        output: impl Write,
+        server: Option<SocketAddr>,
+        config: &ManglerConfig,
+    ) -> Result<OfflineStats, PcapErr> {
+        offline::mangle_pcap(
+            BufReader::new(input),
+            BufWriter::new(output),
+            server,
+            config,
+        )
+    }
+
     /// Updates the config used for mangling
     pub fn update_config(&self, new_config: ManglerConfig) {
         self.config.store(Arc::new(new_config));
     }
 
+    /// Starts playing back a [Scenario], which changes the config over time. The config at the moment
+    /// this is called is used as the baseline of the scenario. Any scenario or network model that is
+    /// already playing is stopped first. Changes made with [update_config](Self::update_config)
+    /// during playback last until the scenario's next change
+    pub fn play_scenario(&self, scenario: Scenario) {
+        let config = self.config.clone();
+
+        self.start_playback(move |control| scenario_main(config, scenario, control));
+    }
+
+    /// Starts moving through the states of a [NetworkModel], switching the impairments of both directions
+    /// whenever the state changes. Any scenario or network model that is already playing is stopped first.
+    /// The random choices of the model are derived from the [seed](Self::seed).
+    ///
+    /// Fails if a dwell time of the model is negative or not finite, in which case the current playback keeps playing
+    pub fn play_network_model(&self, model: NetworkModel) -> Result<(), NetworkModelErr> {
+        model.validate()?;
+
+        let config = self.config.clone();
+        let seed = self.seed;
+
+        self.start_playback(move |control| network_model_main(config, model, seed, control));
+
+        Ok(())
+    }
+
+    /// Returns the name of the current state of the network model that is being played back, if any
+    pub fn network_state(&self) -> Option<String> {
+        self.playback.lock().unwrap().as_ref()?.state()
+    }
+
+    /// Stops the scenario or network model that is currently being played back, if any.
+    /// The config stays as the playback left it
+    pub fn stop_playback(&self) {
+        if let Some(playback) = self.playback.lock().unwrap().take() {
+            playback.stop();
+        }
+    }
+
+    /// Stops the current playback, and starts running `run` on a new playback thread
+    fn start_playback(&self, run: impl FnOnce(playback::PlaybackControl) + Send + 'static) {
+        let mut playback = self.playback.lock().unwrap();
+
+        if let Some(previous) = playback.take() {
+            previous.stop();
+        }
+
+        *playback = Some(Playback::start(self.quit.clone(), self.clock.clone(), run));
+    }
+
+    /// Starts replaying a [TrafficRecording] into the forward target, as if its clients sent the packets again.
+    /// The packets are mangled like any other upstream packets, and keep their recorded timing multiplied by
+    /// `time_scale`, so that 0.5 replays twice as fast. Replayed clients get sessions of their own, apart from any
+    /// live traffic of the same address, and the replies of the forward target to them are discarded.
+    /// Any recording that is already being replayed is stopped first.
+    ///
+    /// Fails if `time_scale` is negative or not finite, in which case nothing is replayed
+    pub fn replay_traffic(
+        &self,
+        recording: TrafficRecording,
+        time_scale: f64,
+    ) -> Result<(), ReplayTrafficErr> {
+        if !time_scale.is_finite()
+            || time_scale < 0.0
+            || Duration::try_from_secs_f64(recording.duration().as_secs_f64() * time_scale).is_err()
+        {
+            return Err(ReplayTrafficErr::InvalidTimeScale(time_scale));
+        }
+
+        let config = self.config.clone();
+        let clock = self.clock.clone();
+        let sessions = self.sessions.clone();
+        let to_upstream_mangler = self.to_upstream_mangler.clone();
+        let to_downstream_mangler = self.to_downstream_mangler.clone();
+        let observers = self.observers.clone();
+
+        let mut traffic_replay = self.traffic_replay.lock().unwrap();
+
+        if let Some(previous) = traffic_replay.take() {
+            previous.stop();
+        }
+
+        *traffic_replay = Some(Playback::start(
+            self.quit.clone(),
+            clock.clone(),
+            move |control| {
+                replay_main(
+                    config,
+                    clock,
+                    recording,
+                    time_scale,
+                    sessions,
+                    to_upstream_mangler,
+                    to_downstream_mangler,
+                    observers,
+                    control,
+                )
+            },
+        ));
+
+        Ok(())
+    }
+
+    /// Stops the replay of recorded traffic, if any
+    pub fn stop_traffic_replay(&self) {
+        if let Some(traffic_replay) = self.traffic_replay.lock().unwrap().take() {
+            traffic_replay.stop();
+        }
+    }
+
+    /// Returns statistics on how the order of the packets sent in the given direction differs
+    /// from the order in which they arrived
+    pub fn reorder_stats(&self, direction: Direction) -> ReorderStats {
+        match direction {
+            Direction::Upstream => self.upstream_reorder.stats(),
+            Direction::Downstream => self.downstream_reorder.stats(),
+        }
+    }
+
+    /// Returns the config currently in use, which includes any changes made by a playing scenario or network model
+    pub fn config(&self) -> Arc<ManglerConfig> {
+        self.config.load_full()
+    }
+
+    /// Returns a snapshot of the statistics on the packets the mangler received, dropped and forwarded
+    pub fn stats(&self) -> ManglerStats {
+        self.observers.stats.snapshot()
+    }
+
+    /// Subscribes to the events of every packet passing through the mangler from now on.
+    /// The subscription ends once the mangler has stopped
+    pub fn subscribe(&self) -> Subscription {
+        self.observers.events.subscribe()
+    }
+
+    /// Returns the seed of the random number generators. This is either the [configured seed](ManglerConfig::seed),
+    /// or the one that was randomly chosen at startup
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
     /// Stops the mangler threads gracefully.
     /// The threads themselves are not guaranteed to be done until after this [Mangler] is [dropped](drop)
     pub fn stop(&self) {
         self.quit.store(true, Ordering::Release);
     }
 
-    /// Blocks the main thread until the mangler stops by itself
+    /// Blocks the main thread until the mangler stops by itself, or until one of its threads fails.
+    /// Either way, all [subscriptions](Self::subscribe) end, and the files written by the mangler are flushed
     pub fn wait_until_complete(&self) -> Result<(), Box<dyn Error>> {
         let result = self.errs.lock().unwrap().recv();
 
+        // A failed thread means the mangler is broken, so its observers are done just as if it had stopped
+        self.observers.finish();
+
         match result {
             Ok(err) => {
                 log::error!("Received error: {err}");
@@ -163,7 +550,7 @@ impl Mangler {
             }
             Err(RecvError) => {
                 // Channel was closed before any error was returned.
-                // This is the "good" scenario
+                // This is the "good" scenario, and all threads have stopped so nothing more will be observed
                 Ok(())
             }
         }
@@ -178,20 +565,27 @@ impl Drop for Mangler {
 
         _ = self.wait_until_complete();
 
-        if let Some(th) = self.listen_thread.take() {
-            th.join().expect("Failed to join listener thread");
+        for th in self.threads.drain(..) {
+            th.join().expect("Failed to join worker thread");
         }
 
-        if let Some(th) = self.mangler_thread.take() {
-            th.join().expect("Failed to join mangler thread");
-        }
-
-        if let Some(th) = self.forward_thread.take() {
-            th.join().expect("Failed to join forward thread");
-        }
+        self.stop_playback();
+        self.stop_traffic_replay();
     }
 }
 
+/// The direction in which a packet travels through the [Mangler]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum Direction {
+    /// From a client to the forward target
+    #[display("upstream")]
+    Upstream,
+
+    /// From the forward target back to a client
+    #[display("downstream")]
+    Downstream,
+}
+
 /// The configuration for a [Mangler]
 #[derive(Debug, Clone, PartialEq)]
 pub struct ManglerConfig {
@@ -200,28 +594,203 @@ pub struct ManglerConfig {
     /// are dropped without any processing
     pub buffer_size: usize,
 
+    /// The number of seconds after which a client session without any traffic in either
+    /// direction is closed. Any later replies from the forward target to that client are lost
+    pub session_timeout_secs: f64,
+
+    /// The impairments applied to packets from the clients to the forward target
+    pub upstream: ImpairmentConfig,
+
+    /// The impairments applied to replies from the forward target back to the clients
+    pub downstream: ImpairmentConfig,
+
+    /// The seed for the random decisions of the impairments. With the same seed, the same
+    /// packets arriving in the same order get the same decisions, so that a run can be reproduced.
+    /// If [None], a random seed is chosen. Only used when the [Mangler] is created
+    pub seed: Option<u64>,
+
+    /// Rules that give selected packets different impairments. The first matching rule applies,
+    /// and packets that match no rule get the impairments of their direction
+    pub rules: Vec<Rule>,
+
+    /// A pcapng file to which every packet entering and leaving the mangler is written, with synthesized
+    /// IP and UDP headers. Every packet entering the mangler has a comment on whether it was dropped, or how
+    /// it was delayed, duplicated, corrupted and reordered. Only used when the [Mangler] is created
+    pub capture: Option<PathBuf>,
+
+    /// A log to which a record of the decisions on every packet is written, for offline analysis.
+    /// Only used when the [Mangler] is created
+    pub decision_log: Option<DecisionLogConfig>,
+
+    /// A file to which the decision for every packet is recorded, so that it can be [replayed](Self::replay_decisions)
+    /// later. Only used when the [Mangler] is created
+    pub record_decisions: Option<PathBuf>,
+
+    /// A recording of the decisions of an earlier run. While set, every packet gets the recorded decision
+    /// for its position in its flow instead of a new one
+    pub replay_decisions: Option<DecisionRecording>,
+
+    /// A file to which the packets the clients send to the mangler are recorded, with their timing, so that they can
+    /// be [replayed](Mangler::replay_traffic) later. Only used when the [Mangler] is created
+    pub record_traffic: Option<PathBuf>,
+
+    /// The stages every packet passes through, in order. Packets selected by a [rule](Self::rules)
+    /// that passes or drops them skip the pipeline
+    pub pipeline: Vec<PipelineStage>,
+}
+
+impl ManglerConfig {
+    /// Returns the impairments applied to packets travelling in the given direction
+    pub fn impairments(&self, direction: Direction) -> &ImpairmentConfig {
+        match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        }
+    }
+
+    /// Returns a mutable reference to the impairments applied to packets travelling in the given direction
+    pub fn impairments_mut(&mut self, direction: Direction) -> &mut ImpairmentConfig {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+}
+
+impl Default for ManglerConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: u16::MAX as usize,
+            session_timeout_secs: 60.0,
+            upstream: ImpairmentConfig::default(),
+            downstream: ImpairmentConfig::default(),
+            seed: None,
+            rules: Vec::new(),
+            capture: None,
+            decision_log: None,
+            record_decisions: None,
+            replay_decisions: None,
+            record_traffic: None,
+            pipeline: PipelineStage::defaults(),
+        }
+    }
+}
+
+/// The impairments applied to the packets travelling in a single [Direction]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpairmentConfig {
     /// The maximum payload size of a UDP packet before it is either dropped by or fragmented by
-    /// the mangler
+    /// the mangler, depending on the [oversize mode](Self::oversize_mode)
     pub max_payload_size: usize,
 
-    /// The factor (between 0.0 and 1.0 inclusive) of randomly dropped packets
-    pub loss_factor: f64,
+    /// What happens to packets larger than the [maximum payload size](Self::max_payload_size)
+    pub oversize_mode: OversizeMode,
+
+    /// The model deciding which packets are randomly dropped
+    pub loss: LossModel,
 
     /// Additional ping to add
     pub ping_secs: f64,
 
     /// Additional jitter to add
     pub jitter_secs: f64,
+
+    /// The distribution the jitter is sampled from
+    pub jitter_distribution: JitterDistribution,
+
+    /// Whether packets of the same flow (client) keep their order. If set, jitter never lets a packet
+    /// overtake the previous packet of its flow. Explicit reordering still applies
+    pub preserve_order: bool,
+
+    /// A recorded trace of delays and losses to replay. While the trace lasts, it replaces the ping and jitter,
+    /// and its losses come on top of the loss model
+    pub latency_trace: Option<LatencyTrace>,
+
+    /// The factor (between 0.0 and 1.0 inclusive) of packets that are duplicated
+    pub duplicate_factor: f64,
+
+    /// The number of extra copies sent of each duplicated packet
+    pub duplicate_copies: usize,
+
+    /// Additional delay of the extra copies of a duplicated packet. Every extra copy gets a delay of its own,
+    /// sampled like that of the original, and the n-th extra copy is sent n times this later on top of that
+    pub duplicate_delay_secs: f64,
+
+    /// The factor (between 0.0 and 1.0 inclusive) of packets that are explicitly reordered,
+    /// independent of any reordering caused by jitter
+    pub reorder_factor: f64,
+
+    /// How explicitly reordered packets are held back
+    pub reorder_mode: ReorderMode,
+
+    /// The probability (between 0.0 and 1.0 inclusive) of each payload bit being flipped.
+    /// Larger packets are proportionally more likely to be corrupted
+    pub bit_error_rate: f64,
+
+    /// The first payload byte that may be corrupted. Can be used to leave
+    /// an application header intact
+    pub corrupt_offset: usize,
+
+    /// The number of payload bytes, starting at [corrupt_offset](Self::corrupt_offset), that may be corrupted.
+    /// If [None], everything up to the end of the payload may be corrupted
+    pub corrupt_length: Option<usize>,
+
+    /// The number of consecutive bits flipped by each bit error. A value of 1 gives independent
+    /// bit errors, larger values give burst errors
+    pub corrupt_burst_bits: usize,
+
+    /// The capacity of the link in bits per second, or [None] for unlimited capacity.
+    /// Each packet takes time to be serialized onto the link depending on its size,
+    /// and packets are queued behind each other. A rate that is not a positive finite number is unlimited as well
+    pub rate_bits_per_sec: Option<f64>,
+
+    /// The size of the token bucket in bytes. Up to this many bytes can be sent in a burst
+    /// without any serialization delay, after the link has been idle. When [policing](RateMode::Police),
+    /// the bucket holds at least the [maximum payload size](Self::max_payload_size), so that a single packet can pass
+    pub rate_burst_bytes: usize,
+
+    /// A trace of delivery opportunities that determines the capacity of the link over time.
+    /// If set, it replaces the [rate](Self::rate_bits_per_sec) and [burst size](Self::rate_burst_bytes)
+    pub delivery_trace: Option<DeliveryTrace>,
+
+    /// The maximum number of packets per second, or [None] for no limit.
+    /// A rate that is not a positive finite number is no limit as well
+    pub rate_packets_per_sec: Option<f64>,
+
+    /// The maximum number of bytes waiting for the link. Packets that would exceed this limit are dropped.
+    /// If [None], the queue is unbounded
+    pub rate_queue_limit_bytes: Option<usize>,
+
+    /// Whether packets exceeding the rate are queued or dropped
+    pub rate_mode: RateMode,
 }
 
-impl Default for ManglerConfig {
+impl Default for ImpairmentConfig {
     fn default() -> Self {
         Self {
-            buffer_size: u16::MAX as usize,
             max_payload_size: 1472,
-            loss_factor: 0.005,
+            oversize_mode: OversizeMode::default(),
+            loss: LossModel::default(),
             ping_secs: 0.050,   // 50 ms
             jitter_secs: 0.020, // 20 ms
+            jitter_distribution: JitterDistribution::default(),
+            preserve_order: false,
+            latency_trace: None,
+            duplicate_factor: 0.0,
+            duplicate_copies: 1,
+            duplicate_delay_secs: 0.0,
+            reorder_factor: 0.0,
+            reorder_mode: ReorderMode::default(),
+            bit_error_rate: 0.0,
+            corrupt_offset: 0,
+            corrupt_length: None,
+            corrupt_burst_bits: 1,
+            rate_bits_per_sec: None,
+            rate_burst_bytes: 0,
+            delivery_trace: None,
+            rate_packets_per_sec: None,
+            rate_queue_limit_bytes: None,
+            rate_mode: RateMode::default(),
         }
     }
 }
@@ -232,6 +801,30 @@ struct Packet {
     /// The timestamp at which point this packet should be sent out
     send_timestamp: Instant,
 
+    /// The client this packet was received from when travelling upstream,
+    /// or the client it is destined for when travelling downstream
+    client: SocketAddr,
+
+    /// The order in which this packet arrived at the mangler, counted separately for each [Direction]
+    id: u64,
+
+    /// The number of the flow of the client, in the order in which the clients were first seen
+    flow: u64,
+
+    /// Whether this packet is part of a [replay of recorded traffic](Mangler::replay_traffic), which
+    /// goes through sessions of its own, apart from any live traffic of the same client
+    replayed: bool,
+
+    /// The number of earlier packets of the same flow in the same [Direction]
+    flow_index: u64,
+
+    /// The order in which this packet was scheduled, used to keep packets with
+    /// the same send timestamp apart
+    seq: u64,
+
+    /// The number of later packets that must overtake this packet once it is due, for explicit reordering
+    hold_positions: usize,
+
     /// The raw packet payload
     content: Vec<u8>,
 }
@@ -262,7 +855,7 @@ impl DerefMut for ByTimestamp {
 
 impl PartialEq for ByTimestamp {
     fn eq(&self, other: &Self) -> bool {
-        self.0.send_timestamp == other.0.send_timestamp
+        (self.0.send_timestamp, self.0.seq) == (other.0.send_timestamp, other.0.seq)
     }
 }
 
@@ -271,12 +864,12 @@ impl Eq for ByTimestamp {}
 #[allow(clippy::non_canonical_partial_ord_impl, reason = "Forward to Instant")]
 impl PartialOrd for ByTimestamp {
     fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        self.0.send_timestamp.partial_cmp(&other.0.send_timestamp)
+        (self.0.send_timestamp, self.0.seq).partial_cmp(&(other.0.send_timestamp, other.0.seq))
     }
 }
 
 impl Ord for ByTimestamp {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.0.send_timestamp.cmp(&other.0.send_timestamp)
+        (self.0.send_timestamp, self.0.seq).cmp(&(other.0.send_timestamp, other.0.seq))
     }
 }
@@ -2,28 +2,42 @@
 
 use core::error::Error;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 use std::io::ErrorKind;
 use std::net::UdpSocket;
 use std::sync::Arc;
 use std::sync::mpsc::{SendError, Sender};
-use std::time::Instant;
 
 use arc_swap::ArcSwap;
 
-use crate::{ManglerConfig, Packet};
+use crate::clock::Clock;
+use crate::mangle::Fate;
+use crate::observe::Observers;
+use crate::session::SessionTable;
+use crate::stats::DropReason;
+use crate::{Direction, ManglerConfig, Packet};
 
 /// The main function for the listener thread. The listener thread reads input packets from a UDP socket, and simply
-/// forwards them to the [mangler thread](crate::mangle::mangle_main)
+/// forwards them to the upstream [mangler thread](crate::mangle::mangle_main).
+/// It also opens a [session](crate::session::Session) for each new client, whose replies are sent to the
+/// downstream mangler thread, and closes those sessions again once they become idle
+#[allow(clippy::too_many_arguments, reason = "Thread entry point")]
 pub(crate) fn listen_main(
     config: Arc<ArcSwap<ManglerConfig>>,
+    clock: Arc<dyn Clock>,
     errs: Sender<Box<dyn Error + Send>>,
     socket: UdpSocket,
-    to_mangler: Sender<Packet>,
+    sessions: Arc<SessionTable>,
+    to_upstream_mangler: Sender<Packet>,
+    to_downstream_mangler: Sender<Packet>,
+    observers: Arc<Observers>,
     quit: Arc<AtomicBool>,
 ) {
     let mut buffer = Vec::new();
 
     while !quit.load(Ordering::Acquire) {
+        sessions.close_idle(Duration::from_secs_f64(config.load().session_timeout_secs));
+
         buffer.clear();
         buffer.resize(config.load().buffer_size, 0);
 
@@ -33,6 +47,15 @@ pub(crate) fn listen_main(
                 // Retry loop
                 continue;
             }
+            Err(e)
+                if e.kind() == ErrorKind::ConnectionReset
+                    || e.kind() == ErrorKind::ConnectionRefused =>
+            {
+                // On Windows, an ICMP port unreachable for an earlier reply shows up here. Not fatal,
+                // the client might just have gone away
+                log::debug!("Ignoring socket err: {e}");
+                continue;
+            }
             Err(e) => {
                 log::error!("Socket err: {e}");
                 _ = errs.send(Box::new(e));
@@ -42,22 +65,59 @@ pub(crate) fn listen_main(
 
         if packet_size >= buffer.len() {
             // Packet might be truncated
+            observers.received(
+                None,
+                &buffer,
+                sender_addr,
+                Direction::Upstream,
+                clock.now(),
+                &Fate::dropped(DropReason::Truncated),
+            );
             continue;
         }
 
         log::trace!("New UDP packet of size {packet_size} from {sender_addr}");
 
+        let flow = match sessions.get_or_open(
+            sender_addr,
+            false,
+            &config,
+            &to_downstream_mangler,
+            &quit,
+        ) {
+            Ok(session) => {
+                session.touch(clock.now());
+                session.flow()
+            }
+            Err(e) => {
+                log::warn!("Dropping packet, could not open session for {sender_addr}: {e}");
+                continue;
+            }
+        };
+
+        let now = clock.now();
+        observers.arrived(&buffer[..packet_size], sender_addr, now);
+
         let packet = Packet {
-            send_timestamp: Instant::now(),
+            send_timestamp: now,
+            client: sender_addr,
+            id: 0,
+            flow,
+            replayed: false,
+            flow_index: 0,
+            seq: 0,
+            hold_positions: 0,
             content: Vec::from(&buffer[..packet_size]),
         };
 
-        match to_mangler.send(packet) {
+        match to_upstream_mangler.send(packet) {
             Ok(val) => val,
             Err(SendError(_)) => {
                 log::debug!("Listener thread returning because the to_mangler channel has closed");
-                return;
+                break;
             }
         };
     }
+
+    sessions.close_all();
 }
@@ -0,0 +1,104 @@
+//! Packet loss models
+
+use rand::{Rng, RngExt};
+
+/// The model used to decide which packets are randomly dropped
+#[derive(Debug, Clone, PartialEq)]
+pub enum LossModel {
+    /// Every packet is dropped independently with the given factor (between 0.0 and 1.0 inclusive)
+    Uniform(f64),
+
+    /// Packets are dropped in bursts, according to a [Gilbert-Elliott](GilbertElliott) model
+    GilbertElliott(GilbertElliott),
+}
+
+impl Default for LossModel {
+    fn default() -> Self {
+        Self::Uniform(0.005)
+    }
+}
+
+impl LossModel {
+    /// Returns the fraction of packets dropped in the long run
+    pub fn mean_loss(&self) -> f64 {
+        match self {
+            Self::Uniform(factor) => *factor,
+            Self::GilbertElliott(ge) => {
+                let transitions = ge.good_to_bad + ge.bad_to_good;
+
+                if transitions == 0.0 {
+                    // The link never leaves the good state it starts in
+                    ge.loss_good
+                } else {
+                    (ge.bad_to_good * ge.loss_good + ge.good_to_bad * ge.loss_bad) / transitions
+                }
+            }
+        }
+    }
+}
+
+/// A two-state Gilbert-Elliott loss model. The link is either in a good or a bad state, and moves
+/// between them with the given transition probabilities for every packet. Each state has its own
+/// loss rate, which makes losses come in bursts while the link is in the bad state.
+///
+/// All values are probabilities between 0.0 and 1.0 inclusive
+#[derive(Debug, Clone, PartialEq)]
+pub struct GilbertElliott {
+    /// The probability of moving from the good to the bad state
+    pub good_to_bad: f64,
+
+    /// The probability of moving from the bad to the good state
+    pub bad_to_good: f64,
+
+    /// The probability of a packet being dropped while in the good state
+    pub loss_good: f64,
+
+    /// The probability of a packet being dropped while in the bad state
+    pub loss_bad: f64,
+}
+
+impl Default for GilbertElliott {
+    fn default() -> Self {
+        Self {
+            good_to_bad: 0.01,
+            bad_to_good: 0.25,
+            loss_good: 0.0,
+            loss_bad: 0.5,
+        }
+    }
+}
+
+/// The running state of a [LossModel]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LossState {
+    /// Whether a [Gilbert-Elliott](GilbertElliott) model is currently in its bad state
+    bad: bool,
+}
+
+impl LossState {
+    /// Decides whether the next packet should be dropped according to the given model
+    pub(crate) fn should_drop(&mut self, model: &LossModel, rng: &mut impl Rng) -> bool {
+        match model {
+            LossModel::Uniform(factor) => *factor != 0.0 && rng.random::<f64>() < *factor,
+            LossModel::GilbertElliott(ge) => {
+                let transition = if self.bad {
+                    ge.bad_to_good
+                } else {
+                    ge.good_to_bad
+                };
+
+                if transition != 0.0 && rng.random::<f64>() < transition {
+                    self.bad = !self.bad;
+                    log::trace!(
+                        "Loss model moved to {} state",
+                        if self.bad { "bad" } else { "good" }
+                    );
+                }
+
+                let loss = if self.bad { ge.loss_bad } else { ge.loss_good };
+
+                loss != 0.0 && rng.random::<f64>() < loss
+            }
+        }
+    }
+}
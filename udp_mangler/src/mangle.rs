@@ -1,45 +1,64 @@
 //! Packet mangling and UDP stream distortion
 
 use core::error::Error;
+use core::net::SocketAddr;
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use std::sync::mpsc::{Receiver, RecvTimeoutError, SendError, Sender};
 use std::time::Instant;
 
 use arc_swap::ArcSwap;
-use rand::RngExt;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
 
-use crate::{ByTimestamp, ManglerConfig, Packet};
+use crate::clock::Clock;
+use crate::corrupt::corrupt;
+use crate::distribution::JitterDistribution;
+use crate::fragment::{OversizeMode, fragment_sizes};
+use crate::latency_trace::TraceSample;
+use crate::loss::LossState;
+use crate::observe::Observers;
+use crate::reorder::{HeldBack, ReorderMode};
+use crate::replay::RecordedDecision;
+use crate::shaper::Shaper;
+use crate::stage::{PipelineStage, StageContext, StagePacket};
+use crate::stats::DropReason;
+use crate::{ByTimestamp, Direction, ImpairmentConfig, ManglerConfig, Packet, RuleAction};
+
+/// The longest delay a packet can get, so that extreme pings, jitter samples or trace delays can not
+/// overflow the moment it is sent
+const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Main function for the mangler thread.
 /// The mangler thread takes the stream of input packets from the [listener thread](crate::listen::listen_main),
 /// and distorts the stream in arbitrary ways. For example, it adds additional latency and jitter, and can randomly
-/// drop packets
+/// drop packets. There is one mangler thread for each [Direction], which each use the impairments configured
+/// for their own direction. The mangling itself is done by a [MangleCore], this thread only moves the packets
+/// in and out of it at the right time
+#[allow(clippy::too_many_arguments, reason = "Thread entry point")]
 pub(crate) fn mangle_main(
     config: Arc<ArcSwap<ManglerConfig>>,
+    direction: Direction,
+    seed: u64,
+    clock: Arc<dyn Clock>,
     _errs: Sender<Box<dyn Error + Send>>,
     from_listener: Receiver<Packet>,
     to_forward: Sender<Packet>,
+    observers: Arc<Observers>,
     quit: Arc<AtomicBool>,
 ) {
     const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    let mut rng = rand::rng();
-    let mut queue: BTreeSet<ByTimestamp> = BTreeSet::new();
-    let mut next_queued: Instant = Instant::now() + DEFAULT_POLL_INTERVAL;
+    let mut core = MangleCore::with_seed(direction, config.load_full(), seed, clock.now());
 
     while !quit.load(Ordering::Acquire) {
-        let now = Instant::now();
+        let now = clock.now();
 
-        while let Some(next_packet) = queue.last()
-            && next_packet.send_timestamp <= now
-        {
-            let to_send = queue.pop_last().unwrap();
-
-            log::trace!("Forwarding packet: {:#?}", to_send.0);
-            match to_forward.send(to_send.0) {
+        for to_send in core.poll_ready_packets(now) {
+            log::trace!("Forwarding packet: {:#?}", to_send);
+            match to_forward.send(to_send) {
                 Ok(val) => val,
                 Err(SendError(_)) => {
                     log::debug!("Mangle thread returning because the forwarder channel was closed");
@@ -48,12 +67,23 @@ pub(crate) fn mangle_main(
             };
         }
 
-        let timeout = next_queued.duration_since(now);
+        // Wake up when the next packet is scheduled.
+        // Never wait longer than a default interval, to make sure we check the `quit` bool once in a while,
+        // and notice when a clock that is not the system clock was moved forward
+        observers
+            .stats
+            .set_queue_depth(direction, core.queue_depth());
+
+        let timeout = core
+            .next_deadline()
+            .map_or(DEFAULT_POLL_INTERVAL, |deadline| {
+                deadline.saturating_duration_since(now)
+            })
+            .min(DEFAULT_POLL_INTERVAL);
 
         let mut packet = match from_listener.recv_timeout(timeout) {
             Ok(p) => p,
             Err(RecvTimeoutError::Timeout) => {
-                next_queued = now + DEFAULT_POLL_INTERVAL;
                 continue;
             }
             Err(RecvTimeoutError::Disconnected) => {
@@ -62,43 +92,1032 @@ pub(crate) fn mangle_main(
             }
         };
 
-        log::trace!("Mangling content: {:?}", packet);
+        log::trace!("Mangling {direction} content: {:?}", packet);
+
+        core.set_config(config.load_full());
+        let fate = core.push_packet(&mut packet, clock.now());
+
+        observers.decided(&packet, direction, &fate);
+
+        observers.received(
+            Some(packet.id),
+            &packet.content,
+            packet.client,
+            direction,
+            packet.send_timestamp,
+            &fate,
+        );
+    }
+}
+
+/// The state of a virtual link, which is either the default link of a direction or the link of a [Rule](crate::Rule)
+#[derive(Debug, Default)]
+struct Link {
+    /// The state of the loss model
+    loss: LossState,
+
+    /// The rate limiter
+    shaper: Shaper,
+}
+
+/// The mangling of the packets travelling in a single [Direction], without any sockets, threads or clocks.
+/// This is what the [Mangler](crate::Mangler) runs for each direction, and it can be driven directly to mangle
+/// packets in tests or simulations, where time is fully under control.
+///
+/// Packets are [pushed](Self::push) in when they arrive, and [polled](Self::poll_ready) out once they are due.
+/// All methods take the current time as `now`, which must never go backwards. The core never looks at the clock
+/// itself, so that with the same seed and the same packets at the same times, it makes the same decisions
+#[derive(Debug)]
+pub struct MangleCore {
+    /// The direction of the packets
+    direction: Direction,
+
+    /// The config the packets are mangled with
+    config: Arc<ManglerConfig>,
+
+    /// The seed of the random number generator
+    seed: u64,
+
+    /// The number of the flow of every client pushed in, in the order in which they were first seen
+    flows: HashMap<SocketAddr, u64>,
+
+    /// Source of randomness for all impairments
+    rng: StdRng,
+
+    /// The state of the default link of the direction, and of the link of each rule with its own impairments
+    links: HashMap<Option<usize>, Link>,
+
+    /// The packets waiting to be sent, sorted by their send timestamp
+    queue: BTreeSet<ByTimestamp>,
+
+    /// Packets that are held back until later packets have overtaken them
+    held: HeldBack,
+
+    /// The latest delivery time of each flow, used to preserve the order of packets within a flow
+    last_delivery: HashMap<SocketAddr, Instant>,
+
+    /// The ID given to the next incoming packet
+    next_id: u64,
+
+    /// The number of packets that arrived so far for every flow
+    flow_packets: HashMap<u64, u64>,
+
+    /// The sequence number given to the next packet inserted into the queue
+    next_seq: u64,
+
+    /// The moment the core was created, from which the offset into a latency trace is counted
+    started: Instant,
+}
+
+/// A packet pushed into a [MangleCore]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    /// The client that sent the packet when travelling upstream,
+    /// or the client it is destined for when travelling downstream
+    pub client: SocketAddr,
+
+    /// The UDP payload
+    pub payload: Vec<u8>,
+}
+
+/// A packet that is due to be sent, polled from a [MangleCore]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadyDatagram {
+    /// The ID that [MangleCore::push] returned for the packet. All copies of a duplicated packet share it
+    pub id: u64,
+
+    /// The client of the packet
+    pub client: SocketAddr,
+
+    /// The UDP payload, which may be corrupted
+    pub payload: Vec<u8>,
+
+    /// The moment the packet was scheduled to be sent. A packet held back for reordering is sent later than that
+    pub scheduled: Instant,
+}
+
+impl MangleCore {
+    /// Creates the core for the packets travelling in `direction`, at the moment `now`. The random decisions
+    /// are derived from the [seed](ManglerConfig::seed) of the config, or from a random seed if it has none
+    pub fn new(direction: Direction, config: impl Into<Arc<ManglerConfig>>, now: Instant) -> Self {
+        let config = config.into();
+        let seed = config.seed.unwrap_or_else(rand::random);
+
+        Self::with_seed(direction, config, seed, now)
+    }
+
+    /// Creates the core for the packets travelling in `direction` at the moment `started`, with its random number
+    /// generator seeded from the `seed` of the mangler
+    pub(crate) fn with_seed(
+        direction: Direction,
+        config: Arc<ManglerConfig>,
+        seed: u64,
+        started: Instant,
+    ) -> Self {
+        // Both directions get their own sequence of random numbers, so that the decisions
+        // in one direction don't depend on the traffic in the other
+        let direction_seed = match direction {
+            Direction::Upstream => seed,
+            Direction::Downstream => seed ^ u64::MAX,
+        };
+
+        Self {
+            direction,
+            config,
+            seed,
+            flows: HashMap::new(),
+            rng: StdRng::seed_from_u64(direction_seed),
+            links: HashMap::new(),
+            queue: BTreeSet::new(),
+            held: HeldBack::default(),
+            last_delivery: HashMap::new(),
+            next_id: 0,
+            flow_packets: HashMap::new(),
+            next_seq: 0,
+            started,
+        }
+    }
+
+    /// Returns the direction of the packets
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Returns the seed the random decisions are derived from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the config the packets are mangled with
+    pub fn config(&self) -> &ManglerConfig {
+        &self.config
+    }
+
+    /// Replaces the config the packets are mangled with. Packets that were already pushed keep their fate
+    pub fn set_config(&mut self, config: impl Into<Arc<ManglerConfig>>) {
+        self.config = config.into();
+    }
+
+    /// Mangles a packet that arrived at `now`, and schedules it to be sent unless it is dropped.
+    /// Returns the ID of the packet if it was scheduled, or why it was dropped
+    pub fn push(&mut self, datagram: Datagram, now: Instant) -> Result<u64, DropReason> {
+        let next_flow = self.flows.len() as u64;
+        let flow = *self.flows.entry(datagram.client).or_insert(next_flow);
+
+        let mut packet = Packet {
+            send_timestamp: now,
+            client: datagram.client,
+            id: 0,
+            flow,
+            replayed: false,
+            flow_index: 0,
+            seq: 0,
+            hold_positions: 0,
+            content: datagram.payload,
+        };
+
+        let fate = self.push_packet(&mut packet, now);
+
+        if fate.delays.is_empty() {
+            // A packet without any copies always has a drop reason, this is just a fallback
+            Err(fate.dropped.unwrap_or(DropReason::Stage))
+        } else {
+            Ok(packet.id)
+        }
+    }
+
+    /// Removes and returns all packets that are due at `now`, in the order they should be sent in
+    pub fn poll_ready(&mut self, now: Instant) -> Vec<ReadyDatagram> {
+        self.poll_ready_packets(now)
+            .into_iter()
+            .map(|packet| ReadyDatagram {
+                id: packet.id,
+                client: packet.client,
+                payload: packet.content,
+                scheduled: packet.send_timestamp,
+            })
+            .collect()
+    }
+
+    /// Returns the next moment at which a packet might be due, if any. Polling before then returns nothing
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let next_queued = self.queue.first().map(|next| next.send_timestamp);
+
+        match (next_queued, self.held.next_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the number of packets waiting to be sent, including those held back
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len() + self.held.len()
+    }
+
+    /// Mangles a single incoming packet, and schedules it for sending if it is not dropped.
+    /// Returns what happened to the packet
+    pub(crate) fn push_packet(&mut self, packet: &mut Packet, now: Instant) -> Fate {
+        let config = self.config.clone();
+        let direction = self.direction;
+
+        packet.id = self.next_id;
+        self.next_id += 1;
+
+        let flow_packets = self.flow_packets.entry(packet.flow).or_insert(0);
+        packet.flow_index = *flow_packets;
+        *flow_packets += 1;
+
+        if let Some(decision) = config
+            .replay_decisions
+            .as_ref()
+            .and_then(|recording| recording.get(packet.flow, direction, packet.flow_index))
+        {
+            return self.replay(packet, decision, now);
+        }
+
+        let rule = config
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(packet, direction));
+
+        match rule {
+            None => self.impair(
+                packet,
+                config.impairments(direction),
+                &config.pipeline,
+                None,
+                now,
+            ),
+            Some((rule_index, rule)) => match &rule.action {
+                RuleAction::Pass => {
+                    log::trace!("Passing packet without impairments due to rule");
+                    self.schedule(packet.clone(), now);
+                    Fate::scheduled(vec![Duration::ZERO], vec![0])
+                }
+                RuleAction::Drop => {
+                    log::trace!("Dropping packet due to rule");
+                    Fate::dropped(DropReason::Rule)
+                }
+                RuleAction::Impair(impairments) => {
+                    self.impair(packet, impairments, &config.pipeline, Some(rule_index), now)
+                }
+            },
+        }
+    }
+
+    /// Schedules a packet exactly as recorded in an earlier run
+    fn replay(&mut self, packet: &Packet, decision: &RecordedDecision, now: Instant) -> Fate {
+        let copies = match decision {
+            RecordedDecision::Drop(reason) => {
+                log::trace!("Dropping packet as recorded");
+                return Fate::dropped(*reason);
+            }
+            RecordedDecision::Send(copies) => copies,
+        };
+
+        for copy in copies {
+            self.schedule(
+                Packet {
+                    send_timestamp: packet.send_timestamp + copy.delay,
+                    hold_positions: copy.hold_positions,
+                    ..packet.clone()
+                },
+                now,
+            );
+        }
+
+        let mut fate = Fate::scheduled(
+            copies.iter().map(|copy| copy.delay).collect(),
+            copies.iter().map(|copy| copy.hold_positions).collect(),
+        );
+        fate.reordered = fate.hold_positions.iter().any(|positions| *positions != 0);
+        fate
+    }
+
+    /// Passes a packet through the stages of the pipeline with the impairments of a link, and schedules
+    /// the packets that come out of it. The link is either the default link of the direction, or the link of a rule
+    fn impair(
+        &mut self,
+        packet: &Packet,
+        config: &ImpairmentConfig,
+        pipeline: &[PipelineStage],
+        link: Option<usize>,
+        now: Instant,
+    ) -> Fate {
+        let mut fate = Fate::default();
+        let mut packets = vec![StagePacket {
+            content: packet.content.clone(),
+            client: packet.client,
+            arrival: packet.send_timestamp,
+            departure: packet.send_timestamp,
+            send_at: packet.send_timestamp,
+            hold_positions: 0,
+        }];
+
+        for stage in pipeline {
+            packets = packets
+                .into_iter()
+                .flat_map(|stage_packet| {
+                    self.run_stage(stage, stage_packet, config, link, now, &mut fate)
+                })
+                .collect();
+
+            if packets.is_empty() {
+                return fate;
+            }
+        }
+
+        // Some copies may have been dropped, but not the packet as a whole
+        fate.dropped = None;
 
-        let config = config.load();
+        for stage_packet in packets {
+            fate.delays.push(
+                stage_packet
+                    .send_at
+                    .saturating_duration_since(packet.send_timestamp),
+            );
+            fate.hold_positions.push(stage_packet.hold_positions);
 
-        if packet.content.len() > config.max_payload_size {
-            log::trace!(
-                "Dropping packet with size above maximum: {}, max {}",
-                packet.content.len(),
-                config.max_payload_size
+            self.schedule(
+                Packet {
+                    send_timestamp: stage_packet.send_at,
+                    hold_positions: stage_packet.hold_positions,
+                    content: stage_packet.content,
+                    ..*packet
+                },
+                now,
             );
-            continue;
         }
 
-        if config.loss_factor != 0.0 && rng.random::<f64>() < config.loss_factor {
-            log::trace!("Dropping packet randomly due to loss factor");
-            continue;
+        fate
+    }
+
+    /// Runs a single stage of the pipeline, and returns the packets that continue to the next stage.
+    /// Whatever the stage did is noted in `fate`
+    fn run_stage(
+        &mut self,
+        stage: &PipelineStage,
+        mut packet: StagePacket,
+        config: &ImpairmentConfig,
+        link: Option<usize>,
+        now: Instant,
+        fate: &mut Fate,
+    ) -> Vec<StagePacket> {
+        match stage {
+            PipelineStage::MaxSize => {
+                if packet.content.len() > config.max_payload_size
+                    && config.oversize_mode == OversizeMode::Drop
+                {
+                    log::trace!(
+                        "Dropping packet with size above maximum: {}, max {}",
+                        packet.content.len(),
+                        config.max_payload_size
+                    );
+                    fate.dropped = Some(DropReason::Oversize);
+                    return Vec::new();
+                }
+            }
+            PipelineStage::Link => {
+                let (departure, delivery) = match self.transmit(&packet, config, link, now) {
+                    Ok(times) => times,
+                    Err(reason) => {
+                        fate.dropped = Some(reason);
+                        return Vec::new();
+                    }
+                };
+
+                packet.departure = departure;
+                packet.send_at = delivery;
+            }
+            PipelineStage::Duplicate => return self.duplicate(packet, config, now),
+            PipelineStage::Corrupt => {
+                let flipped = corrupt(&mut packet.content, config, &mut self.rng);
+                if flipped != 0 {
+                    log::trace!("Corrupted packet by flipping {flipped} bits");
+                    fate.flipped_bits += flipped;
+                }
+            }
+            PipelineStage::Reorder => {
+                if self.should_reorder(config) {
+                    fate.reordered = true;
+
+                    match config.reorder_mode {
+                        ReorderMode::Positions(positions) => packet.hold_positions = positions,
+                        ReorderMode::DelaySecs(delay) => {
+                            log::trace!("Reordering packet by delaying it {delay} seconds");
+                            packet.send_at += Duration::from_secs_f64(delay);
+                        }
+                    }
+                }
+            }
+            PipelineStage::Custom(stage) => {
+                let mut context = StageContext {
+                    direction: self.direction,
+                    now,
+                    started: self.started,
+                    impairments: config,
+                    rng: &mut self.rng,
+                };
+
+                let packets = stage.process(packet, &mut context).into_packets();
+                if packets.is_empty() {
+                    log::trace!("Dropping packet in custom stage {stage:?}");
+                    fate.dropped = Some(DropReason::Stage);
+                }
+
+                return packets;
+            }
+        }
+
+        vec![packet]
+    }
+
+    /// Randomly duplicates a packet. Every extra copy gets its own delay, as if it were a separate packet,
+    /// and the n-th extra copy is sent n times the [duplicate delay](ImpairmentConfig::duplicate_delay_secs) later.
+    /// When [preserving the order](ImpairmentConfig::preserve_order), copies are not delivered before earlier packets
+    fn duplicate(
+        &mut self,
+        packet: StagePacket,
+        config: &ImpairmentConfig,
+        now: Instant,
+    ) -> Vec<StagePacket> {
+        if config.duplicate_factor == 0.0 || self.rng.random::<f64>() >= config.duplicate_factor {
+            return vec![packet];
+        }
+
+        log::trace!("Duplicating packet {} times", config.duplicate_copies);
+
+        let mut copies = Vec::with_capacity(1 + config.duplicate_copies);
+
+        for copy in 1..=config.duplicate_copies {
+            let mut duplicate = packet.clone();
+            let delivery = packet.departure
+                + self.delay(config, packet.arrival)
+                + Duration::from_secs_f64(config.duplicate_delay_secs * copy as f64);
+            duplicate.send_at = self.keep_order(config, packet.client, delivery, now);
+            copies.push(duplicate);
+        }
+
+        copies.insert(0, packet);
+        copies
+    }
+
+    /// Sends a packet over the given virtual link, fragmenting it first if it is oversized and fragmentation is on.
+    /// Every fragment is subject to the loss model, the rate limiter and the delay.
+    /// Packets arriving while the latency trace records a loss are dropped as a whole.
+    ///
+    /// If all fragments survive, returns the moment the last fragment left the rate limiter and the
+    /// moment the last fragment is delivered. Otherwise returns why the packet was dropped
+    fn transmit(
+        &mut self,
+        packet: &StagePacket,
+        config: &ImpairmentConfig,
+        link: Option<usize>,
+        now: Instant,
+    ) -> Result<(Instant, Instant), DropReason> {
+        // Earlier stages may have delayed the packet before it enters the link
+        let arrival = packet.send_at;
+
+        if self
+            .trace_sample(config, arrival)
+            .is_some_and(|sample| sample.lost)
+        {
+            log::trace!("Dropping packet due to latency trace");
+            return Err(DropReason::LatencyTrace);
+        }
+
+        // Without fragmentation an oversized packet only gets here if the max size stage was left out of
+        // the pipeline, in which case it is sent whole
+        let fragments = match config.oversize_mode {
+            OversizeMode::Fragment => fragment_sizes(packet.content.len(), config.max_payload_size),
+            OversizeMode::Drop => vec![packet.content.len()],
+        };
+
+        if fragments.len() > 1 {
+            log::trace!("Fragmenting packet into {} fragments", fragments.len());
+        }
+
+        let link = self.links.entry(link).or_default();
+        let mut departures = Vec::with_capacity(fragments.len());
+        let mut lost = None;
+
+        // Fragments after a lost one are still sent, like a real link would
+        for size in fragments {
+            if link.loss.should_drop(&config.loss, &mut self.rng) {
+                log::trace!("Dropping packet randomly due to loss model");
+                lost = Some(DropReason::RandomLoss);
+                continue;
+            }
+
+            match link.shaper.admit(size, config, arrival) {
+                Ok(departure) => departures.push(departure),
+                Err(reason) => {
+                    log::trace!("Dropping packet in rate limiter: {reason}");
+                    lost = Some(reason.into());
+                }
+            }
+        }
+
+        if let Some(reason) = lost {
+            return Err(reason);
         }
 
-        if config.ping_secs != 0.0 {
-            packet.send_timestamp += Duration::from_secs_f64(config.ping_secs)
+        let departure = departures.last().copied().unwrap_or(arrival);
+        let delivery = departures
+            .into_iter()
+            .map(|departure| departure + self.delay(config, arrival))
+            .fold(arrival, Instant::max);
+
+        let delivery = self.keep_order(config, packet.client, delivery, now);
+
+        Ok((departure, delivery))
+    }
+
+    /// Returns the moment a packet of `client` that would be delivered at `delivery` is delivered, which is
+    /// no earlier than the packets of the client before it when [preserving the order](ImpairmentConfig::preserve_order)
+    fn keep_order(
+        &mut self,
+        config: &ImpairmentConfig,
+        client: SocketAddr,
+        delivery: Instant,
+        now: Instant,
+    ) -> Instant {
+        if !config.preserve_order {
+            return delivery;
         }
 
-        if config.jitter_secs != 0.0 {
-            let offset = rng.random_range::<f64, _>(0.0..=(config.jitter_secs));
+        // Forget about flows that have no packets in flight anymore
+        self.last_delivery.retain(|_, last| *last > now);
 
-            packet.send_timestamp += Duration::from_secs_f64(offset);
+        let last = self.last_delivery.entry(client).or_insert(delivery);
+        *last = delivery.max(*last);
+        *last
+    }
+
+    /// Returns the delay of a packet that arrived at `arrival`. This is the delay from the latency trace
+    /// if there is one, or otherwise the ping plus a random jitter sample
+    fn delay(&mut self, config: &ImpairmentConfig, arrival: Instant) -> Duration {
+        if let Some(sample) = self.trace_sample(config, arrival) {
+            return clamp_delay(sample.delay_secs);
+        }
+
+        let mut delay = config.ping_secs;
+
+        if config.jitter_secs != 0.0
+            || matches!(config.jitter_distribution, JitterDistribution::Empirical(_))
+        {
+            delay += config
+                .jitter_distribution
+                .sample(config.jitter_secs, &mut self.rng);
         }
 
+        clamp_delay(delay)
+    }
+
+    /// Returns the sample of the configured latency trace at `now`, if there is one and it has not ended
+    fn trace_sample<'a>(
+        &self,
+        config: &'a ImpairmentConfig,
+        now: Instant,
+    ) -> Option<&'a TraceSample> {
+        config
+            .latency_trace
+            .as_ref()?
+            .sample_at(now.duration_since(self.started).as_secs_f64())
+    }
+
+    /// Removes and returns all packets that should be sent at `now`, in the order they should be sent in
+    pub(crate) fn poll_ready_packets(&mut self, now: Instant) -> Vec<Packet> {
+        let mut due = Vec::new();
+
+        while let Some(next_packet) = self.queue.first()
+            && next_packet.send_timestamp <= now
+        {
+            let to_send = self.queue.pop_first().unwrap().0;
+
+            if to_send.hold_positions != 0 {
+                let positions = to_send.hold_positions;
+
+                log::trace!("Reordering packet by holding it back {positions} positions");
+                self.held.hold(to_send, positions);
+                continue;
+            }
+
+            self.held.overtaken_by(to_send.id);
+            due.push(to_send);
+
+            // Any packets released by this one are sent right after it
+            due.extend(self.held.release(now));
+        }
+
+        due.extend(self.held.release(now));
+
+        due
+    }
+
+    /// Randomly decides whether a packet should be explicitly reordered
+    fn should_reorder(&mut self, config: &ImpairmentConfig) -> bool {
+        config.reorder_factor != 0.0 && self.rng.random::<f64>() < config.reorder_factor
+    }
+
+    /// Inserts a packet into the queue
+    fn schedule(&mut self, mut packet: Packet, now: Instant) {
+        packet.seq = self.next_seq;
+        self.next_seq += 1;
+
         log::trace!("Inserting into queue: {:#?} (now: {now:?})", packet);
-        queue.insert(packet.into());
+        self.queue.insert(packet.into());
+    }
+}
+
+/// What the mangler did to a single packet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Fate {
+    /// Why the packet was dropped, if it was
+    pub(crate) dropped: Option<DropReason>,
+
+    /// The delay of every copy of the packet that was scheduled, counted from the moment it arrived.
+    /// There is more than one copy if the packet was duplicated
+    pub(crate) delays: Vec<Duration>,
+
+    /// The number of positions every copy is held back for explicit reordering, in the same order as the delays
+    pub(crate) hold_positions: Vec<usize>,
+
+    /// The number of bits flipped in all copies together
+    pub(crate) flipped_bits: usize,
+
+    /// Whether any copy was explicitly reordered
+    pub(crate) reordered: bool,
+}
+
+impl Fate {
+    /// Returns the fate of a packet that was dropped
+    pub(crate) fn dropped(reason: DropReason) -> Self {
+        Self {
+            dropped: Some(reason),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the fate of a packet that was scheduled with the given delays and hold positions, without other impairments
+    fn scheduled(delays: Vec<Duration>, hold_positions: Vec<usize>) -> Self {
+        Self {
+            delays,
+            hold_positions,
+            ..Self::default()
+        }
+    }
+}
+
+impl core::fmt::Display for Fate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.delays.is_empty() {
+            return match self.dropped {
+                Some(reason) => write!(f, "Dropped: {reason}"),
+                None => write!(f, "Dropped"),
+            };
+        }
+
+        if self.delays.len() > 1 {
+            write!(f, "Duplicated into {} copies, ", self.delays.len())?;
+        }
+
+        write!(f, "Delayed")?;
+
+        for (i, delay) in self.delays.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{separator}{:.3} ms", delay.as_secs_f64() * 1000.0)?;
+        }
+
+        if self.flipped_bits != 0 {
+            let unit = if self.flipped_bits == 1 {
+                "bit"
+            } else {
+                "bits"
+            };
+            write!(f, ", corrupted {} {unit}", self.flipped_bits)?;
+        }
+
+        if self.reordered {
+            write!(f, ", reordered")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns a delay of `secs` seconds. Jitter centered around the ping can not make packets arrive before
+/// they were sent, so negative delays become zero, and delays longer than [MAX_DELAY] are clamped to it
+fn clamp_delay(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs.max(0.0)).map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use core::time::Duration;
+    use std::time::Instant;
+
+    use super::{Datagram, MAX_DELAY, MangleCore};
+    use crate::clock::{Clock, ManualClock};
+    use crate::reorder::ReorderMode;
+    use crate::stage::PipelineStage;
+    use crate::stats::DropReason;
+    use crate::{
+        Direction, ImpairmentConfig, LatencyTrace, LossModel, ManglerConfig, OversizeMode,
+        TraceSample,
+    };
+
+    /// The client all test packets come from
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+
+    /// Returns a config with a fixed seed and no impairments in either direction, other than the ones set by `impair`
+    fn config(impair: impl Fn(&mut ImpairmentConfig)) -> ManglerConfig {
+        let mut impairments = ImpairmentConfig {
+            ping_secs: 0.0,
+            jitter_secs: 0.0,
+            loss: LossModel::Uniform(0.0),
+            ..ImpairmentConfig::default()
+        };
+        impair(&mut impairments);
+
+        ManglerConfig {
+            upstream: impairments.clone(),
+            downstream: impairments,
+            seed: Some(42),
+            ..ManglerConfig::default()
+        }
+    }
+
+    /// Pushes `count` packets one millisecond apart, with their number as payload.
+    /// Returns what the core returned for each of them
+    fn push_packets(
+        core: &mut MangleCore,
+        clock: &ManualClock,
+        count: u8,
+    ) -> Vec<Result<u64, DropReason>> {
+        (0..count)
+            .map(|i| {
+                clock.advance(Duration::from_millis(1));
+                core.push(
+                    Datagram {
+                        client: CLIENT,
+                        payload: vec![i],
+                    },
+                    clock.now(),
+                )
+            })
+            .collect()
+    }
+
+    /// Moves the clock through all deadlines of the core, and returns the payload of every packet sent
+    /// with the moment it was sent, in the order they were sent in
+    fn drain(core: &mut MangleCore, clock: &ManualClock) -> Vec<(u8, Instant)> {
+        let mut sent = Vec::new();
+
+        while let Some(deadline) = core.next_deadline() {
+            clock.advance_to(deadline);
+
+            for ready in core.poll_ready(clock.now()) {
+                sent.push((ready.payload[0], clock.now()));
+            }
+        }
+
+        sent
+    }
+
+    /// Packets are held for exactly the ping, and not sent before
+    #[test]
+    fn delay() {
+        let clock = ManualClock::new();
+        let config = config(|impairments| impairments.ping_secs = 0.030);
+        let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+        let start = clock.now();
+        let pushed = push_packets(&mut core, &clock, 5);
+        assert!(pushed.iter().all(Result::is_ok), "No packet is dropped");
+
+        assert_eq!(
+            core.next_deadline(),
+            Some(start + Duration::from_millis(31)),
+            "The first packet is due a ping after it arrived"
+        );
+        assert!(
+            core.poll_ready(clock.now()).is_empty(),
+            "No packet is due before its delay has passed"
+        );
+
+        let sent = drain(&mut core, &clock);
+        let expected: Vec<_> = (0..5)
+            .map(|i| (i, start + Duration::from_millis(u64::from(i) + 31)))
+            .collect();
+
+        assert_eq!(
+            sent, expected,
+            "Every packet is sent a ping after it arrived"
+        );
+        assert_eq!(core.queue_depth(), 0, "The queue is empty after draining");
+    }
+
+    /// The same seed drops the same packets, and another seed drops other packets
+    #[test]
+    fn loss_with_fixed_seed() {
+        let outcomes = |seed| {
+            let clock = ManualClock::new();
+            let config = ManglerConfig {
+                seed: Some(seed),
+                ..config(|impairments| impairments.loss = LossModel::Uniform(0.5))
+            };
+            let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+            push_packets(&mut core, &clock, 200)
+        };
+
+        let first = outcomes(42);
+        let dropped = first.iter().filter(|outcome| outcome.is_err()).count();
+
+        assert!(
+            (50..150).contains(&dropped),
+            "About half of the packets are dropped, but {dropped} were"
+        );
+        assert!(
+            first
+                .iter()
+                .all(|outcome| outcome.is_ok() || *outcome == Err(DropReason::RandomLoss)),
+            "All packets are dropped by the loss model"
+        );
+        assert_eq!(first, outcomes(42), "The same seed drops the same packets");
+        assert_ne!(first, outcomes(43), "Another seed drops other packets");
+    }
 
-        // Set the next "wake up" time to when the next packet is scheduled.
-        // If no packet is scheduled, set a default interval to make sure we check the `quit` bool once
-        // in a while
-        next_queued = match queue.last() {
-            Some(next) => next.send_timestamp,
-            None => now + DEFAULT_POLL_INTERVAL,
+    /// Reordered packets are overtaken by later packets, and no packet is lost
+    #[test]
+    fn reorder() {
+        let clock = ManualClock::new();
+        let config = config(|impairments| {
+            impairments.ping_secs = 0.010;
+            impairments.reorder_factor = 0.2;
+            impairments.reorder_mode = ReorderMode::Positions(2);
+        });
+        let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+        push_packets(&mut core, &clock, 50);
+        let mut sent: Vec<u8> = drain(&mut core, &clock)
+            .into_iter()
+            .map(|(payload, _)| payload)
+            .collect();
+
+        assert!(
+            !sent.is_sorted(),
+            "Some packets are sent out of order: {sent:?}"
+        );
+
+        sent.sort_unstable();
+        assert_eq!(
+            sent,
+            (0..50).collect::<Vec<_>>(),
+            "Every packet is sent exactly once"
+        );
+    }
+
+    /// Every duplicated packet is sent as all its copies, which share its ID
+    #[test]
+    fn duplicate() {
+        let clock = ManualClock::new();
+        let config = config(|impairments| {
+            impairments.ping_secs = 0.010;
+            impairments.duplicate_factor = 1.0;
+            impairments.duplicate_copies = 2;
+            impairments.duplicate_delay_secs = 0.005;
+        });
+        let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+        let start = clock.now();
+        let ids: Vec<u64> = push_packets(&mut core, &clock, 3)
+            .into_iter()
+            .map(|id| id.expect("No packet is dropped"))
+            .collect();
+        assert_eq!(ids, [0, 1, 2], "The IDs count the pushed packets");
+
+        let mut sent = Vec::new();
+        while let Some(deadline) = core.next_deadline() {
+            clock.advance_to(deadline);
+            sent.extend(core.poll_ready(clock.now()));
         }
+
+        for id in ids {
+            let copies: Vec<_> = sent.iter().filter(|ready| ready.id == id).collect();
+
+            assert_eq!(copies.len(), 3, "Packet {id} is sent as three copies");
+            assert!(
+                copies.iter().all(|copy| copy.payload == [id as u8]),
+                "All copies of packet {id} have its payload"
+            );
+            assert_eq!(
+                copies[0].scheduled,
+                start + Duration::from_millis(id + 11),
+                "The original of packet {id} is sent a ping after it arrived"
+            );
+            for (n, copy) in copies.iter().enumerate().skip(1) {
+                assert_eq!(
+                    copy.scheduled,
+                    copies[0].scheduled + Duration::from_millis(5 * n as u64),
+                    "Extra copy {n} of packet {id} is sent {n} times the duplicate delay after the original"
+                );
+            }
+        }
+    }
+
+    /// Delays too long for the clock are clamped instead of overflowing it
+    #[test]
+    fn extreme_delays() {
+        let trace = LatencyTrace::new(
+            vec![TraceSample {
+                offset_secs: 0.0,
+                delay_secs: 1e300,
+                lost: false,
+            }],
+            false,
+        )
+        .expect("The trace is valid");
+
+        for config in [
+            config(|impairments| impairments.ping_secs = 1e300),
+            config(|impairments| impairments.latency_trace = Some(trace.clone())),
+        ] {
+            let clock = ManualClock::new();
+            let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+            let start = clock.now();
+            push_packets(&mut core, &clock, 1);
+
+            assert_eq!(
+                core.next_deadline(),
+                Some(start + Duration::from_millis(1) + MAX_DELAY),
+                "The packet is held for the longest possible delay"
+            );
+        }
+    }
+
+    /// Oversized packets are only split into fragments when fragmenting, otherwise they cross the link whole
+    #[test]
+    fn fragment_only_when_fragmenting() {
+        for (mode, serialization_ms) in [(OversizeMode::Drop, 192), (OversizeMode::Fragment, 200)] {
+            let clock = ManualClock::new();
+            let config = ManglerConfig {
+                pipeline: vec![PipelineStage::Link],
+                ..config(|impairments| {
+                    impairments.max_payload_size = 100;
+                    impairments.oversize_mode = mode;
+                    impairments.rate_bits_per_sec = Some(8000.0);
+                    impairments.rate_burst_bytes = 0;
+                })
+            };
+            let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+            let pushed = core.push(
+                Datagram {
+                    client: CLIENT,
+                    payload: vec![0; 192],
+                },
+                clock.now(),
+            );
+            assert!(pushed.is_ok(), "The packet is not dropped in {mode:?} mode");
+
+            assert_eq!(
+                core.next_deadline(),
+                Some(clock.now() + Duration::from_millis(serialization_ms)),
+                "In {mode:?} mode {serialization_ms} bytes are serialized at 1000 bytes per second"
+            );
+        }
+    }
+
+    /// Extra copies of a packet keep the order like any other packet when preserving the order
+    #[test]
+    fn duplicate_preserves_order() {
+        let clock = ManualClock::new();
+        let config = config(|impairments| {
+            impairments.ping_secs = 0.010;
+            impairments.jitter_secs = 0.050;
+            impairments.preserve_order = true;
+            impairments.duplicate_factor = 1.0;
+            impairments.duplicate_copies = 2;
+        });
+        let mut core = MangleCore::new(Direction::Upstream, config, clock.now());
+
+        let pushed = push_packets(&mut core, &clock, 50);
+        assert!(pushed.iter().all(Result::is_ok), "No packet is dropped");
+
+        let sent: Vec<u8> = drain(&mut core, &clock)
+            .into_iter()
+            .map(|(payload, _)| payload)
+            .collect();
+
+        assert_eq!(sent.len(), 150, "Every packet is sent as three copies");
+        assert!(
+            sent.is_sorted(),
+            "No copy is sent before a copy of an earlier packet: {sent:?}"
+        );
     }
 }
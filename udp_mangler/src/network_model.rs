@@ -0,0 +1,609 @@
+//! Markov chain model of a link that randomly moves between network states
+
+use core::time::Duration;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use rand::rngs::StdRng;
+use rand::{Rng, RngExt, SeedableRng};
+
+use crate::playback::PlaybackControl;
+use crate::scenario::{parse_duration, parse_setting, parse_value};
+use crate::{Direction, ImpairmentConfig, ManglerConfig, ScenarioErr};
+
+/// A Markov chain of network states, like "good", "degraded" and "outage". The link starts in the first state,
+/// stays there for a random dwell time, and then moves to another state. It can be played back against
+/// a running mangler with [Mangler::play_network_model](crate::Mangler::play_network_model)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkModel {
+    /// The states of the chain. The chain starts in the first one
+    pub states: Vec<NetworkState>,
+}
+
+/// A single state of a [NetworkModel]
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkState {
+    /// The name of the state, as reported by [Mangler::network_state](crate::Mangler::network_state)
+    pub name: String,
+
+    /// The impairments from the clients to the forward target while in this state
+    pub upstream: ImpairmentConfig,
+
+    /// The impairments from the forward target to the clients while in this state
+    pub downstream: ImpairmentConfig,
+
+    /// The distribution of the time spent in this state. If [None], the time is exponentially distributed
+    /// with the sum of the transition rates as rate, which makes the chain a continuous-time Markov chain
+    pub dwell: Option<DwellTime>,
+
+    /// The possible transitions to other states
+    pub transitions: Vec<Transition>,
+}
+
+/// A transition from one [NetworkState] to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    /// The index of the state to move to
+    pub to: usize,
+
+    /// The rate of the transition, per second. When leaving a state, every transition is picked
+    /// with a probability proportional to its rate
+    pub rate: f64,
+}
+
+/// The distribution of the time spent in a [NetworkState]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DwellTime {
+    /// Always the given number of seconds
+    Fixed(f64),
+
+    /// Uniformly distributed between the given numbers of seconds
+    Uniform {
+        /// The shortest dwell time
+        min_secs: f64,
+
+        /// The longest dwell time
+        max_secs: f64,
+    },
+
+    /// Exponentially distributed, with the given mean in seconds
+    Exponential {
+        /// The mean dwell time
+        mean_secs: f64,
+    },
+}
+
+impl DwellTime {
+    /// Returns whether all times are finite and not negative, and a uniform range is not reversed
+    fn is_valid(&self) -> bool {
+        let valid = |secs: f64| secs.is_finite() && secs >= 0.0;
+
+        match *self {
+            Self::Fixed(secs) => valid(secs),
+            Self::Uniform { min_secs, max_secs } => {
+                valid(min_secs) && valid(max_secs) && min_secs <= max_secs
+            }
+            Self::Exponential { mean_secs } => valid(mean_secs),
+        }
+    }
+
+    /// Samples a dwell time in seconds
+    fn sample(&self, rng: &mut impl Rng) -> f64 {
+        match *self {
+            Self::Fixed(secs) => secs,
+            Self::Uniform { min_secs, max_secs } => rng.random_range(min_secs..=max_secs),
+            Self::Exponential { mean_secs } => exponential(mean_secs, rng),
+        }
+    }
+}
+
+/// Samples an exponential distribution with the given mean
+fn exponential(mean: f64, rng: &mut impl Rng) -> f64 {
+    -(1.0 - rng.random::<f64>()).ln() * mean
+}
+
+/// Error while parsing a [NetworkModel]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum NetworkModelErr {
+    /// The model contains no states
+    #[display("The network model contains no states")]
+    Empty,
+
+    /// A line is neither a state nor a transition
+    #[display("Invalid line {}", _0)]
+    InvalidLine(#[error(not(source))] usize),
+
+    /// A line contains an unknown setting
+    #[display("Unknown setting on line {}", _0)]
+    UnknownSetting(#[error(not(source))] usize),
+
+    /// A dwell time, rate, or setting value is missing or invalid
+    #[display("Missing or invalid value on line {}", _0)]
+    InvalidValue(#[error(not(source))] usize),
+
+    /// A transition refers to a state that is not defined
+    #[display("Unknown state on line {}", _0)]
+    UnknownState(#[error(not(source))] usize),
+
+    /// The dwell time of a state is negative or not finite, or its uniform range is reversed
+    #[display("Invalid dwell time for state {}", _0)]
+    InvalidDwell(#[error(not(source))] usize),
+}
+
+impl From<ScenarioErr> for NetworkModelErr {
+    fn from(err: ScenarioErr) -> Self {
+        match err {
+            ScenarioErr::UnknownSetting(line) => Self::UnknownSetting(line),
+            ScenarioErr::InvalidValue(line) => Self::InvalidValue(line),
+            ScenarioErr::InvalidTime(line) | ScenarioErr::NoSettings(line) => {
+                Self::InvalidLine(line)
+            }
+        }
+    }
+}
+
+impl NetworkModel {
+    /// Parses a network model. States are defined with lines starting with `state` and the name of
+    /// the state, followed by an optional dwell time and the settings in that state. Transitions are
+    /// lines with the names of two states and a rate per second:
+    ///
+    /// ```text
+    /// state good      dwell exp 30s         ping 20ms loss 0.1%
+    /// state degraded  dwell uniform 5s 15s  ping 80ms jitter 30ms loss 5%
+    /// state outage    dwell 3s              outage
+    /// state degraded  downstream rate 500kbit
+    ///
+    /// good     -> degraded  0.05
+    /// degraded -> good      0.5
+    /// degraded -> outage    0.1
+    /// outage   -> good      1
+    /// ```
+    ///
+    /// The dwell time is `dwell <duration>` for a fixed time, `dwell exp <duration>` for an exponentially
+    /// distributed time with the given mean, or `dwell uniform <duration> <duration>`. Without a dwell time,
+    /// the transition rates determine how long the link stays in a state.
+    /// The settings are the same as in a [Scenario](crate::Scenario::parse), and are applied on top of `baseline`.
+    /// `upstream` or `downstream` limits the settings of a line to a single direction, and repeating a state
+    /// adds to its settings. The chain starts in the first state.
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn parse(text: &str, baseline: &ManglerConfig) -> Result<Self, NetworkModelErr> {
+        let mut model = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words: Vec<_> = line.split_whitespace().collect();
+
+            match words.as_slice() {
+                ["state", name, rest @ ..] => {
+                    model.parse_state(name, rest, baseline, line_number)?;
+                }
+                [from, "->", to, rate] => {
+                    let from = model.index_of(from, line_number)?;
+                    let to = model.index_of(to, line_number)?;
+                    let rate = rate
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|rate| rate.is_finite() && *rate >= 0.0)
+                        .ok_or(NetworkModelErr::InvalidValue(line_number))?;
+
+                    model.states[from].transitions.push(Transition { to, rate });
+                }
+                _ => return Err(NetworkModelErr::InvalidLine(line_number)),
+            }
+        }
+
+        if model.states.is_empty() {
+            return Err(NetworkModelErr::Empty);
+        }
+
+        Ok(model)
+    }
+
+    /// Checks that the dwell times of all states can be sampled
+    pub(crate) fn validate(&self) -> Result<(), NetworkModelErr> {
+        match self
+            .states
+            .iter()
+            .position(|state| state.dwell.is_some_and(|dwell| !dwell.is_valid()))
+        {
+            Some(i) => Err(NetworkModelErr::InvalidDwell(i + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Parses the dwell time and settings of a state line, defining the state if it is new
+    fn parse_state(
+        &mut self,
+        name: &str,
+        words: &[&str],
+        baseline: &ManglerConfig,
+        line_number: usize,
+    ) -> Result<(), NetworkModelErr> {
+        let index = match self.states.iter().position(|state| state.name == name) {
+            Some(index) => index,
+            None => {
+                self.states.push(NetworkState {
+                    name: name.to_owned(),
+                    upstream: baseline.upstream.clone(),
+                    downstream: baseline.downstream.clone(),
+                    dwell: None,
+                    transitions: Vec::new(),
+                });
+                self.states.len() - 1
+            }
+        };
+
+        let state = &mut self.states[index];
+        let mut directions = vec![Direction::Upstream, Direction::Downstream];
+        let mut settings = Vec::new();
+        let mut words = words.iter().copied();
+
+        while let Some(word) = words.next() {
+            match word {
+                "dwell" => state.dwell = Some(parse_dwell(&mut words, line_number)?),
+                "upstream" => directions = vec![Direction::Upstream],
+                "downstream" => directions = vec![Direction::Downstream],
+                _ => settings.push(parse_setting(word, &mut words, line_number)?),
+            }
+        }
+
+        for direction in directions {
+            let config = match direction {
+                Direction::Upstream => &mut state.upstream,
+                Direction::Downstream => &mut state.downstream,
+            };
+
+            for setting in &settings {
+                setting.apply(config, baseline.impairments(direction));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the index of the state with the given name
+    fn index_of(&self, name: &str, line_number: usize) -> Result<usize, NetworkModelErr> {
+        self.states
+            .iter()
+            .position(|state| state.name == name)
+            .ok_or(NetworkModelErr::UnknownState(line_number))
+    }
+}
+
+/// Parses the dwell time following the `dwell` keyword
+fn parse_dwell<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    line_number: usize,
+) -> Result<DwellTime, NetworkModelErr> {
+    let duration = |word: Option<&str>| parse_value(word, parse_duration, line_number);
+
+    let dwell = match words.next() {
+        Some("exp") => DwellTime::Exponential {
+            mean_secs: duration(words.next())?,
+        },
+        Some("uniform") => {
+            let min_secs = duration(words.next())?;
+            let max_secs = duration(words.next())?;
+            if min_secs > max_secs {
+                return Err(NetworkModelErr::InvalidValue(line_number));
+            }
+
+            DwellTime::Uniform { min_secs, max_secs }
+        }
+        word => DwellTime::Fixed(duration(word)?),
+    };
+
+    Ok(dwell)
+}
+
+impl NetworkState {
+    /// Samples the time to stay in this state, in seconds. Returns [None] if the state is never left
+    fn sample_dwell(&self, num_states: usize, rng: &mut impl Rng) -> Option<f64> {
+        let total_rate = self.total_rate(num_states);
+
+        if total_rate <= 0.0 {
+            return None;
+        }
+
+        Some(match self.dwell {
+            Some(dwell) => dwell.sample(rng),
+            None => exponential(1.0 / total_rate, rng),
+        })
+    }
+
+    /// Picks the state to move to, with a probability proportional to the transition rates
+    fn sample_next(&self, num_states: usize, rng: &mut impl Rng) -> Option<usize> {
+        let mut remaining = rng.random::<f64>() * self.total_rate(num_states);
+
+        self.valid_transitions(num_states).find_map(|transition| {
+            remaining -= transition.rate;
+            (remaining < 0.0).then_some(transition.to)
+        })
+    }
+
+    /// Returns the sum of the rates of all valid transitions
+    fn total_rate(&self, num_states: usize) -> f64 {
+        self.valid_transitions(num_states)
+            .map(|transition| transition.rate)
+            .sum()
+    }
+
+    /// Returns the transitions to existing states, with a positive rate
+    fn valid_transitions(&self, num_states: usize) -> impl Iterator<Item = &Transition> {
+        self.transitions
+            .iter()
+            .filter(move |transition| transition.to < num_states && transition.rate > 0.0)
+    }
+}
+
+/// Main function for the network model thread, which moves through the states of a [NetworkModel]
+/// and switches the impairments accordingly, until the playback is stopped
+pub(crate) fn network_model_main(
+    config: Arc<ArcSwap<ManglerConfig>>,
+    model: NetworkModel,
+    seed: u64,
+    control: PlaybackControl,
+) {
+    // Dwell times this long are as good as never leaving the state, and are clamped so that they fit the clock
+    const MAX_DWELL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+    // Use a different sequence of random numbers than the mangler threads
+    let mut rng = StdRng::seed_from_u64(seed.rotate_left(32));
+    let num_states = model.states.len();
+    let mut current = 0;
+
+    while let Some(state) = model.states.get(current) {
+        log::info!("Network state changed to {}", state.name);
+        control.set_state(&state.name);
+
+        config.rcu(|config| ManglerConfig {
+            upstream: state.upstream.clone(),
+            downstream: state.downstream.clone(),
+            ..ManglerConfig::clone(config)
+        });
+
+        let Some(dwell_secs) = state.sample_dwell(num_states, &mut rng) else {
+            log::info!("Network state {} has no transitions", state.name);
+            while !control.sleep(Duration::from_secs(1)) {}
+            return;
+        };
+
+        let dwell =
+            Duration::try_from_secs_f64(dwell_secs).map_or(MAX_DWELL, |dwell| dwell.min(MAX_DWELL));
+
+        if control.sleep(dwell) {
+            return;
+        }
+
+        if let Some(next) = state.sample_next(num_states, &mut rng) {
+            current = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+    use std::sync::{Arc, mpsc};
+
+    use arc_swap::ArcSwap;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::{
+        DwellTime, NetworkModel, NetworkModelErr, NetworkState, Transition, network_model_main,
+    };
+    use crate::clock::ManualClock;
+    use crate::playback::Playback;
+    use crate::{ImpairmentConfig, LossModel, ManglerConfig};
+
+    /// States, dwell times, settings per direction and transitions are parsed
+    #[test]
+    fn parse() {
+        let baseline = ManglerConfig::default();
+        let model = NetworkModel::parse(
+            "# The states
+            state good      dwell exp 30s         ping 20ms loss 0.1%
+            state degraded  dwell uniform 5s 15s  ping 80ms
+            state outage    dwell 3s              outage
+            state degraded  downstream loss 5%
+
+            good     -> degraded  0.05
+            degraded -> good      0.5
+            degraded -> outage    0.1
+            outage   -> good      1",
+            &baseline,
+        )
+        .expect("The model is valid");
+
+        let names: Vec<_> = model
+            .states
+            .iter()
+            .map(|state| state.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["good", "degraded", "outage"],
+            "A repeated state is not defined twice"
+        );
+
+        let good = &model.states[0];
+        assert_eq!(
+            good.dwell,
+            Some(DwellTime::Exponential { mean_secs: 30.0 }),
+            "The dwell time of good is exponential"
+        );
+        assert_eq!(
+            good.upstream,
+            ImpairmentConfig {
+                ping_secs: 0.02,
+                loss: LossModel::Uniform(0.001),
+                ..baseline.upstream.clone()
+            },
+            "The settings are applied on top of the baseline"
+        );
+        assert_eq!(
+            good.transitions,
+            [Transition { to: 1, rate: 0.05 }],
+            "Good only moves to degraded"
+        );
+
+        let degraded = &model.states[1];
+        assert_eq!(
+            degraded.dwell,
+            Some(DwellTime::Uniform {
+                min_secs: 5.0,
+                max_secs: 15.0
+            }),
+            "The dwell time of degraded is uniform"
+        );
+        assert_eq!(
+            degraded.upstream.loss, baseline.upstream.loss,
+            "The downstream line does not change the upstream loss"
+        );
+        assert_eq!(
+            degraded.downstream.loss,
+            LossModel::Uniform(0.05),
+            "Repeating a state adds to its settings"
+        );
+        assert_eq!(
+            degraded.downstream.ping_secs, 0.08,
+            "Repeating a state keeps its earlier settings"
+        );
+        assert_eq!(
+            degraded.transitions,
+            [
+                Transition { to: 0, rate: 0.5 },
+                Transition { to: 2, rate: 0.1 }
+            ],
+            "Degraded moves to both other states"
+        );
+
+        assert_eq!(
+            model.states[2].dwell,
+            Some(DwellTime::Fixed(3.0)),
+            "The dwell time of outage is fixed"
+        );
+    }
+
+    /// States are left along their transitions, in proportion to the rates
+    #[test]
+    fn transitions_follow_rates() {
+        let model = NetworkModel::parse(
+            "state a\nstate b\nstate c\nstate d\na -> b 1\na -> c 3\na -> d 0",
+            &ManglerConfig::default(),
+        )
+        .expect("The model is valid");
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mut counts = [0; 4];
+        for _ in 0..10_000 {
+            counts[model.states[0]
+                .sample_next(4, &mut rng)
+                .expect("The state has transitions")] += 1;
+        }
+
+        assert_eq!(counts[3], 0, "A transition with a zero rate is never taken");
+        assert!(
+            (7_000..8_000).contains(&counts[2]),
+            "The transition with three times the rate is taken about 75% of the time, not {} of 10000",
+            counts[2]
+        );
+        assert_eq!(
+            model.states[1].sample_dwell(4, &mut rng),
+            None,
+            "A state without transitions is never left"
+        );
+    }
+
+    /// Without a dwell time the time in a state follows from its total rate of leaving
+    #[test]
+    fn dwell_from_rates() {
+        let model = NetworkModel::parse(
+            "state a\nstate b\na -> b 2\na -> a 2",
+            &ManglerConfig::default(),
+        )
+        .expect("The model is valid");
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mean = (0..10_000)
+            .map(|_| {
+                model.states[0]
+                    .sample_dwell(2, &mut rng)
+                    .expect("The state is left")
+            })
+            .sum::<f64>()
+            / 10_000.0;
+
+        assert!(
+            (0.23..0.27).contains(&mean),
+            "The mean dwell is a quarter second, not {mean}"
+        );
+    }
+
+    /// A transition can only refer to states defined above it
+    #[test]
+    fn transition_before_state() {
+        assert_eq!(
+            NetworkModel::parse(
+                "state good\n\ngood -> bad 1\nstate bad",
+                &ManglerConfig::default()
+            ),
+            Err(NetworkModelErr::UnknownState(3)),
+            "The state bad is not defined yet"
+        );
+    }
+
+    /// Dwell times that can not be sampled are rejected, and dwell times too long for the clock are clamped
+    #[test]
+    fn extreme_dwell() {
+        let model = |dwell| NetworkModel {
+            states: vec![NetworkState {
+                name: "stuck".to_owned(),
+                upstream: ImpairmentConfig::default(),
+                downstream: ImpairmentConfig::default(),
+                dwell: Some(dwell),
+                transitions: vec![Transition { to: 0, rate: 1.0 }],
+            }],
+        };
+
+        assert_eq!(
+            model(DwellTime::Fixed(f64::NAN)).validate(),
+            Err(NetworkModelErr::InvalidDwell(1)),
+            "A dwell time that is not a number is rejected"
+        );
+        assert_eq!(
+            model(DwellTime::Uniform {
+                min_secs: 2.0,
+                max_secs: 1.0
+            })
+            .validate(),
+            Err(NetworkModelErr::InvalidDwell(1)),
+            "A reversed range is rejected"
+        );
+
+        let config = Arc::new(ArcSwap::from_pointee(ManglerConfig::default()));
+        let (done, finished) = mpsc::channel();
+        let playback = Playback::start(
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(ManualClock::new()),
+            move |control| {
+                network_model_main(config, model(DwellTime::Fixed(1e300)), 0, control);
+                _ = done.send(());
+            },
+        );
+        playback.stop();
+
+        assert_eq!(
+            finished.recv(),
+            Ok(()),
+            "The playback of an endless dwell stops without panicking"
+        );
+    }
+}
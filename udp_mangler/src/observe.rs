@@ -0,0 +1,200 @@
+//! Observation of the packets passing through the mangler
+
+use core::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::capture::Capture;
+use crate::clock::Clock;
+use crate::decision_log::DecisionLog;
+use crate::events::Subscribers;
+use crate::mangle::Fate;
+use crate::replay::DecisionRecorder;
+use crate::stats::{DropReason, StatsCollector};
+use crate::traffic::TrafficRecorder;
+use crate::{Direction, ManglerConfig, NewManglerErr, Packet};
+
+/// Everything that watches the packets passing through the mangler. Shared by all threads
+#[derive(Debug)]
+pub(crate) struct Observers {
+    /// The capture file, if any
+    pub(crate) capture: Option<Capture>,
+
+    /// The decision log, if any
+    pub(crate) decision_log: Option<DecisionLog>,
+
+    /// The recorder of the decisions, if any
+    pub(crate) decision_recorder: Option<DecisionRecorder>,
+
+    /// The recorder of the traffic of the clients, if any
+    pub(crate) traffic_recorder: Option<TrafficRecorder>,
+
+    /// The statistics
+    pub(crate) stats: StatsCollector,
+
+    /// The subscribers of the packet events
+    pub(crate) events: Subscribers,
+}
+
+impl Observers {
+    /// Creates the observers that are enabled in `config`, for a mangler listening on `listen`
+    /// and forwarding to `forward`, which takes the time from `clock`
+    pub(crate) fn new(
+        config: &ManglerConfig,
+        listen: SocketAddr,
+        forward: SocketAddr,
+        clock: &Arc<dyn Clock>,
+    ) -> Result<Self, NewManglerErr> {
+        Ok(Self {
+            capture: config
+                .capture
+                .as_deref()
+                .map(|path| Capture::create(path, listen, forward))
+                .transpose()
+                .map_err(NewManglerErr::Capture)?,
+            decision_log: config
+                .decision_log
+                .as_ref()
+                .map(|decision_log| DecisionLog::create(decision_log, clock.now()))
+                .transpose()
+                .map_err(NewManglerErr::DecisionLog)?,
+            decision_recorder: config
+                .record_decisions
+                .as_deref()
+                .map(DecisionRecorder::create)
+                .transpose()
+                .map_err(NewManglerErr::DecisionRecording)?,
+            traffic_recorder: config
+                .record_traffic
+                .as_deref()
+                .map(|path| TrafficRecorder::create(path, listen))
+                .transpose()
+                .map_err(NewManglerErr::TrafficRecording)?,
+            stats: StatsCollector::new(clock.clone()),
+            events: Subscribers::default(),
+        })
+    }
+
+    /// Called for every packet of `client` that arrived at `arrival`, with what the mangler did to it.
+    /// Packets that never reached the mangler thread have no `id`
+    pub(crate) fn received(
+        &self,
+        id: Option<u64>,
+        content: &[u8],
+        client: SocketAddr,
+        direction: Direction,
+        arrival: Instant,
+        fate: &Fate,
+    ) {
+        self.stats.received(content.len(), client, direction, fate);
+        self.events
+            .received(id, content.len(), client, direction, arrival, fate);
+
+        if let Some(capture) = &self.capture {
+            capture.received(content, client, direction, arrival, &fate.to_string());
+        }
+
+        if let Some(decision_log) = &self.decision_log {
+            decision_log.received(id, content.len(), client, direction, arrival, fate);
+        }
+    }
+
+    /// Called for every packet that reached the mangler thread of `direction`, with what the mangler did to it
+    pub(crate) fn decided(&self, packet: &Packet, direction: Direction, fate: &Fate) {
+        if let Some(decision_recorder) = &self.decision_recorder {
+            decision_recorder.decided(packet, direction, fate);
+        }
+    }
+
+    /// Called for every packet that `client` sent to the listener, which arrived at `at`
+    pub(crate) fn arrived(&self, content: &[u8], client: SocketAddr, at: Instant) {
+        if let Some(traffic_recorder) = &self.traffic_recorder {
+            traffic_recorder.arrived(content, client, at);
+        }
+    }
+
+    /// Called for every packet that was sent
+    pub(crate) fn sent(&self, packet: &Packet, direction: Direction, at: Instant) {
+        self.stats.sent(
+            packet.content.len(),
+            packet.client,
+            direction,
+            at.saturating_duration_since(packet.send_timestamp),
+        );
+        self.events.sent(
+            packet.id,
+            packet.content.len(),
+            packet.client,
+            direction,
+            at,
+        );
+
+        if let Some(capture) = &self.capture {
+            capture.sent(&packet.content, packet.client, direction, at);
+        }
+
+        if let Some(decision_log) = &self.decision_log {
+            decision_log.sent(packet, direction, at);
+        }
+    }
+
+    /// Called for every copy of a packet that could not be sent at `at`
+    pub(crate) fn undelivered(
+        &self,
+        packet: &Packet,
+        direction: Direction,
+        at: Instant,
+        reason: DropReason,
+    ) {
+        self.stats.undelivered(packet.client, direction, reason);
+        self.events.undelivered(
+            packet.id,
+            packet.content.len(),
+            packet.client,
+            direction,
+            at,
+            reason,
+        );
+
+        if let Some(decision_log) = &self.decision_log {
+            decision_log.undelivered(packet, direction, at, reason);
+        }
+    }
+
+    /// Called when all mangler threads have stopped
+    pub(crate) fn finish(&self) {
+        self.events.close();
+
+        if let Some(capture) = &self.capture {
+            capture.flush();
+        }
+
+        if let Some(decision_log) = &self.decision_log {
+            decision_log.finish();
+        }
+
+        if let Some(decision_recorder) = &self.decision_recorder {
+            decision_recorder.flush();
+        }
+
+        if let Some(traffic_recorder) = &self.traffic_recorder {
+            traffic_recorder.flush();
+        }
+    }
+
+    /// Called when the session of a client is opened on the local address `local`
+    pub(crate) fn session_opened(&self, client: SocketAddr, local: SocketAddr) {
+        if let Some(capture) = &self.capture {
+            capture.session_opened(client, local);
+        }
+    }
+
+    /// Called when the session of a client is closed
+    pub(crate) fn session_closed(&self, client: SocketAddr) {
+        self.stats.session_closed(client);
+
+        if let Some(capture) = &self.capture {
+            capture.session_closed(client);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Mangling of the packets in a pcap file, without any sockets
+
+use core::net::SocketAddr;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::mangle::MangleCore;
+use crate::pcap::{PcapErr, PcapReader, PcapWriter};
+use crate::stats::{Counters, DropReason};
+use crate::{Direction, ManglerConfig, Packet};
+
+/// Statistics on the packets of a pcap file mangled by [Mangler::mangle_pcap](crate::Mangler::mangle_pcap)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OfflineStats {
+    /// The packets travelling from the clients to the server
+    pub upstream: Counters,
+
+    /// The packets travelling from the server to the clients
+    pub downstream: Counters,
+
+    /// The number of packets in the input that are not unfragmented UDP packets, which are left out of the output
+    pub skipped_packets: u64,
+}
+
+impl OfflineStats {
+    /// Returns the counters of the packets travelling in the given direction
+    pub fn direction(&self, direction: Direction) -> &Counters {
+        match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        }
+    }
+
+    /// Returns the counters of the packets travelling in the given direction
+    fn direction_mut(&mut self, direction: Direction) -> &mut Counters {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+}
+
+/// The state of mangling a pcap file
+#[derive(Debug)]
+struct Offline<W> {
+    /// The output file
+    writer: PcapWriter<W>,
+
+    /// The mangling core of each direction
+    cores: [MangleCore; 2],
+
+    /// The moment that stands for the first packet of the input. All timestamps are counted from here
+    started: Instant,
+
+    /// The timestamp of the first packet of the input, since the Unix epoch
+    first_timestamp: core::time::Duration,
+
+    /// The other end of every packet that still has copies waiting to be sent, and the number of those copies
+    peers: [HashMap<u64, (SocketAddr, usize)>; 2],
+
+    /// The number of the flow of every client, in the order in which they were first seen
+    flows: HashMap<SocketAddr, u64>,
+
+    /// The statistics so far
+    stats: OfflineStats,
+}
+
+impl<W: Write> Offline<W> {
+    /// Writes all packets that are due at or before `until` to the output, in the order they are sent in.
+    /// If `until` is [None], all remaining packets are written
+    fn send_due(&mut self, until: Option<Instant>) -> std::io::Result<()> {
+        while let Some(now) = self
+            .cores
+            .iter()
+            .filter_map(MangleCore::next_deadline)
+            .min()
+            .filter(|next| until.is_none_or(|until| *next <= until))
+        {
+            for direction in [Direction::Upstream, Direction::Downstream] {
+                for packet in self.cores[direction as usize].poll_ready_packets(now) {
+                    self.send(&packet, direction, now)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a packet that is sent at `now` to the output
+    fn send(&mut self, packet: &Packet, direction: Direction, now: Instant) -> std::io::Result<()> {
+        let peers = &mut self.peers[direction as usize];
+
+        let Some((peer, remaining)) = peers.get_mut(&packet.id) else {
+            return Ok(());
+        };
+
+        let (src, dst) = match direction {
+            Direction::Upstream => (packet.client, *peer),
+            Direction::Downstream => (*peer, packet.client),
+        };
+
+        *remaining -= 1;
+        if *remaining == 0 {
+            peers.remove(&packet.id);
+        }
+
+        let counters = self.stats.direction_mut(direction);
+        counters.forwarded_packets += 1;
+        counters.forwarded_bytes += packet.content.len() as u64;
+
+        self.writer.write(
+            self.first_timestamp + now.saturating_duration_since(self.started),
+            src,
+            dst,
+            &packet.content,
+        )
+    }
+}
+
+/// Mangles the UDP packets of the pcap file `input`, and writes the result to the pcap file `output`.
+/// See [Mangler::mangle_pcap](crate::Mangler::mangle_pcap)
+pub(crate) fn mangle_pcap(
+    input: impl Read,
+    output: impl Write,
+    server: Option<SocketAddr>,
+    config: &ManglerConfig,
+) -> Result<OfflineStats, PcapErr> {
+    let mut reader = PcapReader::new(input)?;
+    let seed = config.seed.unwrap_or_else(rand::random);
+    let started = Instant::now();
+    let shared_config = Arc::new(config.clone());
+
+    let mut offline = Offline {
+        writer: PcapWriter::new(output).map_err(PcapErr::Io)?,
+        cores: [
+            MangleCore::with_seed(Direction::Upstream, shared_config.clone(), seed, started),
+            MangleCore::with_seed(Direction::Downstream, shared_config, seed, started),
+        ],
+        started,
+        first_timestamp: core::time::Duration::ZERO,
+        peers: [HashMap::new(), HashMap::new()],
+        flows: HashMap::new(),
+        stats: OfflineStats::default(),
+    };
+
+    let mut now = started;
+    let mut first = true;
+
+    while let Some(record) = reader.next_record()? {
+        if first {
+            offline.first_timestamp = record.timestamp;
+            first = false;
+        }
+
+        // Packets that are out of order in the capture arrive at the same time as the packet before them
+        now = now.max(started + record.timestamp.saturating_sub(offline.first_timestamp));
+
+        offline.send_due(Some(now)).map_err(PcapErr::Io)?;
+
+        let Some(udp) = record.udp else {
+            offline.stats.skipped_packets += 1;
+            continue;
+        };
+
+        let (direction, client, peer) = match server {
+            Some(server) if udp.src == server => (Direction::Downstream, udp.dst, udp.src),
+            _ => (Direction::Upstream, udp.src, udp.dst),
+        };
+
+        let counters = offline.stats.direction_mut(direction);
+        counters.received_packets += 1;
+        counters.received_bytes += udp.payload.len() as u64;
+
+        if udp.payload.len() >= config.buffer_size {
+            // The mangler would not have received the whole packet
+            counters.dropped.add(DropReason::Truncated);
+            continue;
+        }
+
+        let next_flow = offline.flows.len() as u64;
+        let flow = *offline.flows.entry(client).or_insert(next_flow);
+
+        let mut packet = Packet {
+            send_timestamp: now,
+            client,
+            id: 0,
+            flow,
+            replayed: false,
+            flow_index: 0,
+            seq: 0,
+            hold_positions: 0,
+            content: udp.payload,
+        };
+
+        let fate = offline.cores[direction as usize].push_packet(&mut packet, now);
+
+        if fate.delays.is_empty() {
+            if let Some(reason) = fate.dropped {
+                offline.stats.direction_mut(direction).dropped.add(reason);
+            }
+        } else {
+            offline.peers[direction as usize].insert(packet.id, (peer, fate.delays.len()));
+        }
+    }
+
+    offline.send_due(None).map_err(PcapErr::Io)?;
+    offline.writer.flush().map_err(PcapErr::Io)?;
+
+    Ok(offline.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use core::time::Duration;
+
+    use super::mangle_pcap;
+    use crate::pcap::{PcapReader, PcapWriter};
+    use crate::stats::DropReason;
+    use crate::{ImpairmentConfig, LossModel, ManglerConfig};
+
+    /// The address of the client in the capture
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+
+    /// The address of the server in the capture
+    const SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+
+    /// Returns a capture of a client and server exchanging `count` packets each, 10 ms apart
+    fn capture(count: u8) -> Vec<u8> {
+        let mut input = Vec::new();
+        let mut writer = PcapWriter::new(&mut input).expect("Writing to memory works");
+
+        for i in 0..count {
+            let at = Duration::from_secs(1_000) + Duration::from_millis(u64::from(i) * 10);
+            writer
+                .write(at, CLIENT, SERVER, &[i])
+                .expect("Writing to memory works");
+            writer
+                .write(at + Duration::from_millis(1), SERVER, CLIENT, &[i, i])
+                .expect("Writing to memory works");
+        }
+
+        writer.flush().expect("Writing to memory works");
+        input
+    }
+
+    /// Returns a config that delays every packet by 20 ms, without jitter or loss
+    fn config() -> ManglerConfig {
+        let impair = ImpairmentConfig {
+            ping_secs: 0.02,
+            jitter_secs: 0.0,
+            loss: LossModel::Uniform(0.0),
+            ..ImpairmentConfig::default()
+        };
+
+        ManglerConfig {
+            seed: Some(7),
+            upstream: impair.clone(),
+            downstream: impair,
+            ..ManglerConfig::default()
+        }
+    }
+
+    /// Every packet is written to the output with its addresses, delayed by the ping
+    #[test]
+    fn round_trip() {
+        let mut output = Vec::new();
+        let stats = mangle_pcap(capture(5).as_slice(), &mut output, Some(SERVER), &config())
+            .expect("The capture is valid");
+
+        assert_eq!(
+            stats.upstream.received_packets, 5,
+            "All client packets are read"
+        );
+        assert_eq!(
+            stats.downstream.received_packets, 5,
+            "All server packets are read"
+        );
+        assert_eq!(
+            stats.upstream.forwarded_packets, 5,
+            "All client packets are forwarded"
+        );
+        assert_eq!(
+            stats.downstream.forwarded_packets, 5,
+            "All server packets are forwarded"
+        );
+        assert_eq!(stats.skipped_packets, 0, "All packets are UDP");
+
+        let mut reader = PcapReader::new(output.as_slice()).expect("The output is a pcap file");
+        let mut records = Vec::new();
+
+        while let Some(record) = reader.next_record().expect("The output is a pcap file") {
+            records.push((
+                record.timestamp,
+                record.udp.expect("The output only has UDP packets"),
+            ));
+        }
+
+        assert_eq!(records.len(), 10, "Every packet is written once");
+
+        for (timestamp, udp) in records {
+            let i = udp.payload[0];
+            let (src, dst, sent_ms) = if udp.payload.len() == 1 {
+                (CLIENT, SERVER, u64::from(i) * 10)
+            } else {
+                (SERVER, CLIENT, u64::from(i) * 10 + 1)
+            };
+
+            assert_eq!(
+                (udp.src, udp.dst),
+                (src, dst),
+                "Packet {i} keeps its addresses"
+            );
+            assert_eq!(
+                timestamp,
+                Duration::from_secs(1_000) + Duration::from_millis(sent_ms + 20),
+                "Packet {i} is delayed by the ping"
+            );
+        }
+    }
+
+    /// The same seed mangles a capture the same way every time
+    #[test]
+    fn same_seed_same_output() {
+        let input = capture(50);
+        let config = ManglerConfig {
+            upstream: ImpairmentConfig::default(),
+            downstream: ImpairmentConfig::default(),
+            ..config()
+        };
+
+        let mangle = || {
+            let mut output = Vec::new();
+            mangle_pcap(input.as_slice(), &mut output, Some(SERVER), &config)
+                .expect("The capture is valid");
+            output
+        };
+
+        assert_eq!(mangle(), mangle(), "The outputs are identical");
+    }
+
+    /// Without a server every packet travels upstream, and packets that would not fit in the receive buffer are dropped
+    #[test]
+    fn no_server_and_truncated() {
+        let mut input = Vec::new();
+        let mut writer = PcapWriter::new(&mut input).expect("Writing to memory works");
+        let config = ManglerConfig {
+            buffer_size: 1000,
+            ..config()
+        };
+
+        writer
+            .write(Duration::from_secs(1), SERVER, CLIENT, &[1])
+            .expect("Writing to memory works");
+        writer
+            .write(
+                Duration::from_secs(2),
+                CLIENT,
+                SERVER,
+                &vec![0; config.buffer_size],
+            )
+            .expect("Writing to memory works");
+        writer.flush().expect("Writing to memory works");
+
+        let stats =
+            mangle_pcap(input.as_slice(), Vec::new(), None, &config).expect("The capture is valid");
+
+        assert_eq!(
+            stats.upstream.received_packets, 2,
+            "Both packets travel upstream"
+        );
+        assert_eq!(
+            stats.upstream.forwarded_packets, 1,
+            "Only the small packet is forwarded"
+        );
+        assert_eq!(
+            stats.upstream.dropped.get(DropReason::Truncated),
+            1,
+            "The packet that fills the whole buffer might have been truncated"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! Reading and writing of UDP packets in classic pcap files
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use core::time::Duration;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::capture::{LINKTYPE_RAW, ip_packet};
+
+/// The magic number of a pcap file with timestamps in microseconds
+const MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+
+/// The magic number of a pcap file with timestamps in nanoseconds
+const MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+
+/// BSD loopback encapsulation, with the address family in the byte order of the capturing machine
+const LINKTYPE_NULL: u32 = 0;
+
+/// Ethernet
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Linux cooked capture
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+/// Raw IPv4
+const LINKTYPE_IPV4: u32 = 228;
+
+/// Raw IPv6
+const LINKTYPE_IPV6: u32 = 229;
+
+/// Linux cooked capture version 2
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Ethertype of IPv4
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+/// Ethertype of IPv6
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+/// Ethertype of an 802.1Q VLAN tag
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// IP protocol number of UDP
+const PROTOCOL_UDP: u8 = 17;
+
+/// The largest packet that is read from a file that does not have a snapshot length. This is also the snapshot
+/// length of the files that are written, which fits any UDP packet over IPv4 or IPv6
+const DEFAULT_SNAPLEN: usize = 262_144;
+
+/// Error while reading a pcap file
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+pub enum PcapErr {
+    /// The file could not be read or written
+    #[display("IO error: {}", _0)]
+    Io(std::io::Error),
+
+    /// The file does not start with a pcap header. pcapng files are not supported
+    #[display("Not a pcap file")]
+    NotPcap,
+
+    /// The link type of the file is not supported
+    #[display("Unsupported link type {}", _0)]
+    UnsupportedLinkType(#[error(not(source))] u32),
+
+    /// The file ends in the middle of a packet
+    #[display("The file ends in the middle of packet {}", _0)]
+    Truncated(#[error(not(source))] usize),
+}
+
+/// A packet read from a pcap file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    /// The timestamp of the packet, since the Unix epoch
+    pub(crate) timestamp: Duration,
+
+    /// The packet, if it is an unfragmented UDP packet
+    pub(crate) udp: Option<UdpPacket>,
+}
+
+/// A UDP packet read from a pcap file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UdpPacket {
+    /// The address the packet was sent from
+    pub(crate) src: SocketAddr,
+
+    /// The address the packet was sent to
+    pub(crate) dst: SocketAddr,
+
+    /// The UDP payload
+    pub(crate) payload: Vec<u8>,
+}
+
+/// Reads the packets of a classic pcap file
+#[derive(Debug)]
+pub(crate) struct PcapReader<R> {
+    /// The file being read
+    reader: R,
+
+    /// Whether the fields of the file are big endian
+    big_endian: bool,
+
+    /// Whether the timestamps have nanosecond instead of microsecond precision
+    nanos: bool,
+
+    /// The link type of the packets
+    link_type: u32,
+
+    /// The largest number of bytes captured of a single packet
+    snaplen: usize,
+
+    /// The number of packets read so far
+    packets: usize,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the header of the file
+    pub(crate) fn new(mut reader: R) -> Result<Self, PcapErr> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => PcapErr::NotPcap,
+            _ => PcapErr::Io(e),
+        })?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (big_endian, nanos) = match (magic, magic.swap_bytes()) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(PcapErr::NotPcap),
+        };
+
+        let mut pcap = Self {
+            reader,
+            big_endian,
+            nanos,
+            link_type: 0,
+            snaplen: 0,
+            packets: 0,
+        };
+
+        // The upper bits hold the FCS length, which is not needed
+        pcap.link_type = pcap.u32_at(&header, 20) & 0x0FFF_FFFF;
+        pcap.snaplen = match pcap.u32_at(&header, 16) as usize {
+            0 => DEFAULT_SNAPLEN,
+            snaplen => snaplen,
+        };
+
+        match pcap.link_type {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_IPV4 | LINKTYPE_IPV6
+            | LINKTYPE_LINUX_SLL | LINKTYPE_LINUX_SLL2 => Ok(pcap),
+            link_type if link_type == u32::from(LINKTYPE_RAW) => Ok(pcap),
+            link_type => Err(PcapErr::UnsupportedLinkType(link_type)),
+        }
+    }
+
+    /// Reads the next packet of the file. Returns [None] at the end of the file
+    pub(crate) fn next_record(&mut self) -> Result<Option<Record>, PcapErr> {
+        let mut header = [0; 16];
+
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(PcapErr::Io(e)),
+        }
+
+        self.packets += 1;
+
+        let secs = self.u32_at(&header, 0);
+        let fraction = self.u32_at(&header, 4);
+        let captured_len = self.u32_at(&header, 8) as usize;
+
+        if captured_len > self.snaplen {
+            return Err(PcapErr::Io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Packet {} is {captured_len} bytes, more than the snapshot length of {} bytes",
+                    self.packets, self.snaplen
+                ),
+            )));
+        }
+
+        let mut frame = vec![0; captured_len];
+        self.reader
+            .read_exact(&mut frame)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => PcapErr::Truncated(self.packets),
+                _ => PcapErr::Io(e),
+            })?;
+
+        let timestamp = Duration::from_secs(u64::from(secs))
+            + if self.nanos {
+                Duration::from_nanos(u64::from(fraction))
+            } else {
+                Duration::from_micros(u64::from(fraction))
+            };
+
+        let udp = self
+            .ip_payload(&frame)
+            .and_then(udp)
+            .map(|(src, dst, payload)| UdpPacket {
+                src,
+                dst,
+                payload: payload.to_vec(),
+            });
+
+        Ok(Some(Record { timestamp, udp }))
+    }
+
+    /// Returns the IP packet inside a frame of the link type of the file
+    fn ip_payload<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        match self.link_type {
+            LINKTYPE_NULL => frame.get(4..),
+            LINKTYPE_ETHERNET => {
+                let mut offset = 12;
+                let mut ethertype =
+                    u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+
+                while ethertype == ETHERTYPE_VLAN {
+                    offset += 4;
+                    ethertype = u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+                }
+
+                matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6)
+                    .then_some(frame.get(offset + 2..)?)
+            }
+            LINKTYPE_LINUX_SLL => frame.get(16..),
+            LINKTYPE_LINUX_SLL2 => frame.get(20..),
+            _ => Some(frame),
+        }
+    }
+
+    /// Reads a 32 bit field in the byte order of the file
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let field = bytes[offset..offset + 4].try_into().unwrap();
+
+        if self.big_endian {
+            u32::from_be_bytes(field)
+        } else {
+            u32::from_le_bytes(field)
+        }
+    }
+}
+
+/// Returns the addresses and payload of an IPv4 or IPv6 packet, if it is an unfragmented UDP packet
+fn udp(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (src_ip, dst_ip, udp): (IpAddr, IpAddr, _) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0F) * 4;
+            let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?));
+            let fragment = u16::from_be_bytes(ip.get(6..8)?.try_into().ok()?);
+
+            // Fragments have the more fragments flag or an offset
+            if *ip.get(9)? != PROTOCOL_UDP || fragment & 0x3FFF != 0 {
+                return None;
+            }
+
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                ip.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            let payload_len = usize::from(u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?));
+
+            // Extension headers are not supported
+            if *ip.get(6)? != PROTOCOL_UDP {
+                return None;
+            }
+
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                ip.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let src_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let dst_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    let udp_len = usize::from(u16::from_be_bytes(udp.get(4..6)?.try_into().ok()?));
+
+    Some((
+        SocketAddr::new(src_ip, src_port),
+        SocketAddr::new(dst_ip, dst_port),
+        udp.get(8..udp_len)?,
+    ))
+}
+
+/// Writes UDP packets to a classic pcap file, with synthesized IP and UDP headers
+#[derive(Debug)]
+pub(crate) struct PcapWriter<W> {
+    /// The file being written
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the header of the file
+    pub(crate) fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(&MAGIC_MICROS.to_le_bytes())?;
+        writer.write_all(&2_u16.to_le_bytes())?;
+        writer.write_all(&4_u16.to_le_bytes())?;
+        // Time zone and accuracy of the timestamps, both always zero
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&(DEFAULT_SNAPLEN as u32).to_le_bytes())?;
+        writer.write_all(&u32::from(LINKTYPE_RAW).to_le_bytes())?;
+
+        Ok(Self { writer })
+    }
+
+    /// Writes a packet with the given timestamp since the Unix epoch
+    pub(crate) fn write(
+        &mut self,
+        timestamp: Duration,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let packet = ip_packet(payload, src, dst);
+
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&packet)
+    }
+
+    /// Writes everything that is buffered
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use core::time::Duration;
+
+    use super::{PcapReader, PcapWriter, UdpPacket};
+
+    /// An IPv6 packet with a payload too large for a 16 bit snapshot length is read back whole
+    #[test]
+    fn large_ipv6_round_trip() {
+        let packet = UdpPacket {
+            src: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 5000),
+            dst: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 6000),
+            payload: vec![0xab; 65_500],
+        };
+
+        let mut file = Vec::new();
+        let mut writer = PcapWriter::new(&mut file).expect("Writing to memory works");
+        writer
+            .write(
+                Duration::from_secs(1),
+                packet.src,
+                packet.dst,
+                &packet.payload,
+            )
+            .expect("Writing to memory works");
+        writer.flush().expect("Writing to memory works");
+
+        let mut reader = PcapReader::new(file.as_slice()).expect("The file is a pcap file");
+        let record = reader
+            .next_record()
+            .expect("The packet fits the snapshot length")
+            .expect("The file has a packet");
+
+        assert_eq!(record.udp, Some(packet), "The packet is read back whole");
+    }
+}
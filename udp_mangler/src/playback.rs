@@ -0,0 +1,136 @@
+//! Threads that play something back over time
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::clock::Clock;
+
+/// A thread that plays something back over time, like a [Scenario](crate::Scenario) or a
+/// [NetworkModel](crate::NetworkModel) changing the config, or a [TrafficRecording](crate::TrafficRecording)
+#[derive(Debug)]
+pub(crate) struct Playback {
+    /// A flag that can be set to stop the playback
+    stop: Arc<AtomicBool>,
+
+    /// The name of the state the playback is in, if it has states
+    state: Arc<Mutex<Option<String>>>,
+
+    /// Handle to the playback thread
+    thread: JoinHandle<()>,
+}
+
+impl Playback {
+    /// Starts running `run` on its own thread, following the time of `clock`
+    pub(crate) fn start(
+        quit: Arc<AtomicBool>,
+        clock: Arc<dyn Clock>,
+        run: impl FnOnce(PlaybackControl) + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(Mutex::new(None));
+
+        let control = PlaybackControl {
+            stop: stop.clone(),
+            quit,
+            clock,
+            state: state.clone(),
+        };
+
+        let thread = std::thread::spawn(move || run(control));
+
+        Self {
+            stop,
+            state,
+            thread,
+        }
+    }
+
+    /// Returns the name of the state the playback is in, if it has states
+    pub(crate) fn state(&self) -> Option<String> {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Stops the playback, and waits for its thread to finish.
+    /// The config stays as the playback left it. A panic of the playback thread is logged, so that it
+    /// does not take down whoever stops the playback
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+
+        if self.thread.join().is_err() {
+            log::error!("Playback thread panicked");
+        }
+    }
+}
+
+/// Gives a playback thread access to its stop flags and state
+#[derive(Debug)]
+pub(crate) struct PlaybackControl {
+    /// Set when only the playback should stop
+    stop: Arc<AtomicBool>,
+
+    /// Set when the whole mangler should stop
+    quit: Arc<AtomicBool>,
+
+    /// The clock the playback follows
+    clock: Arc<dyn Clock>,
+
+    /// The name of the state the playback is in
+    state: Arc<Mutex<Option<String>>>,
+}
+
+impl PlaybackControl {
+    /// Returns whether the playback should stop
+    pub(crate) fn should_stop(&self) -> bool {
+        self.stop.load(Ordering::Acquire) || self.quit.load(Ordering::Acquire)
+    }
+
+    /// Returns the flag that is set when the whole mangler should stop
+    pub(crate) fn quit(&self) -> &Arc<AtomicBool> {
+        &self.quit
+    }
+
+    /// Returns the current time of the clock the playback follows
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Sleeps for `duration` of the clock, while checking the stop flags once in a while.
+    /// A duration too long for the clock lasts until the playback is stopped.
+    /// Returns whether the playback should stop
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let Some(end) = self.now().checked_add(duration) else {
+            while !self.sleep(Duration::from_secs(1)) {}
+            return true;
+        };
+
+        self.sleep_until(end)
+    }
+
+    /// Sleeps until the clock reaches `end`, while checking the stop flags once in a while.
+    /// Returns whether the playback should stop
+    pub(crate) fn sleep_until(&self, end: Instant) -> bool {
+        // Also the interval in which a clock that is not the system clock is checked for being moved forward
+        const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+        loop {
+            if self.should_stop() {
+                return true;
+            }
+
+            let now = self.now();
+            if now >= end {
+                return false;
+            }
+
+            std::thread::sleep(end.duration_since(now).min(POLL_INTERVAL));
+        }
+    }
+
+    /// Sets the name of the state the playback is in
+    pub(crate) fn set_state(&self, name: &str) {
+        *self.state.lock().unwrap() = Some(name.to_owned());
+    }
+}
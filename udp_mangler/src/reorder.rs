@@ -0,0 +1,152 @@
+//! Explicit packet reordering
+
+use core::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::Packet;
+
+/// The maximum time a packet is held back waiting for later packets to overtake it.
+/// Makes sure that a pause in the stream does not hold back a packet indefinitely
+const MAX_HOLD: Duration = Duration::from_secs(1);
+
+/// How a reordered packet is held back
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReorderMode {
+    /// The packet is held back until the given number of later packets have overtaken it
+    Positions(usize),
+
+    /// The packet is held back by the given additional delay, in seconds
+    DelaySecs(f64),
+}
+
+impl Default for ReorderMode {
+    fn default() -> Self {
+        Self::Positions(1)
+    }
+}
+
+/// A packet that is being held back
+#[derive(Debug)]
+struct Held {
+    /// The packet itself
+    packet: Packet,
+
+    /// The number of later packets that still need to overtake this one
+    remaining: usize,
+
+    /// The time at which the packet is released regardless of how many packets overtook it
+    deadline: Instant,
+}
+
+/// Packets that are held back until a number of later packets have overtaken them
+#[derive(Debug, Default)]
+pub(crate) struct HeldBack {
+    /// The packets currently being held back
+    held: Vec<Held>,
+}
+
+impl HeldBack {
+    /// Holds back a packet until `positions` later packets have overtaken it
+    pub(crate) fn hold(&mut self, packet: Packet, positions: usize) {
+        let deadline = packet.send_timestamp + MAX_HOLD;
+
+        self.held.push(Held {
+            packet,
+            remaining: positions,
+            deadline,
+        });
+    }
+
+    /// Registers that the packet with the given `id` was sent, overtaking
+    /// any held back packets that arrived before it
+    pub(crate) fn overtaken_by(&mut self, id: u64) {
+        for held in &mut self.held {
+            if held.packet.id < id {
+                held.remaining = held.remaining.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Removes and returns all packets that may be sent again
+    pub(crate) fn release(&mut self, now: Instant) -> Vec<Packet> {
+        self.held
+            .extract_if(.., |held| held.remaining == 0 || held.deadline <= now)
+            .map(|held| held.packet)
+            .collect()
+    }
+
+    /// Returns the number of packets being held back
+    pub(crate) fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Returns the earliest time at which a held back packet is released by its deadline
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.held.iter().map(|held| held.deadline).min()
+    }
+}
+
+/// Statistics on the order in which packets leave the [Mangler](crate::Mangler)
+/// in a single [Direction](crate::Direction), compared to the order they arrived in.
+///
+/// The reorder distance of a late packet is how many positions earlier it arrived than the
+/// latest-arriving packet that was sent before it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// The number of packets sent
+    pub packets: u64,
+
+    /// The number of packets that were sent after a packet that arrived later
+    pub reordered: u64,
+
+    /// The largest reorder distance seen
+    pub max_distance: u64,
+
+    /// The sum of all reorder distances, of reordered packets only
+    pub total_distance: u64,
+}
+
+impl ReorderStats {
+    /// Returns the mean reorder distance of the reordered packets
+    pub fn mean_distance(&self) -> f64 {
+        if self.reordered == 0 {
+            0.0
+        } else {
+            self.total_distance as f64 / self.reordered as f64
+        }
+    }
+}
+
+/// Keeps track of the [ReorderStats] of outgoing packets
+#[derive(Debug, Default)]
+pub(crate) struct ReorderTracker {
+    /// The highest packet ID sent so far, and the statistics
+    state: Mutex<(Option<u64>, ReorderStats)>,
+}
+
+impl ReorderTracker {
+    /// Registers that the packet with the given `id` was sent
+    pub(crate) fn sent(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let (highest, stats) = &mut *state;
+
+        stats.packets += 1;
+
+        match *highest {
+            Some(h) if id < h => {
+                let distance = h - id;
+
+                stats.reordered += 1;
+                stats.total_distance += distance;
+                stats.max_distance = stats.max_distance.max(distance);
+            }
+            _ => *highest = Some(id),
+        }
+    }
+
+    /// Returns the current statistics
+    pub(crate) fn stats(&self) -> ReorderStats {
+        self.state.lock().unwrap().1.clone()
+    }
+}
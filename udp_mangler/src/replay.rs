@@ -0,0 +1,380 @@
+//! Recording of the decisions of the mangler, and deterministic replay of them
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::mangle::Fate;
+use crate::sink::FileSink;
+use crate::stats::DropReason;
+use crate::{Direction, Packet};
+
+/// The first line of a decision recording
+const HEADER: &str = "# flow direction index decision";
+
+/// Identifies a packet independently of its timing and the ports of its client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PacketKey {
+    /// The number of the flow, in the order in which the clients were first seen
+    flow: u64,
+
+    /// The direction of the packet
+    direction: Direction,
+
+    /// The number of earlier packets of the flow in the same direction
+    index: u64,
+}
+
+/// What the mangler decided for a single packet, in a [DecisionRecording]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedDecision {
+    /// The packet was dropped for the given reason
+    Drop(DropReason),
+
+    /// The packet was sent as the given copies. There is more than one copy if the packet was duplicated
+    Send(Vec<RecordedCopy>),
+}
+
+/// A single copy of a packet in a [RecordedDecision]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedCopy {
+    /// The delay of the copy, counted from the moment the packet arrived
+    pub delay: Duration,
+
+    /// The number of later packets that overtook the copy, for explicit reordering
+    pub hold_positions: usize,
+}
+
+/// The decisions the mangler made for every packet during an earlier run, recorded by setting
+/// [ManglerConfig::record_decisions](crate::ManglerConfig::record_decisions).
+///
+/// While a recording is set as [ManglerConfig::replay_decisions](crate::ManglerConfig::replay_decisions),
+/// every packet gets exactly the recorded decision of the packet with the same position in the same flow, regardless
+/// of the timing of the packets and the random seed. The flows are numbered in the order in which their clients were
+/// first seen, so that they match even if the clients use other ports. Packets beyond the end of the recording are
+/// mangled as usual. Changes to the payload, like corruption, are not part of the recording.
+///
+/// Every line of a recording holds the decision for a single packet. It starts with the number of the flow, the
+/// direction, and the number of earlier packets of the flow in that direction. Then follows either `drop` and the
+/// [reason](DropReason::label), or `send` and the delay of every copy in milliseconds, with an optional number of
+/// positions the copy was held back after a slash:
+///
+/// ```text
+/// # flow direction index decision
+/// 0 upstream 0 send 20.512
+/// 0 upstream 1 drop random_loss
+/// 0 downstream 0 send 20.105 35.250/1
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecisionRecording {
+    /// The recorded decisions. Shared, so that the config containing the recording is cheap to clone
+    decisions: Arc<HashMap<PacketKey, RecordedDecision>>,
+}
+
+/// Error while parsing a [DecisionRecording]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum DecisionRecordingErr {
+    /// A line could not be parsed as a decision
+    #[display("Invalid decision on line {}", _0)]
+    InvalidLine(#[error(not(source))] usize),
+
+    /// A line holds a decision for a packet that already has one
+    #[display("Second decision for the same packet on line {}", _0)]
+    DuplicatePacket(#[error(not(source))] usize),
+}
+
+impl DecisionRecording {
+    /// Parses a recording
+    pub fn parse(text: &str) -> Result<Self, DecisionRecordingErr> {
+        let mut decisions = HashMap::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, decision) =
+                parse_line(line).ok_or(DecisionRecordingErr::InvalidLine(i + 1))?;
+
+            if decisions.insert(key, decision).is_some() {
+                return Err(DecisionRecordingErr::DuplicatePacket(i + 1));
+            }
+        }
+
+        Ok(Self {
+            decisions: Arc::new(decisions),
+        })
+    }
+
+    /// Returns the number of recorded decisions
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    /// Returns whether there are no recorded decisions
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+
+    /// Returns the decision for a packet of the given flow, which had `index` earlier packets in the same direction
+    pub fn get(&self, flow: u64, direction: Direction, index: u64) -> Option<&RecordedDecision> {
+        self.decisions.get(&PacketKey {
+            flow,
+            direction,
+            index,
+        })
+    }
+}
+
+/// Parses a single line of a recording
+fn parse_line(line: &str) -> Option<(PacketKey, RecordedDecision)> {
+    let mut fields = line.split_whitespace();
+
+    let flow = fields.next()?.parse().ok()?;
+    let direction = match fields.next()? {
+        "upstream" => Direction::Upstream,
+        "downstream" => Direction::Downstream,
+        _ => return None,
+    };
+    let index = fields.next()?.parse().ok()?;
+
+    let decision = match fields.next()? {
+        "drop" => {
+            let label = fields.next()?;
+            let reason = DropReason::ALL
+                .into_iter()
+                .find(|reason| reason.label() == label)?;
+
+            if fields.next().is_some() {
+                return None;
+            }
+
+            RecordedDecision::Drop(reason)
+        }
+        "send" => {
+            let copies = fields.map(parse_copy).collect::<Option<Vec<_>>>()?;
+
+            if copies.is_empty() {
+                return None;
+            }
+
+            RecordedDecision::Send(copies)
+        }
+        _ => return None,
+    };
+
+    Some((
+        PacketKey {
+            flow,
+            direction,
+            index,
+        },
+        decision,
+    ))
+}
+
+/// Parses a copy of a packet, which is a delay in milliseconds with an optional number of positions after a slash
+fn parse_copy(text: &str) -> Option<RecordedCopy> {
+    let (delay, hold_positions) = match text.split_once('/') {
+        Some((delay, positions)) => (delay, positions.parse().ok()?),
+        None => (text, 0),
+    };
+
+    let delay_ms = delay.parse::<f64>().ok()?;
+
+    if !delay_ms.is_finite() || delay_ms < 0.0 {
+        return None;
+    }
+
+    Some(RecordedCopy {
+        delay: Duration::from_secs_f64(delay_ms / 1000.0),
+        hold_positions,
+    })
+}
+
+/// Writes a [DecisionRecording] while the mangler is running
+#[derive(Debug)]
+pub(crate) struct DecisionRecorder {
+    /// The file being written
+    writer: FileSink,
+}
+
+impl DecisionRecorder {
+    /// Creates the recording file
+    pub(crate) fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: FileSink::create(path, "decision recording", |mut writer| {
+                writeln!(writer, "{HEADER}")?;
+                Ok(writer)
+            })?,
+        })
+    }
+
+    /// Records what the mangler did to a packet
+    pub(crate) fn decided(&self, packet: &Packet, direction: Direction, fate: &Fate) {
+        let mut line = format!("{} {direction} {}", packet.flow, packet.flow_index);
+
+        if fate.delays.is_empty() {
+            // A packet without any copies always has a drop reason, this is just a fallback
+            let reason = fate.dropped.unwrap_or(DropReason::Stage);
+            line.push_str(" drop ");
+            line.push_str(reason.label());
+        } else {
+            line.push_str(" send");
+
+            for (delay, hold_positions) in fate.delays.iter().zip(&fate.hold_positions) {
+                line.push_str(&format!(" {:.3}", delay.as_secs_f64() * 1000.0));
+
+                if *hold_positions != 0 {
+                    line.push_str(&format!("/{hold_positions}"));
+                }
+            }
+        }
+
+        self.writer.line(&line);
+    }
+
+    /// Writes everything that is buffered to the file
+    pub(crate) fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, SocketAddr};
+    use core::time::Duration;
+    use std::time::Instant;
+
+    use super::{
+        DecisionRecorder, DecisionRecording, DecisionRecordingErr, RecordedCopy, RecordedDecision,
+    };
+    use crate::mangle::Fate;
+    use crate::stats::DropReason;
+    use crate::{Direction, Packet};
+
+    /// Returns a copy with a delay in milliseconds
+    fn copy(delay_ms: f64, hold_positions: usize) -> RecordedCopy {
+        RecordedCopy {
+            delay: Duration::from_secs_f64(delay_ms / 1000.0),
+            hold_positions,
+        }
+    }
+
+    /// Dropped and sent packets are parsed, with the positions of held back copies
+    #[test]
+    fn parse() {
+        let recording = DecisionRecording::parse(
+            "# flow direction index decision
+            0 upstream 0 send 20.512
+            0 upstream 1 drop random_loss
+
+            0 downstream 0 send 20.105 35.250/1
+            1 upstream 0 drop rule",
+        )
+        .expect("The recording is valid");
+
+        assert_eq!(recording.len(), 4, "Every line is a decision");
+        assert_eq!(
+            recording.get(0, Direction::Upstream, 0),
+            Some(&RecordedDecision::Send(vec![copy(20.512, 0)])),
+            "A single copy is sent"
+        );
+        assert_eq!(
+            recording.get(0, Direction::Upstream, 1),
+            Some(&RecordedDecision::Drop(DropReason::RandomLoss)),
+            "The drop reason is parsed from its label"
+        );
+        assert_eq!(
+            recording.get(0, Direction::Downstream, 0),
+            Some(&RecordedDecision::Send(vec![
+                copy(20.105, 0),
+                copy(35.25, 1)
+            ])),
+            "A duplicated packet has all its copies"
+        );
+        assert_eq!(
+            recording.get(1, Direction::Upstream, 0),
+            Some(&RecordedDecision::Drop(DropReason::Rule)),
+            "Packets of other flows are kept apart"
+        );
+        assert_eq!(
+            recording.get(1, Direction::Downstream, 0),
+            None,
+            "Packets that are not recorded have no decision"
+        );
+    }
+
+    /// What the recorder writes is read back as the same decisions
+    #[test]
+    fn record_and_parse() {
+        let path =
+            std::env::temp_dir().join(format!("udp_mangler_decisions_{}.txt", std::process::id()));
+        let packet = |flow, flow_index| Packet {
+            send_timestamp: Instant::now(),
+            client: SocketAddr::from((Ipv4Addr::LOCALHOST, 5000)),
+            id: 0,
+            flow,
+            replayed: false,
+            flow_index,
+            seq: 0,
+            hold_positions: 0,
+            content: Vec::new(),
+        };
+
+        {
+            let recorder = DecisionRecorder::create(&path).expect("The file can be created");
+            recorder.decided(
+                &packet(0, 0),
+                Direction::Upstream,
+                &Fate::dropped(DropReason::RandomLoss),
+            );
+            recorder.decided(
+                &packet(0, 1),
+                Direction::Upstream,
+                &Fate {
+                    delays: vec![
+                        Duration::from_secs_f64(0.0205),
+                        Duration::from_secs_f64(0.035),
+                    ],
+                    hold_positions: vec![0, 2],
+                    ..Fate::default()
+                },
+            );
+        }
+
+        let text = std::fs::read_to_string(&path).expect("The file was written");
+        _ = std::fs::remove_file(&path);
+        let recording = DecisionRecording::parse(&text).expect("The recording is valid");
+
+        assert_eq!(
+            recording.get(0, Direction::Upstream, 0),
+            Some(&RecordedDecision::Drop(DropReason::RandomLoss)),
+            "The drop is read back"
+        );
+        assert_eq!(
+            recording.get(0, Direction::Upstream, 1),
+            Some(&RecordedDecision::Send(vec![copy(20.5, 0), copy(35.0, 2)])),
+            "Both copies are read back, with their hold positions"
+        );
+    }
+
+    /// A packet can only have a single decision, and a dropped packet needs a known reason
+    #[test]
+    fn invalid_decisions() {
+        assert_eq!(
+            DecisionRecording::parse("# Header\n0 upstream 0 send 20\n\n0 upstream 0 drop rule"),
+            Err(DecisionRecordingErr::DuplicatePacket(4)),
+            "The same packet appears twice"
+        );
+        assert_eq!(
+            DecisionRecording::parse("0 upstream 0 drop bad_luck"),
+            Err(DecisionRecordingErr::InvalidLine(1)),
+            "The drop reason is unknown"
+        );
+    }
+}
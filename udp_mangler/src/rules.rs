@@ -0,0 +1,491 @@
+//! Rules that give selected packets different impairments
+
+use core::net::IpAddr;
+use core::ops::RangeInclusive;
+use core::str::FromStr;
+
+use crate::scenario::parse_setting;
+use crate::{Direction, ImpairmentConfig, ManglerConfig, Packet, ScenarioErr};
+
+/// A rule that selects packets and decides what happens to them. The rules are
+/// [configured](crate::ManglerConfig::rules) as an ordered list, and the first matching rule
+/// applies. Packets that match no rule get the impairments of their direction.
+/// All conditions that are set must match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    /// The direction of the packets, or [None] for both
+    pub direction: Option<Direction>,
+
+    /// The network of the client address. This is the source of upstream packets,
+    /// and the destination of downstream packets
+    pub client: Option<Cidr>,
+
+    /// The port of the client
+    pub client_port: Option<u16>,
+
+    /// The range of payload sizes in bytes
+    pub size: Option<RangeInclusive<usize>>,
+
+    /// A pattern in the payload
+    pub pattern: Option<BytePattern>,
+
+    /// What happens to matching packets
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// Returns whether a packet travelling in `direction` matches this rule
+    pub(crate) fn matches(&self, packet: &Packet, direction: Direction) -> bool {
+        self.direction
+            .is_none_or(|rule_direction| rule_direction == direction)
+            && self
+                .client
+                .as_ref()
+                .is_none_or(|client| client.contains(packet.client.ip()))
+            && self
+                .client_port
+                .is_none_or(|port| port == packet.client.port())
+            && self
+                .size
+                .as_ref()
+                .is_none_or(|size| size.contains(&packet.content.len()))
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(&packet.content))
+    }
+}
+
+/// What happens to the packets matching a [Rule]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum RuleAction {
+    /// Packets are sent right away, without any impairments
+    #[default]
+    Pass,
+
+    /// Packets are always dropped
+    Drop,
+
+    /// Packets get these impairments instead of the ones of their direction. The loss model and
+    /// rate limiter of each rule keep their own state, so the matching packets form a separate link
+    Impair(Box<ImpairmentConfig>),
+}
+
+/// A network of IP addresses, given by an address and the length of its prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    /// The address of the network
+    addr: IpAddr,
+
+    /// The number of leading bits that must match
+    prefix_len: u8,
+}
+
+/// Error while parsing a [Cidr]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display, derive_more::Error)]
+#[display("Invalid network address")]
+pub struct CidrErr;
+
+impl Cidr {
+    /// Creates a network from an address and a prefix length. Returns [None] if the prefix is longer than the address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        (prefix_len <= max_len).then_some(Self { addr, prefix_len })
+    }
+
+    /// Returns whether `addr` is part of this network. IPv4 addresses mapped to IPv6 count as IPv4 addresses
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let prefix_len = u32::from(self.prefix_len);
+
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrErr;
+
+    /// Parses a network like `10.0.0.0/8` or `fd00::/8`. A single address is a network of only that address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|_| CidrErr)?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| CidrErr)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Self::new(addr, prefix_len).ok_or(CidrErr)
+    }
+}
+
+/// A pattern of bytes at a fixed offset in the payload
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BytePattern {
+    /// The offset of the first byte of the pattern in the payload
+    pub offset: usize,
+
+    /// The bytes that must match
+    pub bytes: Vec<u8>,
+
+    /// The bits of each byte that must match. If shorter than the pattern, the missing bytes must match exactly
+    pub mask: Vec<u8>,
+}
+
+impl BytePattern {
+    /// Returns whether `content` contains this pattern
+    pub fn matches(&self, content: &[u8]) -> bool {
+        let Some(window) = content.get(self.offset..self.offset.saturating_add(self.bytes.len()))
+        else {
+            return false;
+        };
+
+        window
+            .iter()
+            .zip(&self.bytes)
+            .enumerate()
+            .all(|(i, (byte, expected))| {
+                let mask = self.mask.get(i).copied().unwrap_or(u8::MAX);
+                byte & mask == expected & mask
+            })
+    }
+}
+
+/// Error while parsing [rules](Rule)
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum RuleErr {
+    /// A line contains an unknown condition
+    #[display("Unknown condition on line {}", _0)]
+    UnknownCondition(#[error(not(source))] usize),
+
+    /// A line contains an unknown setting
+    #[display("Unknown setting on line {}", _0)]
+    UnknownSetting(#[error(not(source))] usize),
+
+    /// A condition or setting value is missing or invalid
+    #[display("Missing or invalid value on line {}", _0)]
+    InvalidValue(#[error(not(source))] usize),
+
+    /// A line has no action
+    #[display("No action on line {}", _0)]
+    NoAction(#[error(not(source))] usize),
+}
+
+impl From<ScenarioErr> for RuleErr {
+    fn from(err: ScenarioErr) -> Self {
+        match err {
+            ScenarioErr::UnknownSetting(line) => Self::UnknownSetting(line),
+            ScenarioErr::InvalidTime(line)
+            | ScenarioErr::InvalidValue(line)
+            | ScenarioErr::NoSettings(line) => Self::InvalidValue(line),
+        }
+    }
+}
+
+impl Rule {
+    /// Parses an ordered list of rules, with one rule per line. Each rule has its conditions,
+    /// followed by its action:
+    ///
+    /// ```text
+    /// # Conditions                                Action
+    /// client 10.0.0.5 port 5000                   impair loss 30% ping 100ms
+    /// client 10.0.0.0/8 upstream size 0-64        drop
+    /// pattern 4:ab01/ff0f                         pass
+    /// ```
+    ///
+    /// The conditions are `client <network>`, `port <port>`, `size <min>-<max>` or `size <size>`,
+    /// `pattern <offset>:<hex bytes>[/<hex mask>]`, and `upstream` or `downstream`.
+    /// The action is `pass`, `drop`, or `impair` followed by settings like in a [Scenario](crate::Scenario::parse),
+    /// which are applied on top of `baseline`. Empty lines and lines starting with `#` are ignored
+    pub fn parse_list(text: &str, baseline: &ManglerConfig) -> Result<Vec<Self>, RuleErr> {
+        let mut rules = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut rule = Self::default();
+            let mut settings = None;
+            let mut has_action = false;
+            let mut words = line.split_whitespace();
+            let invalid = || RuleErr::InvalidValue(line_number);
+
+            while let Some(word) = words.next() {
+                // Nothing may follow the action
+                if has_action {
+                    return Err(invalid());
+                }
+
+                match word {
+                    "client" => rule.client = Some(parse_word(words.next()).ok_or_else(invalid)?),
+                    "port" => {
+                        rule.client_port = Some(parse_word(words.next()).ok_or_else(invalid)?);
+                    }
+                    "size" => rule.size = Some(parse_size(words.next()).ok_or_else(invalid)?),
+                    "pattern" => {
+                        rule.pattern = Some(parse_pattern(words.next()).ok_or_else(invalid)?);
+                    }
+                    "upstream" => rule.direction = Some(Direction::Upstream),
+                    "downstream" => rule.direction = Some(Direction::Downstream),
+                    "pass" => rule.action = RuleAction::Pass,
+                    "drop" => rule.action = RuleAction::Drop,
+                    "impair" => {
+                        let mut parsed = Vec::new();
+                        while let Some(name) = words.next() {
+                            parsed.push(parse_setting(name, &mut words, line_number)?);
+                        }
+
+                        settings = Some(parsed);
+                    }
+                    _ => return Err(RuleErr::UnknownCondition(line_number)),
+                }
+
+                has_action = matches!(word, "pass" | "drop" | "impair");
+            }
+
+            if !has_action {
+                return Err(RuleErr::NoAction(line_number));
+            }
+
+            let Some(settings) = settings else {
+                rules.push(rule);
+                continue;
+            };
+
+            // The impairments are relative to the baseline of each direction,
+            // so a rule for both directions becomes a rule for each of them
+            let directions = match rule.direction {
+                Some(direction) => vec![direction],
+                None => vec![Direction::Upstream, Direction::Downstream],
+            };
+
+            for direction in directions {
+                let baseline = baseline.impairments(direction);
+                let mut config = baseline.clone();
+
+                for setting in &settings {
+                    setting.apply(&mut config, baseline);
+                }
+
+                rules.push(Self {
+                    direction: Some(direction),
+                    action: RuleAction::Impair(Box::new(config)),
+                    ..rule.clone()
+                });
+            }
+        }
+
+        Ok(rules)
+    }
+}
+
+/// Parses a single value
+fn parse_word<T: FromStr>(word: Option<&str>) -> Option<T> {
+    word?.parse().ok()
+}
+
+/// Parses a size range like `100-200`, or a single size
+fn parse_size(word: Option<&str>) -> Option<RangeInclusive<usize>> {
+    let word = word?;
+
+    let (min, max) = word.split_once('-').unwrap_or((word, word));
+    let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+
+    (min <= max).then_some(min..=max)
+}
+
+/// Parses a pattern like `4:ab01/ff0f`
+fn parse_pattern(word: Option<&str>) -> Option<BytePattern> {
+    let (offset, rest) = word?.split_once(':')?;
+    let (bytes, mask) = rest.split_once('/').unwrap_or((rest, ""));
+
+    Some(BytePattern {
+        offset: offset.parse().ok()?,
+        bytes: parse_hex(bytes).filter(|bytes| !bytes.is_empty())?,
+        mask: parse_hex(mask)?,
+    })
+}
+
+/// Parses bytes written as hexadecimal digits
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{BytePattern, Cidr, Rule, RuleAction, RuleErr};
+    use crate::{Direction, ImpairmentConfig, LossModel, ManglerConfig};
+
+    /// Conditions and actions are parsed, and impairments are applied on top of the baseline of each direction
+    #[test]
+    fn parse_list() {
+        let baseline = ManglerConfig {
+            downstream: ImpairmentConfig {
+                ping_secs: 0.5,
+                ..ImpairmentConfig::default()
+            },
+            ..ManglerConfig::default()
+        };
+
+        let rules = Rule::parse_list(
+            "# Conditions                                Action
+            client 10.0.0.5 port 5000                   impair loss 30% ping 100ms
+            client 10.0.0.0/8 upstream size 0-64        drop
+
+            pattern 4:ab01/ff0f                         pass",
+            &baseline,
+        )
+        .expect("The rules are valid");
+
+        let impaired = |baseline: &ImpairmentConfig| {
+            RuleAction::Impair(Box::new(ImpairmentConfig {
+                loss: LossModel::Uniform(0.3),
+                ping_secs: 0.1,
+                ..baseline.clone()
+            }))
+        };
+
+        let client = Rule {
+            client: Some(Cidr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), 32).unwrap()),
+            client_port: Some(5000),
+            ..Rule::default()
+        };
+
+        assert_eq!(
+            rules,
+            [
+                Rule {
+                    direction: Some(Direction::Upstream),
+                    action: impaired(&baseline.upstream),
+                    ..client.clone()
+                },
+                Rule {
+                    direction: Some(Direction::Downstream),
+                    action: impaired(&baseline.downstream),
+                    ..client
+                },
+                Rule {
+                    direction: Some(Direction::Upstream),
+                    client: Some(Cidr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8).unwrap()),
+                    size: Some(0..=64),
+                    action: RuleAction::Drop,
+                    ..Rule::default()
+                },
+                Rule {
+                    pattern: Some(BytePattern {
+                        offset: 4,
+                        bytes: vec![0xab, 0x01],
+                        mask: vec![0xff, 0x0f],
+                    }),
+                    action: RuleAction::Pass,
+                    ..Rule::default()
+                },
+            ],
+            "A rule with impairments for both directions becomes a rule for each direction"
+        );
+    }
+
+    /// Networks match by prefix, and IPv4 clients that reach an IPv6 socket still match IPv4 networks
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().expect("The network is valid");
+
+        assert!(
+            net.contains(IpAddr::V4(Ipv4Addr::new(10, 1, 200, 3))),
+            "An address inside the prefix matches"
+        );
+        assert!(
+            !net.contains(IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))),
+            "An address outside the prefix does not match"
+        );
+        assert!(
+            net.contains(IpAddr::V6(Ipv4Addr::new(10, 1, 0, 1).to_ipv6_mapped())),
+            "A mapped IPv4 address matches"
+        );
+
+        let everything: Cidr = "::/0".parse().expect("The network is valid");
+        assert!(
+            everything.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            "A zero prefix matches every IPv6 address"
+        );
+        assert!(
+            "10.0.0.0/33".parse::<Cidr>().is_err(),
+            "A prefix can not be longer than the address"
+        );
+    }
+
+    /// Only the masked bits of a pattern have to match, and payloads too short for the pattern never do
+    #[test]
+    fn pattern_matches() {
+        let pattern = BytePattern {
+            offset: 1,
+            bytes: vec![0xab, 0x01],
+            mask: vec![0xff, 0x0f],
+        };
+
+        assert!(
+            pattern.matches(&[0x00, 0xab, 0xf1, 0x00]),
+            "The masked out bits may differ"
+        );
+        assert!(
+            !pattern.matches(&[0x00, 0xab, 0x02]),
+            "The masked bits must match"
+        );
+        assert!(
+            !pattern.matches(&[0x00, 0xab]),
+            "A payload that ends inside the pattern does not match"
+        );
+    }
+
+    /// Values are checked, and errors report the number of the line counting comments and empty lines
+    #[test]
+    fn invalid_values() {
+        let parse = |text| Rule::parse_list(text, &ManglerConfig::default());
+
+        assert_eq!(
+            parse("size 64-0 drop"),
+            Err(RuleErr::InvalidValue(1)),
+            "A size range must not be reversed"
+        );
+        assert_eq!(
+            parse("# Comment\n\npattern 0:abc drop"),
+            Err(RuleErr::InvalidValue(3)),
+            "Hex needs whole bytes"
+        );
+        assert_eq!(
+            parse("port 80"),
+            Err(RuleErr::NoAction(1)),
+            "A rule needs an action"
+        );
+    }
+}
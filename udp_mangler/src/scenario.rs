@@ -0,0 +1,709 @@
+//! Timelines of scheduled and ramped configuration changes
+
+use core::time::Duration;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::playback::PlaybackControl;
+use crate::{Direction, ImpairmentConfig, LossModel, ManglerConfig};
+
+/// A timeline of configuration changes, which can be played back against a running mangler
+/// with [Mangler::play_scenario](crate::Mangler::play_scenario).
+/// All changes are relative to the baseline config the mangler had when the playback started
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    /// The changes that make up the timeline, in any order
+    pub steps: Vec<ScenarioStep>,
+
+    /// If set, the timeline starts over from the baseline config after this many seconds
+    pub loop_secs: Option<f64>,
+}
+
+/// A single change in a [Scenario]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScenarioStep {
+    /// The number of seconds after the start of the scenario at which the change starts
+    pub at_secs: f64,
+
+    /// The number of seconds over which the numeric settings move linearly from their previous
+    /// value to the new one. Other settings change right at the start of the ramp
+    pub ramp_secs: f64,
+
+    /// If set, the changed settings go back to the values they had before this step,
+    /// this many seconds after the ramp has ended
+    pub for_secs: Option<f64>,
+
+    /// The direction whose impairments are changed, or [None] to change both
+    pub direction: Option<Direction>,
+
+    /// The settings to change, applied in order
+    pub settings: Vec<Setting>,
+}
+
+/// A single setting changed by a [ScenarioStep]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    /// Returns all impairments to the baseline config
+    Baseline,
+
+    /// Sets the [ping](ImpairmentConfig::ping_secs), in seconds
+    PingSecs(f64),
+
+    /// Sets the [jitter](ImpairmentConfig::jitter_secs), in seconds
+    JitterSecs(f64),
+
+    /// Switches to the [uniform loss model](LossModel::Uniform) with the given loss factor
+    LossFactor(f64),
+
+    /// Sets the [duplicate factor](ImpairmentConfig::duplicate_factor)
+    DuplicateFactor(f64),
+
+    /// Sets the [reorder factor](ImpairmentConfig::reorder_factor)
+    ReorderFactor(f64),
+
+    /// Sets the [bit error rate](ImpairmentConfig::bit_error_rate)
+    BitErrorRate(f64),
+
+    /// Sets the [rate limit](ImpairmentConfig::rate_bits_per_sec), in bits per second
+    RateBitsPerSec(Option<f64>),
+}
+
+impl Setting {
+    /// Applies this setting to `config`, given the baseline config of the same direction
+    pub(crate) fn apply(&self, config: &mut ImpairmentConfig, baseline: &ImpairmentConfig) {
+        match *self {
+            Self::Baseline => *config = baseline.clone(),
+            Self::PingSecs(ping) => config.ping_secs = ping,
+            Self::JitterSecs(jitter) => config.jitter_secs = jitter,
+            Self::LossFactor(factor) => config.loss = LossModel::Uniform(factor),
+            Self::DuplicateFactor(factor) => config.duplicate_factor = factor,
+            Self::ReorderFactor(factor) => config.reorder_factor = factor,
+            Self::BitErrorRate(rate) => config.bit_error_rate = rate,
+            Self::RateBitsPerSec(rate) => config.rate_bits_per_sec = rate,
+        }
+    }
+
+    /// Undoes this setting in `config`, by taking back the value it had in `before`.
+    /// Other settings are left alone
+    fn restore(&self, config: &mut ImpairmentConfig, before: &ImpairmentConfig) {
+        match self {
+            Self::Baseline => *config = before.clone(),
+            Self::PingSecs(_) => config.ping_secs = before.ping_secs,
+            Self::JitterSecs(_) => config.jitter_secs = before.jitter_secs,
+            Self::LossFactor(_) => config.loss = before.loss.clone(),
+            Self::DuplicateFactor(_) => config.duplicate_factor = before.duplicate_factor,
+            Self::ReorderFactor(_) => config.reorder_factor = before.reorder_factor,
+            Self::BitErrorRate(_) => config.bit_error_rate = before.bit_error_rate,
+            Self::RateBitsPerSec(_) => config.rate_bits_per_sec = before.rate_bits_per_sec,
+        }
+    }
+}
+
+/// Error while parsing a [Scenario]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum ScenarioErr {
+    /// A line does not start with a valid time or keyword
+    #[display("Invalid time on line {}", _0)]
+    InvalidTime(#[error(not(source))] usize),
+
+    /// A line contains an unknown setting
+    #[display("Unknown setting on line {}", _0)]
+    UnknownSetting(#[error(not(source))] usize),
+
+    /// A duration or setting value is missing or invalid
+    #[display("Missing or invalid value on line {}", _0)]
+    InvalidValue(#[error(not(source))] usize),
+
+    /// A step does not change any settings
+    #[display("No settings on line {}", _0)]
+    NoSettings(#[error(not(source))] usize),
+}
+
+/// The moment a [Scenario] changes the config
+#[derive(Debug)]
+struct Event<'a> {
+    /// The number of seconds after the start of the scenario
+    at_secs: f64,
+
+    /// The step this event belongs to
+    step: &'a ScenarioStep,
+
+    /// The index of the step
+    index: usize,
+
+    /// Whether this event undoes the step, instead of applying it
+    restore: bool,
+}
+
+/// A ramp in progress from one config to another
+#[derive(Debug)]
+struct Ramp {
+    /// The config at the start of the ramp
+    from: ManglerConfig,
+
+    /// The config at the end of the ramp
+    to: ManglerConfig,
+
+    /// The start of the ramp, in seconds since the start of the scenario
+    start_secs: f64,
+
+    /// The length of the ramp in seconds
+    ramp_secs: f64,
+}
+
+impl Ramp {
+    /// Returns the interpolated config at the given moment
+    fn at(&self, secs: f64) -> ManglerConfig {
+        let t = ((secs - self.start_secs) / self.ramp_secs).clamp(0.0, 1.0);
+
+        let mut config = self.to.clone();
+        for direction in [Direction::Upstream, Direction::Downstream] {
+            *config.impairments_mut(direction) = interpolate(
+                self.from.impairments(direction),
+                self.to.impairments(direction),
+                t,
+            );
+        }
+
+        config
+    }
+}
+
+/// Linearly interpolates the numeric settings between `from` (at `t` 0.0) and `to` (at `t` 1.0).
+/// All other settings are taken from `to`
+fn interpolate(from: &ImpairmentConfig, to: &ImpairmentConfig, t: f64) -> ImpairmentConfig {
+    let mix = |from: f64, to: f64| from + (to - from) * t;
+
+    let mut config = to.clone();
+    config.ping_secs = mix(from.ping_secs, to.ping_secs);
+    config.jitter_secs = mix(from.jitter_secs, to.jitter_secs);
+    config.duplicate_factor = mix(from.duplicate_factor, to.duplicate_factor);
+    config.reorder_factor = mix(from.reorder_factor, to.reorder_factor);
+    config.bit_error_rate = mix(from.bit_error_rate, to.bit_error_rate);
+
+    if let (LossModel::Uniform(from), LossModel::Uniform(to)) = (&from.loss, &to.loss) {
+        config.loss = LossModel::Uniform(mix(*from, *to));
+    }
+
+    if let (Some(from), Some(to)) = (from.rate_bits_per_sec, to.rate_bits_per_sec) {
+        config.rate_bits_per_sec = Some(mix(from, to));
+    }
+
+    config
+}
+
+impl Scenario {
+    /// The interval at which the config is updated while a ramp is in progress
+    const RAMP_INTERVAL_SECS: f64 = 0.02;
+
+    /// Parses a scenario, with one step per line. Each step starts with the time at which it starts,
+    /// followed by optional modifiers, and the settings to change:
+    ///
+    /// ```text
+    /// # Time  Modifiers      Settings
+    /// 0s                     ping 20ms
+    /// 10s     ramp 5s        loss 30%
+    /// 20s     for 3s         outage
+    /// 25s                    baseline
+    /// 30s     downstream     rate 1mbit jitter 10ms
+    /// loop 40s
+    /// ```
+    ///
+    /// The modifiers are `ramp <duration>`, `for <duration>`, and `upstream` or `downstream`.
+    /// The settings are `baseline`, `ping <duration>`, `jitter <duration>`, `loss <ratio>`, `outage` (the same as `loss 100%`),
+    /// `duplicate <ratio>`, `reorder <ratio>`, `bit-error-rate <ratio>`, and `rate <bitrate>` or `rate off`.
+    /// Durations are in seconds unless they end in `ms` and last at most a year, ratios may end in `%`, and bitrates are in bits per second
+    /// unless they end in `kbit`, `mbit` or `gbit`. The line `loop <duration>` makes the scenario start over after that time.
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn parse(text: &str) -> Result<Self, ScenarioErr> {
+        let mut scenario = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+
+            let first = words.next().expect("Line is not empty");
+            if first == "loop" {
+                let duration = parse_value(words.next(), parse_duration, line_number)?;
+                if duration <= 0.0 || words.next().is_some() {
+                    return Err(ScenarioErr::InvalidValue(line_number));
+                }
+
+                scenario.loop_secs = Some(duration);
+                continue;
+            }
+
+            let mut step = ScenarioStep {
+                at_secs: parse_duration(first.trim_end_matches(':'))
+                    .ok_or(ScenarioErr::InvalidTime(line_number))?,
+                ..Default::default()
+            };
+
+            while let Some(word) = words.next() {
+                match word {
+                    "ramp" => {
+                        step.ramp_secs = parse_value(words.next(), parse_duration, line_number)?;
+                    }
+                    "for" => {
+                        step.for_secs =
+                            Some(parse_value(words.next(), parse_duration, line_number)?);
+                    }
+                    "upstream" => step.direction = Some(Direction::Upstream),
+                    "downstream" => step.direction = Some(Direction::Downstream),
+                    _ => step
+                        .settings
+                        .push(parse_setting(word, &mut words, line_number)?),
+                }
+            }
+
+            if step.settings.is_empty() {
+                return Err(ScenarioErr::NoSettings(line_number));
+            }
+
+            scenario.steps.push(step);
+        }
+
+        Ok(scenario)
+    }
+
+    /// Returns the config at `elapsed_secs` after the start of the scenario, given the baseline config
+    pub fn config_at(&self, baseline: &ManglerConfig, elapsed_secs: f64) -> ManglerConfig {
+        let secs = self.time_in_loop(elapsed_secs);
+
+        let mut current = baseline.clone();
+        let mut ramp: Option<Ramp> = None;
+
+        // The config before each step, so that it can be restored later
+        let mut before: Vec<Option<ManglerConfig>> = vec![None; self.steps.len()];
+
+        for event in self
+            .events()
+            .into_iter()
+            .take_while(|event| event.at_secs <= secs)
+        {
+            // A ramp that is still in progress is cut short by the next change
+            if let Some(ramp) = ramp.take() {
+                current = ramp.at(event.at_secs);
+            }
+
+            let directions = match event.step.direction {
+                Some(direction) => vec![direction],
+                None => vec![Direction::Upstream, Direction::Downstream],
+            };
+
+            let mut target = current.clone();
+
+            if event.restore {
+                // Only the settings of the step are undone, so that overlapping steps keep their own changes
+                if let Some(before) = &before[event.index] {
+                    for direction in directions {
+                        for setting in &event.step.settings {
+                            setting.restore(
+                                target.impairments_mut(direction),
+                                before.impairments(direction),
+                            );
+                        }
+                    }
+                }
+            } else {
+                before[event.index] = Some(current.clone());
+
+                for direction in directions {
+                    for setting in &event.step.settings {
+                        setting.apply(
+                            target.impairments_mut(direction),
+                            baseline.impairments(direction),
+                        );
+                    }
+                }
+            }
+
+            if !event.restore && event.step.ramp_secs > 0.0 {
+                ramp = Some(Ramp {
+                    from: current.clone(),
+                    to: target,
+                    start_secs: event.at_secs,
+                    ramp_secs: event.step.ramp_secs,
+                });
+            } else {
+                current = target;
+            }
+        }
+
+        match ramp {
+            Some(ramp) => ramp.at(secs),
+            None => current,
+        }
+    }
+
+    /// Returns when the config should be updated next, in seconds since the start of the scenario.
+    /// Returns [None] when the scenario has ended
+    fn next_update(&self, elapsed_secs: f64) -> Option<f64> {
+        let secs = self.time_in_loop(elapsed_secs);
+        let loop_start = elapsed_secs - secs;
+
+        let in_ramp = self.steps.iter().any(|step| {
+            step.ramp_secs > 0.0 && step.at_secs <= secs && secs < step.at_secs + step.ramp_secs
+        });
+
+        if in_ramp {
+            return Some(elapsed_secs + Self::RAMP_INTERVAL_SECS);
+        }
+
+        let next_event = self
+            .events()
+            .into_iter()
+            .map(|event| event.at_secs)
+            .find(|at_secs| *at_secs > secs);
+
+        match (next_event, self.loop_secs) {
+            (Some(at_secs), Some(loop_secs)) if at_secs >= loop_secs => {
+                Some(loop_start + loop_secs)
+            }
+            (Some(at_secs), _) => Some(loop_start + at_secs),
+            (None, Some(loop_secs)) => Some(loop_start + loop_secs),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns the time since the start of the current loop
+    fn time_in_loop(&self, elapsed_secs: f64) -> f64 {
+        match self.loop_secs {
+            Some(loop_secs) if loop_secs > 0.0 => elapsed_secs % loop_secs,
+            _ => elapsed_secs,
+        }
+    }
+
+    /// Returns the moments the config changes, sorted by time. When a step and
+    /// a restore happen at the same time, the restore goes first
+    fn events(&self) -> Vec<Event<'_>> {
+        let mut events = Vec::with_capacity(self.steps.len() * 2);
+
+        for (index, step) in self.steps.iter().enumerate() {
+            events.push(Event {
+                at_secs: step.at_secs,
+                step,
+                index,
+                restore: false,
+            });
+
+            if let Some(for_secs) = step.for_secs {
+                events.push(Event {
+                    at_secs: step.at_secs + step.ramp_secs + for_secs,
+                    step,
+                    index,
+                    restore: true,
+                });
+            }
+        }
+
+        events.sort_by(|a, b| {
+            a.at_secs
+                .total_cmp(&b.at_secs)
+                .then(b.restore.cmp(&a.restore))
+        });
+
+        events
+    }
+}
+
+/// Parses a single setting starting with `name`, taking its value from `words`
+pub(crate) fn parse_setting<'a>(
+    name: &str,
+    words: &mut impl Iterator<Item = &'a str>,
+    line_number: usize,
+) -> Result<Setting, ScenarioErr> {
+    let setting = match name {
+        "baseline" => Setting::Baseline,
+        "outage" => Setting::LossFactor(1.0),
+        "ping" => Setting::PingSecs(parse_value(words.next(), parse_duration, line_number)?),
+        "jitter" => Setting::JitterSecs(parse_value(words.next(), parse_duration, line_number)?),
+        "loss" => Setting::LossFactor(parse_value(words.next(), parse_ratio, line_number)?),
+        "duplicate" => {
+            Setting::DuplicateFactor(parse_value(words.next(), parse_ratio, line_number)?)
+        }
+        "reorder" => Setting::ReorderFactor(parse_value(words.next(), parse_ratio, line_number)?),
+        "bit-error-rate" => {
+            Setting::BitErrorRate(parse_value(words.next(), parse_ratio, line_number)?)
+        }
+        "rate" => Setting::RateBitsPerSec(match words.next() {
+            Some("off") => None,
+            word => Some(parse_value(word, parse_bitrate, line_number)?),
+        }),
+        _ => return Err(ScenarioErr::UnknownSetting(line_number)),
+    };
+
+    Ok(setting)
+}
+
+/// Parses a required value with the given parser
+pub(crate) fn parse_value(
+    word: Option<&str>,
+    parse: fn(&str) -> Option<f64>,
+    line_number: usize,
+) -> Result<f64, ScenarioErr> {
+    word.and_then(parse)
+        .ok_or(ScenarioErr::InvalidValue(line_number))
+}
+
+/// Parses a non-negative number that may end in the given suffixes, and multiplies it with the
+/// factor of the suffix. Without a suffix, the factor is 1.0
+fn parse_number(word: &str, suffixes: &[(&str, f64)]) -> Option<f64> {
+    let (number, factor) = suffixes
+        .iter()
+        .find_map(|(suffix, factor)| Some((word.strip_suffix(suffix)?, *factor)))
+        .unwrap_or((word, 1.0));
+
+    let value = number.parse::<f64>().ok()? * factor;
+
+    (value.is_finite() && value >= 0.0).then_some(value)
+}
+
+/// Parses a duration in seconds. Durations longer than a year are rejected, so that any sum of
+/// them still fits the clock
+pub(crate) fn parse_duration(word: &str) -> Option<f64> {
+    /// The longest duration that is accepted
+    const MAX_SECS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+    parse_number(word, &[("ms", 0.001), ("s", 1.0)]).filter(|secs| *secs <= MAX_SECS)
+}
+
+/// Parses a ratio between 0.0 and 1.0
+fn parse_ratio(word: &str) -> Option<f64> {
+    parse_number(word, &[("%", 0.01)]).filter(|ratio| *ratio <= 1.0)
+}
+
+/// Parses a bitrate in bits per second
+fn parse_bitrate(word: &str) -> Option<f64> {
+    parse_number(
+        word,
+        &[("kbit", 1e3), ("mbit", 1e6), ("gbit", 1e9), ("bit", 1.0)],
+    )
+    .filter(|rate| *rate > 0.0)
+}
+
+/// Main function for the scenario thread, which plays back a [Scenario] by updating the config
+/// until the scenario ends, or until the playback is stopped
+pub(crate) fn scenario_main(
+    config: Arc<ArcSwap<ManglerConfig>>,
+    scenario: Scenario,
+    control: PlaybackControl,
+) {
+    let baseline = config.load_full();
+    let start = control.now();
+    let mut last = None;
+
+    while !control.should_stop() {
+        let elapsed_secs = control.now().duration_since(start).as_secs_f64();
+        let new_config = scenario.config_at(&baseline, elapsed_secs);
+
+        // Only store actual changes, so that changes made by hand survive until the next step
+        if last.as_ref() != Some(&new_config) {
+            log::debug!("Scenario changed config at {elapsed_secs:.2}s");
+            config.store(Arc::new(new_config.clone()));
+            last = Some(new_config);
+        }
+
+        let Some(next_secs) = scenario.next_update(elapsed_secs) else {
+            log::info!("Scenario finished after {elapsed_secs:.2}s");
+            return;
+        };
+
+        // Steps of a scenario built by hand may be too far away for the clock
+        let wait = Duration::try_from_secs_f64((next_secs - elapsed_secs).max(0.0))
+            .unwrap_or(Duration::MAX);
+        control.sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scenario, ScenarioErr, ScenarioStep, Setting};
+    use crate::{Direction, LossModel, ManglerConfig};
+
+    /// All modifiers and settings are parsed, and comments and empty lines are skipped
+    #[test]
+    fn parse() {
+        let scenario = Scenario::parse(
+            "# Time  Modifiers      Settings
+            0s                     ping 20ms
+
+            10s     ramp 5s        loss 30%
+            20s     for 3s         outage
+            25:                    baseline
+            30s     downstream     rate 1mbit jitter 10ms
+            35s     upstream       rate off
+            loop 40s",
+        )
+        .expect("The scenario is valid");
+
+        let step = |at_secs, settings| ScenarioStep {
+            at_secs,
+            settings,
+            ..ScenarioStep::default()
+        };
+
+        assert_eq!(
+            scenario,
+            Scenario {
+                steps: vec![
+                    step(0.0, vec![Setting::PingSecs(0.02)]),
+                    ScenarioStep {
+                        ramp_secs: 5.0,
+                        ..step(10.0, vec![Setting::LossFactor(0.3)])
+                    },
+                    ScenarioStep {
+                        for_secs: Some(3.0),
+                        ..step(20.0, vec![Setting::LossFactor(1.0)])
+                    },
+                    step(25.0, vec![Setting::Baseline]),
+                    ScenarioStep {
+                        direction: Some(Direction::Downstream),
+                        ..step(
+                            30.0,
+                            vec![
+                                Setting::RateBitsPerSec(Some(1e6)),
+                                Setting::JitterSecs(0.01)
+                            ]
+                        )
+                    },
+                    ScenarioStep {
+                        direction: Some(Direction::Upstream),
+                        ..step(35.0, vec![Setting::RateBitsPerSec(None)])
+                    },
+                ],
+                loop_secs: Some(40.0),
+            },
+            "The parsed scenario matches the text"
+        );
+    }
+
+    /// A ramp moves the numeric settings linearly from the old value to the new one
+    #[test]
+    fn config_at_ramps() {
+        let baseline = ManglerConfig::default();
+        let scenario = Scenario::parse("0s ping 20ms\n10s ramp 10s ping 120ms")
+            .expect("The scenario is valid");
+
+        let ping_at = |secs| scenario.config_at(&baseline, secs).upstream.ping_secs;
+
+        assert_eq!(ping_at(5.0), 0.02, "Before the ramp the first step holds");
+        assert!(
+            (ping_at(15.0) - 0.07).abs() < 1e-9,
+            "Halfway the ramp the ping is halfway, not {}",
+            ping_at(15.0)
+        );
+        assert_eq!(
+            ping_at(30.0),
+            0.12,
+            "After the ramp the ping is the new value"
+        );
+    }
+
+    /// A step with a duration is undone afterwards, in its own direction only
+    #[test]
+    fn config_at_restores() {
+        let baseline = ManglerConfig::default();
+        let scenario =
+            Scenario::parse("5s for 2s downstream outage").expect("The scenario is valid");
+
+        let during = scenario.config_at(&baseline, 6.0);
+        assert_eq!(
+            during.downstream.loss,
+            LossModel::Uniform(1.0),
+            "The outage is on downstream"
+        );
+        assert_eq!(during.upstream, baseline.upstream, "Upstream is untouched");
+
+        assert_eq!(
+            scenario.config_at(&baseline, 7.5),
+            baseline,
+            "The outage is over after two seconds"
+        );
+    }
+
+    /// A looping scenario starts over from the baseline
+    #[test]
+    fn config_at_loops() {
+        let baseline = ManglerConfig::default();
+        let scenario = Scenario::parse("2s ping 80ms\nloop 5s").expect("The scenario is valid");
+
+        assert_eq!(
+            scenario.config_at(&baseline, 3.0).upstream.ping_secs,
+            0.08,
+            "The step applies in the first loop"
+        );
+        assert_eq!(
+            scenario.config_at(&baseline, 6.0),
+            baseline,
+            "The second loop starts from the baseline"
+        );
+        assert_eq!(
+            scenario.config_at(&baseline, 7.0).upstream.ping_secs,
+            0.08,
+            "The step applies again in the second loop"
+        );
+    }
+
+    /// Line numbers in errors count the comments and empty lines too
+    #[test]
+    fn error_line_numbers() {
+        assert_eq!(
+            Scenario::parse("# Comment\n\n0s ping 20ms\n5s ramp 2s"),
+            Err(ScenarioErr::NoSettings(4)),
+            "A step that only has modifiers has nothing to change"
+        );
+        assert_eq!(
+            Scenario::parse("0s loss 150%"),
+            Err(ScenarioErr::InvalidValue(1)),
+            "A ratio above 100% is not a loss factor"
+        );
+    }
+
+    /// When steps with a duration overlap, undoing one keeps the settings of the other
+    #[test]
+    fn config_at_restores_overlapping() {
+        let baseline = ManglerConfig::default();
+        let scenario = Scenario::parse("0s for 10s ping 100ms\n5s for 10s loss 50%")
+            .expect("The scenario is valid");
+
+        let after_first = scenario.config_at(&baseline, 12.0);
+        assert_eq!(
+            after_first.upstream.ping_secs, baseline.upstream.ping_secs,
+            "The ping is back to the baseline"
+        );
+        assert_eq!(
+            after_first.upstream.loss,
+            LossModel::Uniform(0.5),
+            "The loss of the second step is still in effect"
+        );
+
+        assert_eq!(
+            scenario.config_at(&baseline, 16.0),
+            baseline,
+            "Both steps are undone"
+        );
+    }
+
+    /// Durations are limited to a year, so that they fit the clock
+    #[test]
+    fn duration_limit() {
+        assert_eq!(
+            Scenario::parse("1e300s ping 20ms"),
+            Err(ScenarioErr::InvalidTime(1)),
+            "The step is too far away"
+        );
+        assert_eq!(
+            Scenario::parse("0s for 1e300s outage"),
+            Err(ScenarioErr::InvalidValue(1)),
+            "The step lasts too long"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Per-packet decisions made by an embedded Rhai script
+
+use core::net::SocketAddr;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rhai::{AST, Blob, CallFnOptions, Dynamic, Engine, Map, ParseError, Scope};
+
+use crate::stage::{Stage, StageContext, StagePacket, Verdict};
+
+/// The name of the function that a script must define
+const PROCESS_FN: &str = "process";
+
+/// The maximum number of operations a script may run for a single packet, so that an endless loop
+/// can not stall the mangler
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// The time after which the state of a flow without any packets is forgotten
+const FLOW_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The largest extra delay a script can give a packet. Longer delays are clamped to this
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// The largest number of extra copies a script can make of a packet. More copies are clamped to this
+const MAX_COPIES: usize = 100;
+
+/// A [Stage] that lets a [Rhai](https://rhai.rs) script decide what happens to each packet. The script
+/// must define a `process` function, which gets the packet and returns the decision:
+///
+/// ```text
+/// fn process(packet) {
+///     this.count = (this.count ?? 0) + 1;
+///
+///     if packet.size > 1000 && this.count % 10 == 0 {
+///         return #{ drop: true };
+///     }
+///
+///     if packet.payload[0] == 0x17 {
+///         packet.payload[1] = 0;
+///         return #{ delay_ms: 20, payload: packet.payload, duplicate: 1 };
+///     }
+/// }
+/// ```
+///
+/// The packet has the fields `payload` (a blob), `size`, `client` (the address and port of the client),
+/// `direction` (`"upstream"` or `"downstream"`) and `arrival_ms` (the milliseconds since the mangler started).
+/// `this` is a map with the state of the flow, which is kept between the packets of the same client in both directions.
+///
+/// The decision is a map with any of the fields `drop` (true to drop the packet), `delay_ms` (an extra delay,
+/// of at most a minute), `payload` (a replacement payload) and `duplicate` (the number of extra copies to send,
+/// at most 100). Returning nothing passes the packet unchanged. If the script fails, the packet is passed unchanged as well.
+///
+/// A new script can be loaded while the mangler is running by placing it in the [pipeline](crate::ManglerConfig::pipeline)
+/// of the config given to [Mangler::update_config](crate::Mangler::update_config). The new script starts without any flow state
+#[derive(Debug)]
+pub struct Script {
+    /// The engine that runs the script
+    engine: Engine,
+
+    /// The compiled script
+    ast: AST,
+
+    /// The state of every flow, and the moment its last packet was processed
+    flows: Mutex<HashMap<SocketAddr, (Dynamic, Instant)>>,
+
+    /// The last moment idle flows were forgotten
+    last_cleanup: Mutex<Option<Instant>>,
+}
+
+/// Error while compiling a [Script]
+#[derive(Debug, Clone, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum ScriptErr {
+    /// The script could not be parsed
+    #[display("Syntax error in script: {}", _0)]
+    Syntax(ParseError),
+
+    /// The script does not define a `process` function with a single parameter
+    #[display("The script does not define a `process(packet)` function")]
+    NoProcessFn,
+}
+
+impl Script {
+    /// Compiles a script
+    pub fn compile(source: &str) -> Result<Self, ScriptErr> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| log::info!("Script: {text}"));
+        engine.on_debug(|text, _, _| log::debug!("Script: {text}"));
+
+        let ast = engine.compile(source).map_err(ScriptErr::Syntax)?;
+
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == PROCESS_FN && function.params.len() == 1)
+        {
+            return Err(ScriptErr::NoProcessFn);
+        }
+
+        Ok(Self {
+            engine,
+            ast,
+            flows: Mutex::new(HashMap::new()),
+            last_cleanup: Mutex::new(None),
+        })
+    }
+
+    /// Runs the `process` function of the script for a packet, with the state of its flow
+    fn run(
+        &self,
+        packet: &StagePacket,
+        context: &StageContext<'_>,
+    ) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
+        let mut fields = Map::new();
+        fields.insert("payload".into(), Dynamic::from_blob(packet.content.clone()));
+        fields.insert("size".into(), (packet.content.len() as i64).into());
+        fields.insert("client".into(), packet.client.to_string().into());
+        fields.insert("direction".into(), context.direction().to_string().into());
+        fields.insert(
+            "arrival_ms".into(),
+            (packet
+                .arrival
+                .saturating_duration_since(context.started)
+                .as_secs_f64()
+                * 1000.0)
+                .into(),
+        );
+
+        let mut flows = self.flows.lock().unwrap();
+        let (state, last_seen) = flows
+            .entry(packet.client)
+            .or_insert_with(|| (Map::new().into(), context.now()));
+        *last_seen = context.now();
+
+        let mut options = CallFnOptions::new().bind_this_ptr(state);
+        // The script only defines functions, so there is no need to run its top level statements every time
+        options.eval_ast = false;
+
+        self.engine.call_fn_with_options(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            PROCESS_FN,
+            (Dynamic::from_map(fields),),
+        )
+    }
+
+    /// Forgets the state of flows that had no packets for a while
+    fn forget_idle_flows(&self, now: Instant) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+
+        if last_cleanup.is_some_and(|last| now.saturating_duration_since(last) < FLOW_TIMEOUT) {
+            return;
+        }
+
+        *last_cleanup = Some(now);
+
+        self.flows
+            .lock()
+            .unwrap()
+            .retain(|_, (_, last_seen)| now.saturating_duration_since(*last_seen) < FLOW_TIMEOUT);
+    }
+}
+
+impl Stage for Script {
+    fn process(&self, mut packet: StagePacket, context: &mut StageContext<'_>) -> Verdict {
+        self.forget_idle_flows(context.now());
+
+        let decision = match self.run(&packet, context) {
+            Ok(decision) => decision,
+            Err(e) => {
+                log::warn!("Script failed, passing packet unchanged: {e}");
+                return Verdict::Pass(packet);
+            }
+        };
+
+        if decision.is_unit() {
+            return Verdict::Pass(packet);
+        }
+
+        let Some(decision) = decision.try_cast::<Map>() else {
+            log::warn!("Script returned something other than a map, passing packet unchanged");
+            return Verdict::Pass(packet);
+        };
+
+        let field = |name: &str| decision.get(name).cloned();
+
+        if field("drop").and_then(|drop| drop.as_bool().ok()) == Some(true) {
+            return Verdict::Drop;
+        }
+
+        if let Some(payload) = field("payload").and_then(|payload| payload.try_cast::<Blob>()) {
+            packet.content = payload;
+        }
+
+        if let Some(delay_ms) = field("delay_ms").and_then(|delay| number(&delay)) {
+            let Some(send_at) = packet.send_at.checked_add(delay(delay_ms)) else {
+                log::warn!("Script delay overflows the send time of the packet, dropping it");
+                return Verdict::Drop;
+            };
+
+            packet.send_at = send_at;
+        }
+
+        let copies = field("duplicate")
+            .and_then(|copies| copies.as_int().ok())
+            .unwrap_or(0)
+            .max(0);
+
+        let copies = usize::try_from(copies)
+            .ok()
+            .filter(|copies| *copies <= MAX_COPIES)
+            .unwrap_or_else(|| {
+                log::warn!("Script asked for {copies} copies, sending {MAX_COPIES} instead");
+                MAX_COPIES
+            });
+
+        Verdict::Emit(vec![packet; 1 + copies])
+    }
+}
+
+/// Returns the extra delay for the `delay_ms` a script returned. Values that are not finite are ignored,
+/// and values above [MAX_DELAY] are clamped
+fn delay(delay_ms: f64) -> Duration {
+    if !delay_ms.is_finite() {
+        log::warn!("Script returned a delay of {delay_ms} ms, ignoring it");
+        return Duration::ZERO;
+    }
+
+    let max_ms = MAX_DELAY.as_secs_f64() * 1000.0;
+
+    if delay_ms > max_ms {
+        log::warn!("Script returned a delay of {delay_ms} ms, clamping it to {max_ms} ms");
+        return MAX_DELAY;
+    }
+
+    Duration::from_secs_f64(delay_ms.max(0.0) / 1000.0)
+}
+
+/// Returns the value of an integer or floating point number
+fn number(value: &Dynamic) -> Option<f64> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|int| int as f64))
+}
@@ -0,0 +1,410 @@
+//! Per-client sessions with the forward target
+
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::mpsc::{SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+
+use crate::clock::Clock;
+use crate::mangle::Fate;
+use crate::observe::Observers;
+use crate::stats::DropReason;
+use crate::{Direction, ManglerConfig, Packet};
+
+/// A single client session. Every distinct client gets its own socket towards the forward
+/// target, so that any replies can be routed back to the client that caused them
+#[derive(Debug)]
+pub(crate) struct Session {
+    /// The address of the client that opened this session
+    client: SocketAddr,
+
+    /// The number of the flow of the client
+    flow: u64,
+
+    /// Whether the session was opened for a [replay of recorded traffic](crate::Mangler::replay_traffic),
+    /// in which case the client is not there to receive replies
+    replayed: bool,
+
+    /// The socket connected to the forward target, used exclusively by this client
+    socket: UdpSocket,
+
+    /// The last time a packet passed through this session, in either direction
+    last_active: Mutex<Instant>,
+
+    /// A flag that is set when the session is closed, which stops its reply thread
+    closed: AtomicBool,
+}
+
+impl Session {
+    /// Sends a packet from the client to the forward target
+    pub(crate) fn send(&self, content: &[u8]) -> std::io::Result<usize> {
+        self.socket.send(content)
+    }
+
+    /// Marks the session as active at `now`, resetting its idle timeout
+    pub(crate) fn touch(&self, now: Instant) {
+        *self.last_active.lock().unwrap() = now;
+    }
+
+    /// Returns the number of the flow of the client, in the order in which the clients were first seen.
+    /// A client keeps its number when its session is closed and opened again
+    pub(crate) fn flow(&self) -> u64 {
+        self.flow
+    }
+
+    /// Returns for how long the session has not seen any packets
+    fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.last_active.lock().unwrap())
+    }
+}
+
+/// A [Session] together with the thread reading its replies
+#[derive(Debug)]
+struct SessionEntry {
+    /// The session itself
+    session: Arc<Session>,
+
+    /// Handle to the thread reading replies from the forward target
+    reply_thread: JoinHandle<()>,
+}
+
+/// The table of all currently open client sessions, keyed by client address and whether the session
+/// is [replayed](Session::replayed). A replayed client gets a session of its own, so that its packets and
+/// live packets of the same client never share a socket
+#[derive(Debug)]
+pub(crate) struct SessionTable {
+    /// The address every session forwards to
+    forward: SocketAddr,
+
+    /// The clock the arrival of the replies is taken from
+    clock: Arc<dyn Clock>,
+
+    /// The open sessions
+    sessions: Mutex<HashMap<(SocketAddr, bool), SessionEntry>>,
+
+    /// Sessions that have been closed, whose reply threads might not have finished yet
+    closing: Mutex<Vec<SessionEntry>>,
+
+    /// The number of the flow of every client ever seen, apart for live and replayed clients
+    flows: Mutex<HashMap<(SocketAddr, bool), u64>>,
+
+    /// Observers of the packets, which are told about opened and closed live sessions. Replayed sessions are
+    /// not reported, since the observers know clients only by their address
+    observers: Arc<Observers>,
+}
+
+impl SessionTable {
+    /// Creates a new, empty session table for sessions forwarding to `forward`
+    pub(crate) fn new(
+        forward: SocketAddr,
+        clock: Arc<dyn Clock>,
+        observers: Arc<Observers>,
+    ) -> Self {
+        Self {
+            forward,
+            clock,
+            sessions: Mutex::new(HashMap::new()),
+            closing: Mutex::new(Vec::new()),
+            flows: Mutex::new(HashMap::new()),
+            observers,
+        }
+    }
+
+    /// Returns the live or `replayed` session for the given client, if it is open
+    pub(crate) fn get(&self, client: SocketAddr, replayed: bool) -> Option<Arc<Session>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&(client, replayed))
+            .map(|entry| entry.session.clone())
+    }
+
+    /// Returns the live or `replayed` session for the given client, opening a new one if it does not exist yet.
+    /// Replies to a newly opened session are sent to `to_mangler`, unless it is `replayed`
+    pub(crate) fn get_or_open(
+        &self,
+        client: SocketAddr,
+        replayed: bool,
+        config: &Arc<ArcSwap<ManglerConfig>>,
+        to_mangler: &Sender<Packet>,
+        quit: &Arc<AtomicBool>,
+    ) -> std::io::Result<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        if let Some(entry) = sessions.get(&(client, replayed)) {
+            return Ok(entry.session.clone());
+        }
+
+        let socket = UdpSocket::bind(if self.forward.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        })?;
+
+        socket.connect(self.forward)?;
+        socket.set_read_timeout(Some(Duration::from_secs_f64(0.1)))?;
+        socket.set_write_timeout(Some(Duration::from_secs_f64(0.1)))?;
+
+        let local_addr = socket.local_addr()?;
+
+        log::debug!("Opened session for {client} on local address {local_addr}");
+        if !replayed {
+            self.observers.session_opened(client, local_addr);
+        }
+
+        let flow = {
+            let mut flows = self.flows.lock().unwrap();
+            let next_flow = flows.len() as u64;
+            *flows.entry((client, replayed)).or_insert(next_flow)
+        };
+
+        let session = Arc::new(Session {
+            client,
+            flow,
+            replayed,
+            socket,
+            last_active: Mutex::new(self.clock.now()),
+            closed: AtomicBool::new(false),
+        });
+
+        let session_cloned = session.clone();
+        let config_cloned = config.clone();
+        let clock_cloned = self.clock.clone();
+        let to_mangler_cloned = to_mangler.clone();
+        let observers_cloned = self.observers.clone();
+        let quit_cloned = quit.clone();
+        let reply_thread = std::thread::spawn(move || {
+            reply_main(
+                config_cloned,
+                clock_cloned,
+                session_cloned,
+                to_mangler_cloned,
+                observers_cloned,
+                quit_cloned,
+            )
+        });
+
+        sessions.insert(
+            (client, replayed),
+            SessionEntry {
+                session: session.clone(),
+                reply_thread,
+            },
+        );
+
+        Ok(session)
+    }
+
+    /// Closes all sessions that have been idle for longer than `timeout`. Does not wait for their
+    /// reply threads to finish, so that it can be called from the listener thread
+    pub(crate) fn close_idle(&self, timeout: Duration) {
+        let now = self.clock.now();
+
+        let idle: Vec<SessionEntry> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .extract_if(|_, entry| entry.session.idle_for(now) > timeout)
+            .map(|(_, entry)| entry)
+            .collect();
+
+        for entry in idle {
+            log::debug!("Closing idle session for {}", entry.session.client);
+            self.close(entry);
+        }
+
+        self.reap(false);
+    }
+
+    /// Closes all sessions and waits for their reply threads to finish
+    pub(crate) fn close_all(&self) {
+        let all: Vec<SessionEntry> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, entry)| entry)
+            .collect();
+
+        for entry in all {
+            self.close(entry);
+        }
+
+        self.reap(true);
+    }
+
+    /// Closes a single session. Its reply thread stops by itself, and is joined by [Self::reap]
+    fn close(&self, entry: SessionEntry) {
+        entry.session.closed.store(true, Ordering::Release);
+        if !entry.session.replayed {
+            self.observers.session_closed(entry.session.client);
+        }
+        self.closing.lock().unwrap().push(entry);
+    }
+
+    /// Joins the reply threads of closed sessions. If `wait` is false, only the threads that
+    /// have already finished are joined
+    fn reap(&self, wait: bool) {
+        let finished: Vec<SessionEntry> = self
+            .closing
+            .lock()
+            .unwrap()
+            .extract_if(.., |entry| wait || entry.reply_thread.is_finished())
+            .collect();
+
+        for entry in finished {
+            entry
+                .reply_thread
+                .join()
+                .expect("Failed to join session reply thread");
+        }
+    }
+}
+
+/// The main function for a session reply thread. Each session has one, which reads the replies
+/// of the forward target and passes them on to the downstream [mangler thread](crate::mangle::mangle_main)
+fn reply_main(
+    config: Arc<ArcSwap<ManglerConfig>>,
+    clock: Arc<dyn Clock>,
+    session: Arc<Session>,
+    to_mangler: Sender<Packet>,
+    observers: Arc<Observers>,
+    quit: Arc<AtomicBool>,
+) {
+    let mut buffer = Vec::new();
+
+    while !quit.load(Ordering::Acquire) && !session.closed.load(Ordering::Acquire) {
+        buffer.clear();
+        buffer.resize(config.load().buffer_size, 0);
+
+        let packet_size = match session.socket.recv(&mut buffer) {
+            Ok(packet_size) => packet_size,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                // Retry loop
+                continue;
+            }
+            Err(e)
+                if e.kind() == ErrorKind::ConnectionRefused
+                    || e.kind() == ErrorKind::ConnectionReset =>
+            {
+                // The forward target is not (yet) listening, which shows up as a reset on Windows.
+                // Not fatal, it might come up later
+                log::debug!("Forward target refused packet from {}", session.client);
+                continue;
+            }
+            Err(e) => {
+                log::error!("Session socket err for {}: {e}", session.client);
+                return;
+            }
+        };
+
+        if packet_size >= buffer.len() {
+            // Packet might be truncated
+            observers.received(
+                None,
+                &buffer,
+                session.client,
+                Direction::Downstream,
+                clock.now(),
+                &Fate::dropped(DropReason::Truncated),
+            );
+            continue;
+        }
+
+        log::trace!("New UDP reply of size {packet_size} for {}", session.client);
+
+        session.touch(clock.now());
+
+        if session.replayed {
+            log::trace!("Discarding reply to replayed packet of {}", session.client);
+            continue;
+        }
+
+        let packet = Packet {
+            send_timestamp: clock.now(),
+            client: session.client,
+            id: 0,
+            flow: session.flow,
+            replayed: session.replayed,
+            flow_index: 0,
+            seq: 0,
+            hold_positions: 0,
+            content: Vec::from(&buffer[..packet_size]),
+        };
+
+        match to_mangler.send(packet) {
+            Ok(val) => val,
+            Err(SendError(_)) => {
+                log::debug!("Reply thread returning because the to_mangler channel has closed");
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, SocketAddr};
+    use core::sync::atomic::AtomicBool;
+    use std::net::UdpSocket;
+    use std::sync::{Arc, mpsc};
+
+    use arc_swap::ArcSwap;
+
+    use super::SessionTable;
+    use crate::ManglerConfig;
+    use crate::clock::{Clock, ManualClock};
+    use crate::observe::Observers;
+
+    /// A replayed client gets a session and flow of its own, next to the live session of the same address
+    #[test]
+    fn replayed_sessions_are_apart() {
+        let target =
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Can bind the forward target");
+        let forward = target.local_addr().unwrap();
+        let config = ManglerConfig::default();
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let observers =
+            Observers::new(&config, forward, forward, &clock).expect("No files to open");
+        let sessions = SessionTable::new(forward, clock, Arc::new(observers));
+
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let (to_mangler, _from_sessions) = mpsc::channel();
+        let quit = Arc::new(AtomicBool::new(false));
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 5000));
+
+        let open = |replayed| {
+            sessions
+                .get_or_open(client, replayed, &config, &to_mangler, &quit)
+                .expect("The session opens")
+        };
+        let live = open(false);
+        let replayed = open(true);
+
+        assert!(
+            !Arc::ptr_eq(&live, &replayed),
+            "The replayed client does not share the live session"
+        );
+        assert_ne!(
+            live.flow(),
+            replayed.flow(),
+            "The replayed client is a flow of its own"
+        );
+        assert!(
+            sessions
+                .get(client, false)
+                .is_some_and(|session| Arc::ptr_eq(&session, &live)),
+            "Live packets are forwarded through the live session"
+        );
+
+        sessions.close_all();
+    }
+}
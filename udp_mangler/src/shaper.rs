@@ -0,0 +1,206 @@
+//! Bandwidth limiting with a token bucket or a delivery trace
+
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::ImpairmentConfig;
+use crate::delivery_trace::TraceCursor;
+
+/// What happens to packets that exceed the configured rate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateMode {
+    /// Packets are queued behind each other until the rate allows them to be sent
+    #[default]
+    Shape,
+
+    /// Packets that cannot be sent right away are dropped
+    Police,
+}
+
+/// Why the [Shaper] did not accept a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub(crate) enum ShaperDrop {
+    /// The shaper queue would exceed its byte limit
+    #[display("queue limit exceeded")]
+    QueueFull,
+
+    /// The packet exceeds the rate while policing
+    #[display("rate exceeded")]
+    OverRate,
+}
+
+/// A rate limiter using a token bucket, or a [DeliveryTrace](crate::DeliveryTrace) if one is configured.
+/// Keeps track of when the packets accepted so far leave the (virtual) bottleneck link
+#[derive(Debug, Default)]
+pub(crate) struct Shaper {
+    /// The number of bytes that may be sent without waiting, at [updated](Self::updated)
+    tokens: f64,
+
+    /// The moment the bucket was last updated, which is also the moment the last accepted packet leaves the link
+    updated: Option<Instant>,
+
+    /// The moments at which the queued packets leave the link, and their sizes
+    queue: VecDeque<(Instant, usize)>,
+
+    /// The total size of the packets in [queue](Self::queue)
+    queued_bytes: usize,
+
+    /// The position in the delivery trace, which starts with the first packet
+    trace: Option<TraceCursor>,
+}
+
+impl Shaper {
+    /// Accepts a packet of `size` bytes arriving at `now`, and returns the moment it leaves the link.
+    /// If there is no rate or delivery trace configured the packet leaves right away
+    pub(crate) fn admit(
+        &mut self,
+        size: usize,
+        config: &ImpairmentConfig,
+        now: Instant,
+    ) -> Result<Instant, ShaperDrop> {
+        let rate_bits_per_sec = limit(config.rate_bits_per_sec);
+
+        if rate_bits_per_sec.is_none() && config.delivery_trace.is_none() {
+            return Ok(now);
+        }
+
+        while let Some((departure, departed_size)) = self.queue.front()
+            && *departure <= now
+        {
+            self.queued_bytes -= departed_size;
+            self.queue.pop_front();
+        }
+
+        if let Some(limit) = config.rate_queue_limit_bytes
+            && config.rate_mode == RateMode::Shape
+            && self.queued_bytes + size > limit
+        {
+            return Err(ShaperDrop::QueueFull);
+        }
+
+        let mut tokens = self.tokens;
+        let mut trace = self.trace;
+
+        let mut departure = match (&config.delivery_trace, rate_bits_per_sec) {
+            (Some(delivery_trace), _) => {
+                let (departure, cursor) = trace.get_or_insert_with(|| TraceCursor::new(now)).send(
+                    delivery_trace,
+                    size,
+                    now,
+                );
+
+                trace = Some(cursor);
+                departure
+            }
+            (None, Some(bits_per_sec)) => {
+                let (departure, remaining) = self.bucket_departure(size, bits_per_sec, config, now);
+
+                tokens = remaining;
+                departure
+            }
+            (None, None) => unreachable!("Checked above"),
+        };
+
+        if let Some(packets_per_sec) = limit(config.rate_packets_per_sec)
+            && let Some(updated) = self.updated
+        {
+            departure = departure.max(updated + Duration::from_secs_f64(1.0 / packets_per_sec));
+        }
+
+        if config.rate_mode == RateMode::Police && departure > now {
+            return Err(ShaperDrop::OverRate);
+        }
+
+        self.tokens = tokens;
+        self.trace = trace;
+        self.updated = Some(departure);
+        self.queue.push_back((departure, size));
+        self.queued_bytes += size;
+
+        Ok(departure)
+    }
+
+    /// Returns the moment a packet leaves the token bucket, and the tokens left after it
+    fn bucket_departure(
+        &self,
+        size: usize,
+        bits_per_sec: f64,
+        config: &ImpairmentConfig,
+        now: Instant,
+    ) -> (Instant, f64) {
+        let bytes_per_sec = bits_per_sec / 8.0;
+
+        // A packet can only start once the previous one has left the link
+        let start = match self.updated {
+            Some(updated) => updated.max(now),
+            None => now,
+        };
+
+        let refill = match self.updated {
+            Some(updated) => start.duration_since(updated).as_secs_f64() * bytes_per_sec,
+            None => f64::INFINITY,
+        };
+
+        // A policer with a bucket too small for a single packet would drop every packet
+        let burst = match config.rate_mode {
+            RateMode::Shape => config.rate_burst_bytes,
+            RateMode::Police => config.rate_burst_bytes.max(config.max_payload_size),
+        };
+
+        let tokens = (self.tokens + refill).min(burst as f64);
+
+        // Without enough tokens, the packet waits for the missing ones. With an empty bucket this
+        // is exactly the serialization delay of the packet
+        let mut departure = start;
+        if tokens < size as f64 {
+            departure += Duration::from_secs_f64((size as f64 - tokens) / bytes_per_sec);
+        }
+
+        (departure, (tokens - size as f64).max(0.0))
+    }
+}
+
+/// Returns a configured rate if it limits anything. Rates that are not positive finite numbers are unlimited
+fn limit(rate: Option<f64>) -> Option<f64> {
+    rate.filter(|rate| *rate > 0.0 && rate.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::time::Instant;
+
+    use super::{RateMode, Shaper, ShaperDrop};
+    use crate::ImpairmentConfig;
+
+    /// A policer without a configured burst still lets a packet of the maximum payload size pass an idle link,
+    /// and drops the packets that exceed the rate after it
+    #[test]
+    fn police_without_burst() {
+        let config = ImpairmentConfig {
+            rate_bits_per_sec: Some(8000.0),
+            rate_burst_bytes: 0,
+            rate_mode: RateMode::Police,
+            ..ImpairmentConfig::default()
+        };
+        let mut shaper = Shaper::default();
+        let now = Instant::now();
+
+        assert_eq!(
+            shaper.admit(config.max_payload_size, &config, now),
+            Ok(now),
+            "The first packet passes right away"
+        );
+        assert_eq!(
+            shaper.admit(100, &config, now),
+            Err(ShaperDrop::OverRate),
+            "The bucket is empty after the first packet"
+        );
+        assert_eq!(
+            shaper.admit(100, &config, now + Duration::from_millis(100)),
+            Ok(now + Duration::from_millis(100)),
+            "The bucket refills with the rate"
+        );
+    }
+}
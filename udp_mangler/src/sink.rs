@@ -0,0 +1,79 @@
+//! Files that the mangler threads write to while the mangler is running
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::pcap::PcapWriter;
+
+/// A writer that buffers what is written to it
+pub(crate) trait Flush {
+    /// Writes everything that is buffered
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+impl<W: Write> Flush for BufWriter<W> {
+    fn flush(&mut self) -> std::io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+impl<W: Write> Flush for PcapWriter<W> {
+    fn flush(&mut self) -> std::io::Result<()> {
+        PcapWriter::flush(self)
+    }
+}
+
+/// A file shared by all threads. Errors while writing are logged, so that a full disk does not stop
+/// the mangler. Everything that is buffered is written when the sink is dropped
+#[derive(Debug)]
+pub(crate) struct FileSink<W: Flush = BufWriter<File>> {
+    /// The file being written
+    writer: Mutex<W>,
+
+    /// What the file is, for the log messages
+    name: &'static str,
+}
+
+impl<W: Flush> FileSink<W> {
+    /// Creates the file at `path`, called `name` in the log messages. `open` wraps the buffered file,
+    /// and may write a header to it
+    pub(crate) fn create(
+        path: &Path,
+        name: &'static str,
+        open: impl FnOnce(BufWriter<File>) -> std::io::Result<W>,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: Mutex::new(open(BufWriter::new(File::create(path)?))?),
+            name,
+        })
+    }
+
+    /// Runs `write` with exclusive access to the file, and logs any error it returns
+    pub(crate) fn write(&self, write: impl FnOnce(&mut W) -> std::io::Result<()>) {
+        if let Err(e) = write(&mut self.writer.lock().unwrap()) {
+            log::warn!("Could not write to {}: {e}", self.name);
+        }
+    }
+
+    /// Writes everything that is buffered to the file
+    pub(crate) fn flush(&self) {
+        if let Err(e) = Flush::flush(&mut *self.writer.lock().unwrap()) {
+            log::warn!("Could not write {}: {e}", self.name);
+        }
+    }
+}
+
+impl<W: Flush + Write> FileSink<W> {
+    /// Writes a single line to the file
+    pub(crate) fn line(&self, line: &str) {
+        self.write(|writer| writeln!(writer, "{line}"));
+    }
+}
+
+impl<W: Flush> Drop for FileSink<W> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
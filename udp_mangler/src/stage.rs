@@ -0,0 +1,175 @@
+//! The pipeline of stages that every packet passes through
+
+use core::fmt::Debug;
+use core::net::SocketAddr;
+use core::time::Duration;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rand::RngExt;
+use rand::rngs::StdRng;
+
+use crate::{Direction, ImpairmentConfig};
+
+/// A single step of the mangling pipeline, which can be implemented to add custom impairments.
+/// Stages are [configured](crate::ManglerConfig::pipeline) as [PipelineStage::Custom], in between the built-in stages.
+///
+/// The same stage is shared by the mangler threads of both directions, so any state it keeps must use
+/// interior mutability, like a [Mutex](std::sync::Mutex)
+pub trait Stage: Debug + Send + Sync {
+    /// Decides what happens to a packet, before it continues to the next stage
+    fn process(&self, packet: StagePacket, context: &mut StageContext<'_>) -> Verdict;
+}
+
+/// A packet passing through the stages of the pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagePacket {
+    /// The payload
+    pub content: Vec<u8>,
+
+    /// The client this packet was received from when travelling upstream,
+    /// or the client it is destined for when travelling downstream
+    pub client: SocketAddr,
+
+    /// The moment the packet arrived at the mangler
+    pub arrival: Instant,
+
+    /// The moment the packet left the rate limiter of the link. Until the [link stage](PipelineStage::Link),
+    /// this is the moment the packet arrived at the stage
+    pub departure: Instant,
+
+    /// The moment the packet will be sent. This starts at the arrival, and grows with every delay
+    pub send_at: Instant,
+
+    /// The number of later packets that must overtake this packet once it is due
+    pub hold_positions: usize,
+}
+
+/// What happens to a packet after a [Stage]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The packet continues to the next stage
+    Pass(StagePacket),
+
+    /// The packet is delayed by the given duration, and continues to the next stage
+    Delay(StagePacket, Duration),
+
+    /// The packet is dropped
+    Drop,
+
+    /// All the given packets continue to the next stage, for example to duplicate a packet.
+    /// An empty list drops the packet
+    Emit(Vec<StagePacket>),
+}
+
+impl Verdict {
+    /// Returns the packets continuing to the next stage
+    pub(crate) fn into_packets(self) -> Vec<StagePacket> {
+        match self {
+            Self::Pass(packet) => vec![packet],
+            Self::Delay(mut packet, delay) => {
+                packet.send_at += delay;
+                vec![packet]
+            }
+            Self::Drop => Vec::new(),
+            Self::Emit(packets) => packets,
+        }
+    }
+}
+
+/// Information that a [Stage] can use to make its decisions
+#[derive(Debug)]
+pub struct StageContext<'a> {
+    /// The direction the packet is travelling in
+    pub(crate) direction: Direction,
+
+    /// The moment the packet is processed
+    pub(crate) now: Instant,
+
+    /// The moment the mangler thread started
+    pub(crate) started: Instant,
+
+    /// The impairments of the link the packet travels over
+    pub(crate) impairments: &'a ImpairmentConfig,
+
+    /// The random number generator of the mangler thread
+    pub(crate) rng: &'a mut StdRng,
+}
+
+impl StageContext<'_> {
+    /// Returns the direction the packet is travelling in
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Returns the moment the packet is processed
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Returns the time since the mangler started
+    pub fn elapsed(&self) -> Duration {
+        self.now.saturating_duration_since(self.started)
+    }
+
+    /// Returns the impairments of the link the packet travels over. These are the impairments of its
+    /// direction, or of the [rule](crate::Rule) that selected the packet
+    pub fn impairments(&self) -> &ImpairmentConfig {
+        self.impairments
+    }
+
+    /// Returns a random number between 0.0 (inclusive) and 1.0 (exclusive). The numbers are derived from
+    /// the [seed](crate::Mangler::seed), so that they are reproducible like those of the built-in impairments
+    pub fn random(&mut self) -> f64 {
+        self.rng.random()
+    }
+}
+
+/// A stage of the mangling pipeline, which is either one of the built-in impairments or a custom [Stage].
+/// The built-in stages use the [impairments](crate::ImpairmentConfig) of the direction of the packet,
+/// or of the [rule](crate::Rule) that selected it
+#[derive(Debug, Clone)]
+pub enum PipelineStage {
+    /// Drops packets above the [maximum payload size](crate::ImpairmentConfig::max_payload_size),
+    /// unless they are fragmented
+    MaxSize,
+
+    /// Sends the packet over the link, with fragmentation, the latency trace, the loss model, the rate
+    /// limiter, and the ping and jitter. Sets the departure of the packet, and delays it until it is delivered
+    Link,
+
+    /// Randomly sends extra copies of a packet
+    Duplicate,
+
+    /// Randomly flips bits in the payload
+    Corrupt,
+
+    /// Randomly reorders packets
+    Reorder,
+
+    /// A custom stage
+    Custom(Arc<dyn Stage>),
+}
+
+impl PipelineStage {
+    /// Returns the built-in stages in their default order
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::MaxSize,
+            Self::Link,
+            Self::Duplicate,
+            Self::Corrupt,
+            Self::Reorder,
+        ]
+    }
+}
+
+impl PartialEq for PipelineStage {
+    /// Built-in stages are equal to the same built-in stage, and custom stages are only equal to the same instance
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            (a, b) => core::mem::discriminant(a) == core::mem::discriminant(b),
+        }
+    }
+}
@@ -0,0 +1,621 @@
+//! Statistics on what the mangler did
+
+use core::net::SocketAddr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::Direction;
+use crate::clock::Clock;
+use crate::mangle::Fate;
+use crate::shaper::ShaperDrop;
+
+/// The number of seconds kept in the [history](ManglerStats::history)
+const HISTORY_SECS: usize = 300;
+
+/// The largest number of clients in [ManglerStats::clients]. Any further clients are counted in
+/// [ManglerStats::other_clients]
+const MAX_CLIENTS: usize = 1024;
+
+/// The number of sub-buckets per power of two in a [Histogram], which determines its precision
+const SUB_BUCKETS: u64 = 16;
+
+/// The number of bits needed for [SUB_BUCKETS]
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// Why the mangler dropped a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, derive_more::Display)]
+pub enum DropReason {
+    /// The packet did not fit in the receive buffer, so it might have been truncated
+    #[display("might be truncated")]
+    Truncated,
+
+    /// The packet was above the maximum payload size
+    #[display("above the maximum payload size")]
+    Oversize,
+
+    /// A rule drops the packet
+    #[display("dropped by rule")]
+    Rule,
+
+    /// The latency trace recorded a loss when the packet arrived
+    #[display("lost in latency trace")]
+    LatencyTrace,
+
+    /// The loss model randomly dropped the packet
+    #[display("random loss")]
+    RandomLoss,
+
+    /// The queue of the rate limiter was full
+    #[display("rate limiter queue overflow")]
+    QueueOverflow,
+
+    /// The packet exceeded the rate while policing
+    #[display("rate exceeded")]
+    OverRate,
+
+    /// A custom stage dropped the packet
+    #[display("dropped by custom stage")]
+    Stage,
+
+    /// A copy of the packet could not be sent, because the session of its client was closed or the receiver refused it
+    #[display("undeliverable")]
+    Undeliverable,
+}
+
+impl DropReason {
+    /// All reasons
+    pub const ALL: [Self; 9] = [
+        Self::Truncated,
+        Self::Oversize,
+        Self::Rule,
+        Self::LatencyTrace,
+        Self::RandomLoss,
+        Self::QueueOverflow,
+        Self::OverRate,
+        Self::Stage,
+        Self::Undeliverable,
+    ];
+
+    /// Returns a short name of the reason without spaces, for use in metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Truncated => "truncated",
+            Self::Oversize => "oversize",
+            Self::Rule => "rule",
+            Self::LatencyTrace => "latency_trace",
+            Self::RandomLoss => "random_loss",
+            Self::QueueOverflow => "queue_overflow",
+            Self::OverRate => "over_rate",
+            Self::Stage => "stage",
+            Self::Undeliverable => "undeliverable",
+        }
+    }
+}
+
+impl From<ShaperDrop> for DropReason {
+    fn from(drop: ShaperDrop) -> Self {
+        match drop {
+            ShaperDrop::QueueFull => Self::QueueOverflow,
+            ShaperDrop::OverRate => Self::OverRate,
+        }
+    }
+}
+
+/// A snapshot of the statistics of a [Mangler](crate::Mangler), returned by [Mangler::stats](crate::Mangler::stats)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ManglerStats {
+    /// The statistics of the packets from the clients to the forward target
+    pub upstream: DirectionStats,
+
+    /// The statistics of the replies from the forward target to the clients
+    pub downstream: DirectionStats,
+
+    /// The counters of every client with an open session. The counters of a client are forgotten once its
+    /// session is closed
+    pub clients: HashMap<SocketAddr, ClientStats>,
+
+    /// The combined counters of the clients that did not fit in [clients](Self::clients), because there
+    /// were already too many
+    pub other_clients: ClientStats,
+
+    /// The counters of each of the last seconds, oldest first. The last entry is the current, incomplete second
+    pub history: Vec<SecondStats>,
+}
+
+impl ManglerStats {
+    /// Returns the statistics of the packets travelling in the given direction
+    pub fn direction(&self, direction: Direction) -> &DirectionStats {
+        match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        }
+    }
+}
+
+/// The statistics of the packets travelling in a single [Direction]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirectionStats {
+    /// The number of packets and bytes
+    pub counters: Counters,
+
+    /// The number of packets currently waiting in the mangler to be sent
+    pub queue_depth: usize,
+
+    /// The delays the mangler applied to the packets, counted from their arrival
+    pub delay: Histogram,
+
+    /// How much later than scheduled the packets were actually sent. This includes the time that
+    /// [reordered](crate::ReorderMode::Positions) packets were held back
+    pub scheduling_error: Histogram,
+}
+
+/// The counters of a single client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// The packets sent by the client
+    pub upstream: Counters,
+
+    /// The replies sent to the client
+    pub downstream: Counters,
+}
+
+impl ClientStats {
+    /// Returns the mutable counters of the given direction
+    fn direction_mut(&mut self, direction: Direction) -> &mut Counters {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+}
+
+/// The counters of a single second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SecondStats {
+    /// The number of the second, counted from the start of the mangler
+    pub second: u64,
+
+    /// The packets sent by the clients during the second
+    pub upstream: Counters,
+
+    /// The replies sent to the clients during the second
+    pub downstream: Counters,
+}
+
+/// Counters of packets and bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// The number of packets that arrived
+    pub received_packets: u64,
+
+    /// The number of payload bytes that arrived
+    pub received_bytes: u64,
+
+    /// The number of packets that were sent on, including duplicates
+    pub forwarded_packets: u64,
+
+    /// The number of payload bytes that were sent on
+    pub forwarded_bytes: u64,
+
+    /// The number of packets that were dropped, including copies that could not be delivered
+    pub dropped: DropCounts,
+}
+
+/// The number of dropped packets for each [DropReason]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DropCounts {
+    /// The counts, indexed by the position of the reason in [DropReason::ALL]
+    counts: [u64; DropReason::ALL.len()],
+}
+
+impl DropCounts {
+    /// Returns the number of packets dropped for the given reason
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    /// Returns the number of packets dropped for any reason
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the number of packets dropped for every reason
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> {
+        DropReason::ALL.into_iter().zip(self.counts)
+    }
+
+    /// Counts a dropped packet
+    pub(crate) fn add(&mut self, reason: DropReason) {
+        self.counts[reason as usize] += 1;
+    }
+}
+
+/// A histogram of durations with a logarithmic bucket size, like an HDR histogram. Durations are recorded
+/// in microseconds, with a precision of about 6%
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// The number of durations in every bucket
+    counts: Vec<u64>,
+
+    /// The number of recorded durations
+    total: u64,
+
+    /// The sum of all recorded durations in microseconds
+    sum_micros: u128,
+
+    /// The longest recorded duration in microseconds
+    max_micros: u64,
+}
+
+impl Histogram {
+    /// Records a duration
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let index = bucket_index(micros);
+
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+
+        self.counts[index] += 1;
+        self.total += 1;
+        self.sum_micros += u128::from(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    /// Returns the number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the sum of all recorded durations
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(u64::try_from(self.sum_micros).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the mean of the recorded durations, or zero if there are none
+    pub fn mean(&self) -> Duration {
+        if self.total == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_micros((self.sum_micros / u128::from(self.total)) as u64)
+    }
+
+    /// Returns the longest recorded duration
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// Returns the duration below which the given fraction (between 0.0 and 1.0 inclusive) of the
+    /// recorded durations falls, or zero if there are none
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let target = (fraction.clamp(0.0, 1.0) * self.total as f64)
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+
+            if seen >= target {
+                let upper = bucket_upper_bound(index).min(self.max_micros);
+                return Duration::from_micros(upper);
+            }
+        }
+
+        Duration::ZERO
+    }
+
+    /// Returns the number of durations at or below `bound`. Durations in the bucket containing `bound`
+    /// are only counted if the whole bucket is below it, so this may be off by the precision of the histogram
+    pub fn count_at_most(&self, bound: Duration) -> u64 {
+        let micros = u64::try_from(bound.as_micros()).unwrap_or(u64::MAX);
+
+        self.counts
+            .iter()
+            .enumerate()
+            .take_while(|(index, _)| bucket_upper_bound(*index) <= micros)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Returns the upper bound of every bucket that contains any durations, with the number of durations
+    /// at or below that bound. The counts are cumulative, like the buckets of a Prometheus histogram
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .scan(0, |seen, (index, count)| {
+                *seen += count;
+                Some((index, *count, *seen))
+            })
+            .filter(|(_, count, _)| *count != 0)
+            .map(|(index, _, seen)| (Duration::from_micros(bucket_upper_bound(index)), seen))
+    }
+}
+
+/// Returns the index of the bucket containing `micros`. Values below [SUB_BUCKETS] each have their own bucket,
+/// after that every power of two is split into [SUB_BUCKETS] buckets
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+
+    let exponent = u64::BITS - 1 - micros.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (micros >> shift) - SUB_BUCKETS;
+
+    (SUB_BUCKETS * (u64::from(shift) + 1) + sub_bucket) as usize
+}
+
+/// Returns the largest value in the bucket with the given index
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let shift = index / SUB_BUCKETS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+
+    let upper = u128::from(SUB_BUCKETS + sub_bucket + 1) << shift;
+    u64::try_from(upper - 1).unwrap_or(u64::MAX)
+}
+
+/// Collects the statistics of a mangler from all its threads
+#[derive(Debug)]
+pub(crate) struct StatsCollector {
+    /// The statistics collected so far
+    stats: Mutex<Collected>,
+
+    /// The number of packets waiting in the mangler thread of each direction
+    queue_depths: [AtomicUsize; 2],
+
+    /// The clock of the mangler
+    clock: Arc<dyn Clock>,
+
+    /// The moment the mangler started, from which the seconds of the history are counted
+    started: Instant,
+}
+
+/// The statistics collected by a [StatsCollector]
+#[derive(Debug, Default)]
+struct Collected {
+    /// The statistics of both directions, without their queue depths
+    totals: ManglerStats,
+
+    /// The last seconds, oldest first
+    history: VecDeque<SecondStats>,
+}
+
+impl StatsCollector {
+    /// Creates a collector without any statistics
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            stats: Mutex::new(Collected::default()),
+            queue_depths: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            started: clock.now(),
+            clock,
+        }
+    }
+
+    /// Counts a packet of `client` that arrived, with what the mangler did to it
+    pub(crate) fn received(
+        &self,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        fate: &Fate,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        let size = size as u64;
+
+        let totals = match direction {
+            Direction::Upstream => &mut stats.totals.upstream,
+            Direction::Downstream => &mut stats.totals.downstream,
+        };
+
+        for delay in &fate.delays {
+            totals.delay.record(*delay);
+        }
+
+        stats.update_counters(client, direction, self.second(), |counters| {
+            counters.received_packets += 1;
+            counters.received_bytes += size;
+
+            if fate.delays.is_empty()
+                && let Some(reason) = fate.dropped
+            {
+                counters.dropped.add(reason);
+            }
+        });
+    }
+
+    /// Counts a packet that was sent `late` after it was scheduled
+    pub(crate) fn sent(
+        &self,
+        size: usize,
+        client: SocketAddr,
+        direction: Direction,
+        late: Duration,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        let size = size as u64;
+
+        let totals = match direction {
+            Direction::Upstream => &mut stats.totals.upstream,
+            Direction::Downstream => &mut stats.totals.downstream,
+        };
+
+        totals.scheduling_error.record(late);
+
+        stats.update_counters(client, direction, self.second(), |counters| {
+            counters.forwarded_packets += 1;
+            counters.forwarded_bytes += size;
+        });
+    }
+
+    /// Counts a copy of a packet of `client` that could not be sent
+    pub(crate) fn undelivered(&self, client: SocketAddr, direction: Direction, reason: DropReason) {
+        let mut stats = self.stats.lock().unwrap();
+
+        stats.update_counters(client, direction, self.second(), |counters| {
+            counters.dropped.add(reason);
+        });
+    }
+
+    /// Forgets the counters of `client`, whose session was closed
+    pub(crate) fn session_closed(&self, client: SocketAddr) {
+        self.stats.lock().unwrap().totals.clients.remove(&client);
+    }
+
+    /// Sets the number of packets waiting in the mangler thread of `direction`
+    pub(crate) fn set_queue_depth(&self, direction: Direction, depth: usize) {
+        self.queue_depths[direction as usize].store(depth, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the statistics
+    pub(crate) fn snapshot(&self) -> ManglerStats {
+        let mut stats = self.stats.lock().unwrap();
+        let second = self.second();
+
+        // Make sure the history runs up to now, even if no packets arrived recently
+        stats.second_mut(second);
+
+        let mut snapshot = stats.totals.clone();
+        snapshot.history = stats.history.iter().copied().collect();
+        snapshot.upstream.queue_depth =
+            self.queue_depths[Direction::Upstream as usize].load(Ordering::Relaxed);
+        snapshot.downstream.queue_depth =
+            self.queue_depths[Direction::Downstream as usize].load(Ordering::Relaxed);
+
+        snapshot
+    }
+
+    /// Returns the number of the current second since the start of the mangler
+    fn second(&self) -> u64 {
+        self.clock
+            .now()
+            .saturating_duration_since(self.started)
+            .as_secs()
+    }
+}
+
+impl Collected {
+    /// Applies `update` to the totals of `direction`, the counters of `client`, and the counters of `second`.
+    /// If there are too many clients already, the combined counters of the other clients are updated instead
+    fn update_counters(
+        &mut self,
+        client: SocketAddr,
+        direction: Direction,
+        second: u64,
+        update: impl Fn(&mut Counters),
+    ) {
+        let totals = match direction {
+            Direction::Upstream => &mut self.totals.upstream,
+            Direction::Downstream => &mut self.totals.downstream,
+        };
+
+        update(&mut totals.counters);
+
+        let clients = &mut self.totals.clients;
+        let client_stats = if clients.len() < MAX_CLIENTS || clients.contains_key(&client) {
+            clients.entry(client).or_default()
+        } else {
+            &mut self.totals.other_clients
+        };
+
+        update(client_stats.direction_mut(direction));
+
+        let current = self.second_mut(second);
+        update(match direction {
+            Direction::Upstream => &mut current.upstream,
+            Direction::Downstream => &mut current.downstream,
+        });
+    }
+
+    /// Returns the counters of `second`, adding empty seconds to the history up to it
+    fn second_mut(&mut self, second: u64) -> &mut SecondStats {
+        let next = self.history.back().map_or(second, |last| last.second + 1);
+
+        // Seconds that are older than the history would be dropped right away
+        for second in next.max(second.saturating_sub(HISTORY_SECS as u64 - 1))..=second {
+            self.history.push_back(SecondStats {
+                second,
+                ..SecondStats::default()
+            });
+        }
+
+        while self.history.len() > HISTORY_SECS {
+            self.history.pop_front();
+        }
+
+        self.history
+            .back_mut()
+            .expect("The history always contains the current second")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, SocketAddr};
+    use core::time::Duration;
+    use std::sync::Arc;
+
+    use super::{MAX_CLIENTS, StatsCollector};
+    use crate::Direction;
+    use crate::clock::ManualClock;
+
+    /// Returns the address of the client with the given number
+    fn client(number: usize) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 1000 + number as u16))
+    }
+
+    /// The counters of a client are forgotten when its session closes, and clients beyond the maximum
+    /// are counted together
+    #[test]
+    fn clients() {
+        let stats = StatsCollector::new(Arc::new(ManualClock::new()));
+
+        for number in 0..=MAX_CLIENTS {
+            stats.sent(10, client(number), Direction::Upstream, Duration::ZERO);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.clients.len(),
+            MAX_CLIENTS,
+            "The clients are capped"
+        );
+        assert_eq!(
+            snapshot.other_clients.upstream.forwarded_packets, 1,
+            "The client beyond the maximum is counted with the others"
+        );
+        assert_eq!(
+            snapshot.upstream.counters.forwarded_packets,
+            MAX_CLIENTS as u64 + 1,
+            "The totals count every client"
+        );
+
+        stats.session_closed(client(0));
+        stats.sent(10, client(MAX_CLIENTS), Direction::Upstream, Duration::ZERO);
+
+        let snapshot = stats.snapshot();
+        assert!(
+            !snapshot.clients.contains_key(&client(0)),
+            "The client with a closed session is forgotten"
+        );
+        assert_eq!(
+            snapshot.clients[&client(MAX_CLIENTS)]
+                .upstream
+                .forwarded_packets,
+            1,
+            "A new client takes the freed place"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Recording of the traffic of the clients, and replay of it from the mangler itself
+
+use core::net::SocketAddr;
+use core::time::Duration;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+
+use crate::clock::Clock;
+use crate::mangle::Fate;
+use crate::observe::Observers;
+use crate::pcap::{PcapErr, PcapReader, PcapWriter};
+use crate::playback::PlaybackControl;
+use crate::session::SessionTable;
+use crate::sink::FileSink;
+use crate::stats::DropReason;
+use crate::{Direction, ManglerConfig, Packet};
+
+/// A single packet of a [TrafficRecording]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedPacket {
+    /// When the packet arrived, counted from the first packet of the recording
+    pub offset: Duration,
+
+    /// The client that sent the packet
+    pub client: SocketAddr,
+
+    /// The UDP payload
+    pub payload: Vec<u8>,
+}
+
+/// The packets that clients sent to the mangler, with their timing, recorded by setting
+/// [ManglerConfig::record_traffic](crate::ManglerConfig::record_traffic). Replay it with
+/// [Mangler::replay_traffic](crate::Mangler::replay_traffic).
+///
+/// A recording is a classic pcap file, which tools like Wireshark can open. Any pcap file of UDP packets can be
+/// replayed, in which case every packet is taken as sent by a client from its source address. Packets that are not
+/// unfragmented UDP packets are skipped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficRecording {
+    /// The packets, in the order they arrived in. Shared, so that the recording is cheap to clone
+    packets: Arc<Vec<RecordedPacket>>,
+}
+
+/// Error while starting a replay with [Mangler::replay_traffic](crate::Mangler::replay_traffic)
+#[derive(Debug, Clone, Copy, PartialEq, derive_more::Display, derive_more::Error)]
+pub enum ReplayTrafficErr {
+    /// The time scale is negative or not finite, or stretches the recording beyond what a duration can hold
+    #[display("Invalid replay time scale: {}", _0)]
+    InvalidTimeScale(#[error(not(source))] f64),
+}
+
+impl TrafficRecording {
+    /// Reads a recording from a pcap file
+    pub fn read(reader: impl Read) -> Result<Self, PcapErr> {
+        let mut reader = PcapReader::new(BufReader::new(reader))?;
+        let mut packets = Vec::new();
+        let mut first = None;
+
+        while let Some(record) = reader.next_record()? {
+            let Some(udp) = record.udp else {
+                continue;
+            };
+
+            let first = *first.get_or_insert(record.timestamp);
+
+            packets.push(RecordedPacket {
+                offset: record.timestamp.saturating_sub(first),
+                client: udp.src,
+                payload: udp.payload,
+            });
+        }
+
+        // Packets that are out of order in the file are sent right after the packet before them
+        for i in 1..packets.len() {
+            packets[i].offset = packets[i].offset.max(packets[i - 1].offset);
+        }
+
+        Ok(Self {
+            packets: Arc::new(packets),
+        })
+    }
+
+    /// Returns the packets, in the order they arrived in
+    pub fn packets(&self) -> &[RecordedPacket] {
+        &self.packets
+    }
+
+    /// Returns the time from the first to the last packet
+    pub fn duration(&self) -> Duration {
+        self.packets
+            .last()
+            .map_or(Duration::ZERO, |last| last.offset)
+    }
+}
+
+/// Writes a [TrafficRecording] while the mangler is running
+#[derive(Debug)]
+pub(crate) struct TrafficRecorder {
+    /// The file being written
+    writer: FileSink<PcapWriter<BufWriter<File>>>,
+
+    /// The address the mangler listens on, which is the destination of every packet
+    listen: SocketAddr,
+
+    /// A moment in both system time and monotonic time, to convert between the two
+    epoch: (SystemTime, Instant),
+}
+
+impl TrafficRecorder {
+    /// Creates the recording file, for a mangler listening on `listen`
+    pub(crate) fn create(path: &Path, listen: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: FileSink::create(path, "traffic recording", PcapWriter::new)?,
+            listen,
+            epoch: (SystemTime::now(), Instant::now()),
+        })
+    }
+
+    /// Records a packet that `client` sent to the mangler, which arrived at `at`
+    pub(crate) fn arrived(&self, content: &[u8], client: SocketAddr, at: Instant) {
+        let time = self.epoch.0 + at.saturating_duration_since(self.epoch.1)
+            - self.epoch.1.saturating_duration_since(at);
+        let timestamp = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        self.writer
+            .write(|writer| writer.write(timestamp, client, self.listen, content));
+    }
+
+    /// Writes everything that is buffered to the file
+    pub(crate) fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// The main function for the traffic replay thread. It sends the packets of a recording to the upstream
+/// [mangler thread](crate::mangle::mangle_main) with their recorded timing multiplied by `time_scale`,
+/// as if the clients sent them again. The replies of the forward target to those clients are discarded
+#[allow(clippy::too_many_arguments, reason = "Thread entry point")]
+pub(crate) fn replay_main(
+    config: Arc<ArcSwap<ManglerConfig>>,
+    clock: Arc<dyn Clock>,
+    recording: TrafficRecording,
+    time_scale: f64,
+    sessions: Arc<SessionTable>,
+    to_upstream_mangler: Sender<Packet>,
+    to_downstream_mangler: Sender<Packet>,
+    observers: Arc<Observers>,
+    control: PlaybackControl,
+) {
+    let started = control.now();
+
+    log::info!(
+        "Replaying {} packets over {:?}",
+        recording.packets().len(),
+        recording.duration().mul_f64(time_scale)
+    );
+
+    for recorded in recording.packets() {
+        if control.sleep_until(started + recorded.offset.mul_f64(time_scale)) {
+            log::debug!("Traffic replay stopped");
+            return;
+        }
+
+        let config_now = config.load();
+        let now = clock.now();
+
+        if recorded.payload.len() >= config_now.buffer_size {
+            // The listener would not have received the whole packet
+            observers.received(
+                None,
+                &recorded.payload,
+                recorded.client,
+                Direction::Upstream,
+                now,
+                &Fate::dropped(DropReason::Truncated),
+            );
+            continue;
+        }
+
+        let flow = match sessions.get_or_open(
+            recorded.client,
+            true,
+            &config,
+            &to_downstream_mangler,
+            control.quit(),
+        ) {
+            Ok(session) => {
+                session.touch(now);
+                session.flow()
+            }
+            Err(e) => {
+                log::warn!(
+                    "Dropping replayed packet, could not open session for {}: {e}",
+                    recorded.client
+                );
+                continue;
+            }
+        };
+
+        let packet = Packet {
+            send_timestamp: now,
+            client: recorded.client,
+            id: 0,
+            flow,
+            replayed: true,
+            flow_index: 0,
+            seq: 0,
+            hold_positions: 0,
+            content: recorded.payload.clone(),
+        };
+
+        if to_upstream_mangler.send(packet).is_err() {
+            log::debug!("Traffic replay returning because the to_mangler channel has closed");
+            return;
+        }
+    }
+
+    log::info!("Finished replaying traffic");
+}
@@ -1,21 +1,33 @@
 //! Command line arguments and conversion
 
 use core::net::SocketAddr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use clap::Parser;
-use udp_mangler::ManglerConfig;
+use clap::{Parser, Subcommand, ValueEnum};
+use udp_mangler::{
+    DecisionLogConfig, DecisionLogFormat, DecisionRecording, DeliveryTrace, EmpiricalDistribution,
+    GilbertElliott, ImpairmentConfig, JitterDistribution, LatencyTrace, LossModel, ManglerConfig,
+    NetworkModel, OversizeMode, PipelineStage, RateMode, ReorderMode, Rule, RuleAction, Scenario,
+    Script, TrafficRecording,
+};
 
 /// Args for the binary
 #[derive(Debug, Clone, Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 pub(crate) struct Args {
+    /// Mangle the packets of a pcap file instead of listening on a socket
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
     /// The address on which the mangle server will listen for incoming UDP packets
-    #[arg(short, long)]
-    pub(crate) input: SocketAddr,
+    #[arg(short, long, required = true)]
+    pub(crate) input: Option<SocketAddr>,
 
     /// The adress to which any UDP packets will be forwarded to, after mangling
-    #[arg(short, long)]
-    pub(crate) output: SocketAddr,
+    #[arg(short, long, required = true)]
+    pub(crate) output: Option<SocketAddr>,
 
     /// The log level used
     #[arg(short, long, default_value_t = if cfg!(debug_assertions) { simplelog::LevelFilter::Debug } else { simplelog::LevelFilter::Info })]
@@ -25,21 +37,274 @@ pub(crate) struct Args {
     #[arg(long, default_value_t = udp_mangler::ManglerConfig::default().buffer_size)]
     pub(crate) input_buffer_size: usize,
 
-    /// The maximum size of the incoming packet payload before the mangler either drops or fragments them
-    #[arg(long, default_value_t = udp_mangler::ManglerConfig::default().max_payload_size)]
+    /// The number of seconds without traffic after which a client session is closed
+    #[arg(long, default_value_t = udp_mangler::ManglerConfig::default().session_timeout_secs)]
+    pub(crate) session_timeout: f64,
+
+    /// The seed for all random decisions. Reusing the seed of an earlier run reproduces its decisions,
+    /// if the packets arrive in the same order. If not given, a random seed is used and logged
+    #[arg(long)]
+    pub(crate) seed: Option<u64>,
+
+    /// A scenario file with a timeline of changes to the impairments, relative to the other options.
+    /// See the library documentation of `Scenario::parse` for the format
+    #[arg(long)]
+    pub(crate) scenario: Option<PathBuf>,
+
+    /// A network model file, with network states the link randomly moves between. The states are relative
+    /// to the other options. See the library documentation of `NetworkModel::parse` for the format
+    #[arg(long, conflicts_with = "scenario")]
+    pub(crate) network_model: Option<PathBuf>,
+
+    /// A rules file, giving packets selected by address, port, size or payload their own impairments.
+    /// The rules are relative to the other options. See the library documentation of `Rule::parse_list` for the format
+    #[arg(long)]
+    pub(crate) rules: Option<PathBuf>,
+
+    /// A Rhai script that decides what happens to each packet, right after the maximum size check.
+    /// See the library documentation of `Script` for the format
+    #[arg(long)]
+    pub(crate) script: Option<PathBuf>,
+
+    /// A pcapng file to write every packet entering and leaving the mangler to. Every packet entering
+    /// the mangler gets a comment on what happened to it, which Wireshark shows
+    #[arg(long)]
+    pub(crate) capture: Option<PathBuf>,
+
+    /// A file to write a record of the decisions on every packet to, for offline analysis.
+    /// See the library documentation of `DecisionLogFormat` for the fields
+    #[arg(long)]
+    pub(crate) decision_log: Option<PathBuf>,
+
+    /// The format of the decision log
+    #[arg(long, value_enum, default_value_t = LogFormat::Csv, requires = "decision_log")]
+    pub(crate) decision_log_format: LogFormat,
+
+    /// A file to record the decision for every packet to, so that it can be replayed with `--replay-decisions`
+    #[arg(long)]
+    pub(crate) record_decisions: Option<PathBuf>,
+
+    /// A decision recording of an earlier run. Every packet gets the recorded decision for its position in its flow,
+    /// regardless of its timing. See the library documentation of `DecisionRecording` for the format
+    #[arg(long)]
+    pub(crate) replay_decisions: Option<PathBuf>,
+
+    /// A pcap file to record the packets the clients send to the mangler to, with their timing, so that they can be
+    /// replayed with `--replay-traffic`
+    #[arg(long)]
+    pub(crate) record_traffic: Option<PathBuf>,
+
+    /// A pcap file of UDP packets to replay into the output address, with their recorded timing, as if the clients
+    /// sent them again. Replies to the replayed packets are discarded
+    #[arg(long)]
+    pub(crate) replay_traffic: Option<PathBuf>,
+
+    /// The factor the timing of the replayed traffic is multiplied with. 0.5 replays twice as fast
+    #[arg(long, default_value_t = 1.0, requires = "replay_traffic")]
+    pub(crate) replay_time_scale: f64,
+
+    /// The address to serve Prometheus metrics on, over HTTP. The metrics include the packet counters,
+    /// drop reasons, queue depths, delay histograms and the current impairment settings
+    #[arg(long)]
+    pub(crate) metrics_listen: Option<SocketAddr>,
+
+    /// The maximum size of the incoming packet payload before the mangler either drops or fragments them, in both directions
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().max_payload_size)]
     pub(crate) max_payload_size: usize,
 
-    /// The factor of packets that are randomly dropped by the mangler
-    #[arg(long, default_value_t = udp_mangler::ManglerConfig::default().loss_factor)]
+    /// Fragment packets above the maximum payload size instead of dropping them. A fragmented packet is only
+    /// delivered if all its fragments survive
+    #[arg(long)]
+    pub(crate) fragment: bool,
+
+    /// The factor of packets that are randomly dropped by the mangler, in both directions
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().loss.mean_loss())]
     pub(crate) loss_factor: f64,
 
-    /// Additional ping to add, in milliseconds
+    /// Drop packets in bursts using a Gilbert-Elliott model instead of the uniform `--loss-factor`,
+    /// in both directions. Given as `GOOD_TO_BAD,BAD_TO_GOOD,LOSS_GOOD,LOSS_BAD` probabilities
+    #[arg(long, value_parser = parse_gilbert_elliott, conflicts_with = "loss_factor")]
+    pub(crate) burst_loss: Option<GilbertElliott>,
+
+    /// Additional ping to add to both directions, in milliseconds
     #[arg(long, default_value_t = 0)]
     pub(crate) ping: usize,
 
-    /// Additional jitter to add, in milliseconds
+    /// Additional jitter to add to both directions, in milliseconds
     #[arg(long, default_value_t = 0)]
     pub(crate) jitter: usize,
+
+    /// The distribution the jitter is sampled from
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    pub(crate) jitter_distribution: Distribution,
+
+    /// The shape of the Pareto distributions. Smaller values give heavier tails
+    #[arg(long, default_value_t = 3.0)]
+    pub(crate) pareto_shape: f64,
+
+    /// Sample the jitter from a measured cumulative distribution instead. Each line of the file holds
+    /// a jitter in milliseconds and its cumulative probability
+    #[arg(long, conflicts_with_all = ["jitter_distribution", "jitter_histogram"])]
+    pub(crate) jitter_cdf: Option<PathBuf>,
+
+    /// Sample the jitter from a measured histogram instead. Each line of the file holds
+    /// a jitter in milliseconds and its weight
+    #[arg(long, conflicts_with = "jitter_distribution")]
+    pub(crate) jitter_histogram: Option<PathBuf>,
+
+    /// Never let jitter reorder the packets of a single client
+    #[arg(long)]
+    pub(crate) preserve_order: bool,
+
+    /// Replay a recorded latency trace in both directions, instead of the ping and jitter. Each line of the CSV
+    /// file holds a timestamp in seconds, a delay in milliseconds, and optionally a lost flag
+    #[arg(long)]
+    pub(crate) latency_trace: Option<PathBuf>,
+
+    /// Start the latency trace over when it ends, instead of returning to the ping and jitter
+    #[arg(long, requires = "latency_trace")]
+    pub(crate) latency_trace_loop: bool,
+
+    /// The delays in the latency trace are round trip times, which are split evenly over both directions
+    #[arg(long, requires = "latency_trace")]
+    pub(crate) latency_trace_rtt: bool,
+
+    /// The factor of packets that are duplicated by the mangler, in both directions
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().duplicate_factor)]
+    pub(crate) duplicate_factor: f64,
+
+    /// The number of extra copies sent of each duplicated packet
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().duplicate_copies)]
+    pub(crate) duplicate_copies: usize,
+
+    /// Additional delay of each extra copy of a duplicated packet, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    pub(crate) duplicate_delay: usize,
+
+    /// The factor of packets that are explicitly reordered by the mangler, in both directions
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().reorder_factor)]
+    pub(crate) reorder_factor: f64,
+
+    /// Reordered packets are held back until this many later packets have overtaken them
+    #[arg(long, default_value_t = 1, conflicts_with = "reorder_delay")]
+    pub(crate) reorder_positions: usize,
+
+    /// Reordered packets are held back by this additional delay instead, in milliseconds
+    #[arg(long)]
+    pub(crate) reorder_delay: Option<usize>,
+
+    /// The probability of each payload bit being flipped, in both directions
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().bit_error_rate)]
+    pub(crate) bit_error_rate: f64,
+
+    /// The first payload byte that may be corrupted by bit errors
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().corrupt_offset)]
+    pub(crate) corrupt_offset: usize,
+
+    /// The number of payload bytes that may be corrupted by bit errors. Defaults to the rest of the payload
+    #[arg(long)]
+    pub(crate) corrupt_length: Option<usize>,
+
+    /// The number of consecutive bits flipped by each bit error
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().corrupt_burst_bits)]
+    pub(crate) corrupt_burst_bits: usize,
+
+    /// The capacity of the link in kilobits per second, in both directions. Unlimited by default
+    #[arg(long)]
+    pub(crate) rate: Option<f64>,
+
+    /// The number of bytes that can be sent in a burst without serialization delay, after the link has been idle
+    #[arg(long, default_value_t = udp_mangler::ImpairmentConfig::default().rate_burst_bytes)]
+    pub(crate) rate_burst: usize,
+
+    /// A Mahimahi trace of delivery opportunities that sets the capacity of the link over time,
+    /// in both directions. Every line is the moment in milliseconds at which 1504 bytes may leave the link
+    #[arg(long, conflicts_with_all = ["rate", "rate_burst"])]
+    pub(crate) delivery_trace: Option<PathBuf>,
+
+    /// The maximum number of packets per second, in both directions. Unlimited by default
+    #[arg(long)]
+    pub(crate) rate_pps: Option<f64>,
+
+    /// The maximum number of bytes waiting for the link before packets are dropped. Unbounded by default
+    #[arg(long)]
+    pub(crate) rate_queue_limit: Option<usize>,
+
+    /// Drop packets exceeding the rate right away, instead of queueing them. Needs a `--rate-burst` with `--rate`
+    #[arg(long)]
+    pub(crate) police: bool,
+
+    /// Overrides the maximum payload size for replies. Defaults to `--max-payload-size`
+    #[arg(long)]
+    pub(crate) downstream_max_payload_size: Option<usize>,
+
+    /// Overrides the loss factor for replies. Defaults to `--loss-factor`
+    #[arg(long)]
+    pub(crate) downstream_loss_factor: Option<f64>,
+
+    /// Overrides the burst loss model for replies. Defaults to `--burst-loss`
+    #[arg(long, value_parser = parse_gilbert_elliott, conflicts_with = "downstream_loss_factor")]
+    pub(crate) downstream_burst_loss: Option<GilbertElliott>,
+
+    /// Overrides the additional ping for replies, in milliseconds. Defaults to `--ping`
+    #[arg(long)]
+    pub(crate) downstream_ping: Option<usize>,
+
+    /// Overrides the additional jitter for replies, in milliseconds. Defaults to `--jitter`
+    #[arg(long)]
+    pub(crate) downstream_jitter: Option<usize>,
+
+    /// Overrides the delivery trace for replies. Defaults to `--delivery-trace`
+    #[arg(long)]
+    pub(crate) downstream_delivery_trace: Option<PathBuf>,
+}
+
+/// Modes of the binary other than running the mangler on sockets
+#[derive(Debug, Clone, Subcommand)]
+pub(crate) enum Command {
+    /// Mangle the UDP packets of a pcap file with the impairments of the other options, which go before the
+    /// subcommand, and write the result to another pcap file. The timestamps of the capture are used as the clock,
+    /// so nothing is waited for
+    Offline {
+        /// The pcap file to read. pcapng files are not supported
+        input: PathBuf,
+
+        /// The pcap file to write, which is replaced if it exists
+        output: PathBuf,
+
+        /// The address of the server. Packets sent from it travel downstream, all others upstream
+        #[arg(long)]
+        server: Option<SocketAddr>,
+    },
+}
+
+/// The jitter distributions selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Distribution {
+    /// Uniform between zero and the jitter
+    Uniform,
+
+    /// Normal around the ping, with the jitter as standard deviation
+    Normal,
+
+    /// Pareto with the jitter as mean
+    Pareto,
+
+    /// Mix of normal and Pareto
+    ParetoNormal,
+
+    /// Exponential with the jitter as mean
+    Exponential,
+}
+
+/// The format of the decision log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Comma separated values, with a header line
+    Csv,
+
+    /// A JSON object on every line
+    Ndjson,
 }
 
 impl Args {
@@ -50,22 +315,386 @@ impl Args {
             return Err(());
         }
 
-        if self.max_payload_size == 0 {
-            eprintln!("Invalid max payload size: {}", self.max_payload_size);
+        if !self.session_timeout.is_finite() || self.session_timeout <= 0.0 {
+            eprintln!("Invalid session timeout: {}", self.session_timeout);
             return Err(());
         }
 
-        if !(0.0..=1.0).contains(&self.loss_factor) {
-            eprintln!("Invalid loss factor: {}", self.loss_factor);
-            return Err(());
-        }
+        let jitter_distribution = self.jitter_distribution()?;
+        let latency_trace = self.latency_trace()?;
 
-        Ok(ManglerConfig {
-            buffer_size: self.input_buffer_size,
+        let upstream = ImpairmentConfig {
             max_payload_size: self.max_payload_size,
-            loss_factor: self.loss_factor,
+            oversize_mode: if self.fragment {
+                OversizeMode::Fragment
+            } else {
+                OversizeMode::Drop
+            },
+            loss: match &self.burst_loss {
+                Some(ge) => LossModel::GilbertElliott(ge.clone()),
+                None => LossModel::Uniform(self.loss_factor),
+            },
             ping_secs: (self.ping as f64) / 1000.0,
             jitter_secs: (self.jitter as f64) / 1000.0,
-        })
+            jitter_distribution,
+            preserve_order: self.preserve_order,
+            latency_trace,
+            duplicate_factor: self.duplicate_factor,
+            duplicate_copies: self.duplicate_copies,
+            duplicate_delay_secs: (self.duplicate_delay as f64) / 1000.0,
+            reorder_factor: self.reorder_factor,
+            reorder_mode: match self.reorder_delay {
+                Some(delay) => ReorderMode::DelaySecs((delay as f64) / 1000.0),
+                None => ReorderMode::Positions(self.reorder_positions),
+            },
+            bit_error_rate: self.bit_error_rate,
+            corrupt_offset: self.corrupt_offset,
+            corrupt_length: self.corrupt_length,
+            corrupt_burst_bits: self.corrupt_burst_bits,
+            rate_bits_per_sec: self.rate.map(|rate| rate * 1000.0),
+            rate_burst_bytes: self.rate_burst,
+            delivery_trace: self
+                .delivery_trace
+                .as_deref()
+                .map(read_delivery_trace)
+                .transpose()?,
+            rate_packets_per_sec: self.rate_pps,
+            rate_queue_limit_bytes: self.rate_queue_limit,
+            rate_mode: if self.police {
+                RateMode::Police
+            } else {
+                RateMode::Shape
+            },
+        };
+
+        let downstream = self.downstream(&upstream)?;
+
+        validate_impairments(&upstream)?;
+        validate_impairments(&downstream)?;
+
+        let mut config = ManglerConfig {
+            buffer_size: self.input_buffer_size,
+            session_timeout_secs: self.session_timeout,
+            upstream,
+            downstream,
+            seed: self.seed,
+            rules: Vec::new(),
+            capture: self.capture.clone(),
+            decision_log: self.decision_log.clone().map(|path| DecisionLogConfig {
+                path,
+                format: match self.decision_log_format {
+                    LogFormat::Csv => DecisionLogFormat::Csv,
+                    LogFormat::Ndjson => DecisionLogFormat::Ndjson,
+                },
+            }),
+            record_decisions: self.record_decisions.clone(),
+            replay_decisions: self.replay_decisions()?,
+            record_traffic: self.record_traffic.clone(),
+            pipeline: PipelineStage::defaults(),
+        };
+
+        config.rules = self.rules(&config)?;
+
+        if let Some(script) = self.script()? {
+            // Right after the maximum size check, so that the script sees the packets as they arrived
+            config
+                .pipeline
+                .insert(1, PipelineStage::Custom(Arc::new(script)));
+        }
+
+        Ok(config)
     }
+
+    /// Returns the downstream impairments, which are the `upstream` impairments with the downstream overrides
+    fn downstream(&self, upstream: &ImpairmentConfig) -> Result<ImpairmentConfig, ()> {
+        let mut downstream = upstream.clone();
+
+        if let Some(max_payload_size) = self.downstream_max_payload_size {
+            downstream.max_payload_size = max_payload_size;
+        }
+
+        if let Some(loss_factor) = self.downstream_loss_factor {
+            downstream.loss = LossModel::Uniform(loss_factor);
+        }
+
+        if let Some(ge) = &self.downstream_burst_loss {
+            downstream.loss = LossModel::GilbertElliott(ge.clone());
+        }
+
+        if let Some(ping) = self.downstream_ping {
+            downstream.ping_secs = (ping as f64) / 1000.0;
+        }
+
+        if let Some(jitter) = self.downstream_jitter {
+            downstream.jitter_secs = (jitter as f64) / 1000.0;
+        }
+
+        if let Some(path) = &self.downstream_delivery_trace {
+            downstream.delivery_trace = Some(read_delivery_trace(path)?);
+        }
+
+        Ok(downstream)
+    }
+
+    /// Reads the scenario file, if one was given
+    pub(crate) fn scenario(&self) -> Result<Option<Scenario>, ()> {
+        let Some(path) = &self.scenario else {
+            return Ok(None);
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read scenario {}: {e}", path.display());
+        })?;
+
+        let scenario = Scenario::parse(&text).map_err(|e| {
+            eprintln!("Invalid scenario {}: {e}", path.display());
+        })?;
+
+        Ok(Some(scenario))
+    }
+
+    /// Reads the network model file, if one was given. The states are relative to `baseline`
+    pub(crate) fn network_model(
+        &self,
+        baseline: &ManglerConfig,
+    ) -> Result<Option<NetworkModel>, ()> {
+        let Some(path) = &self.network_model else {
+            return Ok(None);
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read network model {}: {e}", path.display());
+        })?;
+
+        let model = NetworkModel::parse(&text, baseline).map_err(|e| {
+            eprintln!("Invalid network model {}: {e}", path.display());
+        })?;
+
+        Ok(Some(model))
+    }
+
+    /// Reads the rules file, if one was given. The rules are relative to `baseline`
+    fn rules(&self, baseline: &ManglerConfig) -> Result<Vec<Rule>, ()> {
+        let Some(path) = &self.rules else {
+            return Ok(Vec::new());
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read rules {}: {e}", path.display());
+        })?;
+
+        let rules = Rule::parse_list(&text, baseline).map_err(|e| {
+            eprintln!("Invalid rules {}: {e}", path.display());
+        })?;
+
+        for rule in &rules {
+            if let RuleAction::Impair(config) = &rule.action {
+                validate_impairments(config)?;
+            }
+        }
+
+        Ok(rules)
+    }
+
+    /// Reads the decision recording to replay, if one was given
+    fn replay_decisions(&self) -> Result<Option<DecisionRecording>, ()> {
+        let Some(path) = &self.replay_decisions else {
+            return Ok(None);
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read decision recording {}: {e}", path.display());
+        })?;
+
+        let recording = DecisionRecording::parse(&text).map_err(|e| {
+            eprintln!("Invalid decision recording {}: {e}", path.display());
+        })?;
+
+        Ok(Some(recording))
+    }
+
+    /// Reads the traffic recording to replay, if one was given
+    pub(crate) fn replay_traffic(&self) -> Result<Option<TrafficRecording>, ()> {
+        let Some(path) = &self.replay_traffic else {
+            return Ok(None);
+        };
+
+        let file = File::open(path).map_err(|e| {
+            eprintln!("Could not read traffic recording {}: {e}", path.display());
+        })?;
+
+        let recording = TrafficRecording::read(file).map_err(|e| {
+            eprintln!("Invalid traffic recording {}: {e}", path.display());
+        })?;
+
+        Ok(Some(recording))
+    }
+
+    /// Reads and compiles the script, if one was given
+    fn script(&self) -> Result<Option<Script>, ()> {
+        let Some(path) = &self.script else {
+            return Ok(None);
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read script {}: {e}", path.display());
+        })?;
+
+        let script = Script::compile(&text).map_err(|e| {
+            eprintln!("Invalid script {}: {e}", path.display());
+        })?;
+
+        Ok(Some(script))
+    }
+
+    /// Reads the latency trace, if one was given
+    fn latency_trace(&self) -> Result<Option<LatencyTrace>, ()> {
+        let Some(path) = &self.latency_trace else {
+            return Ok(None);
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read latency trace {}: {e}", path.display());
+        })?;
+
+        let mut trace = LatencyTrace::parse_csv(&text, self.latency_trace_loop).map_err(|e| {
+            eprintln!("Invalid latency trace {}: {e}", path.display());
+        })?;
+
+        if self.latency_trace_rtt {
+            trace.scale_delays(0.5).map_err(|e| {
+                eprintln!("Invalid latency trace {}: {e}", path.display());
+            })?;
+        }
+
+        Ok(Some(trace))
+    }
+
+    /// Returns the selected jitter distribution, reading it from a file if needed
+    fn jitter_distribution(&self) -> Result<JitterDistribution, ()> {
+        let (path, parse): (_, fn(&str) -> _) = match (&self.jitter_cdf, &self.jitter_histogram) {
+            (Some(path), _) => (path, EmpiricalDistribution::parse_cdf),
+            (None, Some(path)) => (path, EmpiricalDistribution::parse_histogram),
+            (None, None) => {
+                if self.pareto_shape.is_nan() || self.pareto_shape <= 1.0 {
+                    eprintln!("Invalid Pareto shape: {}", self.pareto_shape);
+                    return Err(());
+                }
+
+                return Ok(match self.jitter_distribution {
+                    Distribution::Uniform => JitterDistribution::Uniform,
+                    Distribution::Normal => JitterDistribution::Normal,
+                    Distribution::Pareto => JitterDistribution::Pareto {
+                        shape: self.pareto_shape,
+                    },
+                    Distribution::ParetoNormal => JitterDistribution::ParetoNormal {
+                        shape: self.pareto_shape,
+                    },
+                    Distribution::Exponential => JitterDistribution::Exponential,
+                });
+            }
+        };
+
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read jitter distribution {}: {e}", path.display());
+        })?;
+
+        let distribution = parse(&text).map_err(|e| {
+            eprintln!("Invalid jitter distribution {}: {e}", path.display());
+        })?;
+
+        Ok(JitterDistribution::Empirical(distribution))
+    }
+}
+
+/// Reads a Mahimahi delivery trace
+fn read_delivery_trace(path: &Path) -> Result<DeliveryTrace, ()> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        eprintln!("Could not read delivery trace {}: {e}", path.display());
+    })?;
+
+    DeliveryTrace::parse(&text).map_err(|e| {
+        eprintln!("Invalid delivery trace {}: {e}", path.display());
+    })
+}
+
+/// Validates the impairments for a single direction
+fn validate_impairments(config: &ImpairmentConfig) -> Result<(), ()> {
+    if config.max_payload_size == 0 {
+        eprintln!("Invalid max payload size: {}", config.max_payload_size);
+        return Err(());
+    }
+
+    match &config.loss {
+        LossModel::Uniform(factor) => {
+            if !(0.0..=1.0).contains(factor) {
+                eprintln!("Invalid loss factor: {factor}");
+                return Err(());
+            }
+        }
+        LossModel::GilbertElliott(ge) => {
+            let probabilities = [ge.good_to_bad, ge.bad_to_good, ge.loss_good, ge.loss_bad];
+
+            if !probabilities.iter().all(|p| (0.0..=1.0).contains(p)) {
+                eprintln!("Invalid burst loss probabilities: {probabilities:?}");
+                return Err(());
+            }
+        }
+    }
+
+    if !(0.0..=1.0).contains(&config.duplicate_factor) {
+        eprintln!("Invalid duplicate factor: {}", config.duplicate_factor);
+        return Err(());
+    }
+
+    if !(0.0..=1.0).contains(&config.reorder_factor) {
+        eprintln!("Invalid reorder factor: {}", config.reorder_factor);
+        return Err(());
+    }
+
+    if !(0.0..=1.0).contains(&config.bit_error_rate) {
+        eprintln!("Invalid bit error rate: {}", config.bit_error_rate);
+        return Err(());
+    }
+
+    if config.corrupt_burst_bits == 0 {
+        eprintln!("Invalid corrupt burst bits: {}", config.corrupt_burst_bits);
+        return Err(());
+    }
+
+    if config.rate_mode == RateMode::Police
+        && config.rate_bits_per_sec.is_some()
+        && config.rate_burst_bytes == 0
+    {
+        eprintln!(
+            "Policing a rate needs a burst size of at least one packet, set with --rate-burst"
+        );
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Parses a [GilbertElliott] model from four comma separated probabilities
+#[allow(clippy::result_large_err, reason = "Not that large")]
+fn parse_gilbert_elliott(arg: &str) -> Result<GilbertElliott, String> {
+    let values = arg
+        .split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let [good_to_bad, bad_to_good, loss_good, loss_bad] = values[..] else {
+        return Err(format!(
+            "expected 4 comma separated values, got {}",
+            values.len()
+        ));
+    };
+
+    Ok(GilbertElliott {
+        good_to_bad,
+        bad_to_good,
+        loss_good,
+        loss_bad,
+    })
 }
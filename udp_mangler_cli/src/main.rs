@@ -1,13 +1,18 @@
 #![doc = include_str!("../README.md")]
 
+use core::net::SocketAddr;
+use std::fs::File;
+use std::net::TcpListener;
+use std::path::Path;
 use std::process::ExitCode;
 use std::sync::Arc;
 
-use args::Args;
+use args::{Args, Command};
 use clap::Parser;
-use udp_mangler::Mangler;
+use udp_mangler::{Direction, Mangler, ManglerConfig};
 
 mod args;
+mod metrics;
 
 fn main() -> ExitCode {
     let args = Args::parse();
@@ -24,7 +29,77 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     };
 
-    let mangler = Arc::new(Mangler::new(args.input, args.output, mangler_config).unwrap());
+    if let Some(Command::Offline {
+        input,
+        output,
+        server,
+    }) = &args.command
+    {
+        if args.scenario.is_some()
+            || args.network_model.is_some()
+            || args.metrics_listen.is_some()
+            || args.replay_traffic.is_some()
+        {
+            log::warn!(
+                "Scenarios, network models, metrics and traffic replay are not used in offline mode"
+            );
+        }
+
+        return offline(input, output, *server, &mangler_config);
+    }
+
+    let Ok(scenario) = args.scenario() else {
+        return ExitCode::FAILURE;
+    };
+
+    let Ok(network_model) = args.network_model(&mangler_config) else {
+        return ExitCode::FAILURE;
+    };
+
+    let Ok(traffic_recording) = args.replay_traffic() else {
+        return ExitCode::FAILURE;
+    };
+
+    let metrics_listener = match args.metrics_listen.map(TcpListener::bind).transpose() {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen for metrics requests: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Both addresses are required without a subcommand
+    let (Some(input), Some(output)) = (args.input, args.output) else {
+        return ExitCode::FAILURE;
+    };
+
+    let mangler = Arc::new(Mangler::new(input, output, mangler_config).unwrap());
+
+    if let Some(listener) = metrics_listener {
+        metrics::serve(listener, mangler.clone());
+    }
+
+    if let Some(scenario) = scenario {
+        mangler.play_scenario(scenario);
+    }
+
+    if let Some(model) = network_model
+        && let Err(e) = mangler.play_network_model(model)
+    {
+        eprintln!("{e}");
+        mangler.stop();
+        _ = mangler.wait_until_complete();
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(recording) = traffic_recording
+        && let Err(e) = mangler.replay_traffic(recording, args.replay_time_scale)
+    {
+        eprintln!("{e}");
+        mangler.stop();
+        _ = mangler.wait_until_complete();
+        return ExitCode::FAILURE;
+    }
 
     let mangler_cloned = mangler.clone();
 
@@ -33,5 +108,88 @@ fn main() -> ExitCode {
 
     mangler.wait_until_complete().unwrap();
 
+    let stats = mangler.stats();
+
+    for direction in [Direction::Upstream, Direction::Downstream] {
+        let direction_stats = stats.direction(direction);
+        let counters = &direction_stats.counters;
+
+        log::info!(
+            "{direction} packets: received {}, forwarded {}, dropped {}, p50 delay {:?}, p99 delay {:?}",
+            counters.received_packets,
+            counters.forwarded_packets,
+            counters.dropped.total(),
+            direction_stats.delay.percentile(0.5),
+            direction_stats.delay.percentile(0.99),
+        );
+
+        for (reason, count) in counters.dropped.iter().filter(|(_, count)| *count != 0) {
+            log::info!("Dropped {direction} packets, {reason}: {count}");
+        }
+
+        let stats = mangler.reorder_stats(direction);
+
+        log::info!(
+            "Reordered {direction} packets: {} of {}, max distance {}, mean distance {:.2}",
+            stats.reordered,
+            stats.packets,
+            stats.max_distance,
+            stats.mean_distance()
+        );
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Mangles the packets of the pcap file `input` and writes them to the pcap file `output`
+fn offline(
+    input: &Path,
+    output: &Path,
+    server: Option<SocketAddr>,
+    config: &ManglerConfig,
+) -> ExitCode {
+    let input_file = match File::open(input) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not open {}: {e}", input.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let output_file = match File::create(output) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not create {}: {e}", output.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let stats = match Mangler::mangle_pcap(input_file, output_file, server, config) {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Could not mangle {}: {e}", input.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for direction in [Direction::Upstream, Direction::Downstream] {
+        let counters = stats.direction(direction);
+
+        log::info!(
+            "{direction} packets: received {}, forwarded {}, dropped {}",
+            counters.received_packets,
+            counters.forwarded_packets,
+            counters.dropped.total(),
+        );
+
+        for (reason, count) in counters.dropped.iter().filter(|(_, count)| *count != 0) {
+            log::info!("Dropped {direction} packets, {reason}: {count}");
+        }
+    }
+
+    if stats.skipped_packets != 0 {
+        log::info!("Skipped {} packets that are not UDP", stats.skipped_packets);
+    }
+
     ExitCode::SUCCESS
 }
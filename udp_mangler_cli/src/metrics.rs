@@ -0,0 +1,267 @@
+//! Prometheus metrics of the mangler, served over a minimal HTTP server
+
+use core::fmt::Write as _;
+use core::time::Duration;
+use std::io::{BufRead, BufReader, Write as _};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+use udp_mangler::{Direction, DropReason, Histogram, ImpairmentConfig, Mangler, ManglerConfig};
+
+/// The upper bounds of the buckets of the delay histograms, in seconds
+const BUCKETS_SECS: [f64; 15] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The content type of the Prometheus text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Both directions, in the order their metrics are written
+const DIRECTIONS: [Direction; 2] = [Direction::Upstream, Direction::Downstream];
+
+/// Serves the metrics of `mangler` to every HTTP client connecting to `listener` on a background thread.
+/// Every connection is answered on a thread of its own, so that a slow client does not hold up the others
+pub(crate) fn serve(listener: TcpListener, mangler: Arc<Mangler>) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("Could not accept metrics request: {e}");
+                    continue;
+                }
+            };
+
+            let mangler = mangler.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = respond(stream, &mangler) {
+                    log::debug!("Metrics request failed: {e}");
+                }
+            });
+        }
+    });
+}
+
+/// Reads a single HTTP request and answers it with the metrics, or with an error if it asks for anything else
+fn respond(stream: TcpStream, mangler: &Mangler) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The headers are not needed, but have to be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => ("200 OK", CONTENT_TYPE, render(mangler)),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("Method not allowed\n"),
+        ),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Renders the metrics of the mangler in the Prometheus text exposition format
+fn render(mangler: &Mangler) -> String {
+    let stats = mangler.stats();
+    let config = mangler.config();
+    let mut out = String::new();
+
+    let mut counter = |name: &str, help: &str, value: &dyn Fn(Direction) -> u64| {
+        header(&mut out, name, "counter", help);
+        for direction in DIRECTIONS {
+            _ = writeln!(
+                out,
+                "{name}{{direction=\"{direction}\"}} {}",
+                value(direction)
+            );
+        }
+    };
+
+    counter(
+        "udp_mangler_received_packets_total",
+        "Packets received by the mangler",
+        &|direction| stats.direction(direction).counters.received_packets,
+    );
+    counter(
+        "udp_mangler_received_bytes_total",
+        "Payload bytes received by the mangler",
+        &|direction| stats.direction(direction).counters.received_bytes,
+    );
+    counter(
+        "udp_mangler_forwarded_packets_total",
+        "Packets sent by the mangler, including duplicates",
+        &|direction| stats.direction(direction).counters.forwarded_packets,
+    );
+    counter(
+        "udp_mangler_forwarded_bytes_total",
+        "Payload bytes sent by the mangler, including duplicates",
+        &|direction| stats.direction(direction).counters.forwarded_bytes,
+    );
+    counter(
+        "udp_mangler_reordered_packets_total",
+        "Packets sent after a packet that arrived later",
+        &|direction| mangler.reorder_stats(direction).reordered,
+    );
+
+    header(
+        &mut out,
+        "udp_mangler_dropped_packets_total",
+        "counter",
+        "Packets dropped by the mangler",
+    );
+    for direction in DIRECTIONS {
+        for reason in DropReason::ALL {
+            _ = writeln!(
+                out,
+                "udp_mangler_dropped_packets_total{{direction=\"{direction}\",reason=\"{}\"}} {}",
+                reason.label(),
+                stats.direction(direction).counters.dropped.get(reason)
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "udp_mangler_queue_depth",
+        "gauge",
+        "Packets waiting in the mangler to be sent",
+    );
+    for direction in DIRECTIONS {
+        _ = writeln!(
+            out,
+            "udp_mangler_queue_depth{{direction=\"{direction}\"}} {}",
+            stats.direction(direction).queue_depth
+        );
+    }
+
+    histogram(
+        &mut out,
+        "udp_mangler_delay_seconds",
+        "Delay added to the packets by the mangler",
+        &|direction| &stats.direction(direction).delay,
+    );
+    histogram(
+        &mut out,
+        "udp_mangler_scheduling_error_seconds",
+        "How much later than scheduled the packets were sent",
+        &|direction| &stats.direction(direction).scheduling_error,
+    );
+
+    render_config(&mut out, &config);
+
+    out
+}
+
+/// Renders the current impairment settings as gauges
+fn render_config(out: &mut String, config: &ManglerConfig) {
+    let mut gauge = |name: &str, help: &str, value: &dyn Fn(&ImpairmentConfig) -> Option<f64>| {
+        header(out, name, "gauge", help);
+        for direction in DIRECTIONS {
+            if let Some(value) = value(config.impairments(direction)) {
+                _ = writeln!(out, "{name}{{direction=\"{direction}\"}} {value}");
+            }
+        }
+    };
+
+    gauge(
+        "udp_mangler_config_ping_seconds",
+        "Configured ping",
+        &|impairments| Some(impairments.ping_secs),
+    );
+    gauge(
+        "udp_mangler_config_jitter_seconds",
+        "Configured jitter",
+        &|impairments| Some(impairments.jitter_secs),
+    );
+    gauge(
+        "udp_mangler_config_loss_ratio",
+        "Configured fraction of packets randomly dropped in the long run",
+        &|impairments| Some(impairments.loss.mean_loss()),
+    );
+    gauge(
+        "udp_mangler_config_duplicate_ratio",
+        "Configured fraction of packets duplicated",
+        &|impairments| Some(impairments.duplicate_factor),
+    );
+    gauge(
+        "udp_mangler_config_reorder_ratio",
+        "Configured fraction of packets explicitly reordered",
+        &|impairments| Some(impairments.reorder_factor),
+    );
+    gauge(
+        "udp_mangler_config_bit_error_rate",
+        "Configured probability of every bit being flipped",
+        &|impairments| Some(impairments.bit_error_rate),
+    );
+    gauge(
+        "udp_mangler_config_rate_bits_per_second",
+        "Configured rate limit, absent without a limit",
+        &|impairments| impairments.rate_bits_per_sec,
+    );
+    gauge(
+        "udp_mangler_config_rate_packets_per_second",
+        "Configured packet rate limit, absent without a limit",
+        &|impairments| impairments.rate_packets_per_sec,
+    );
+}
+
+/// Writes the help and type lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Writes a histogram metric for both directions
+fn histogram<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histogram: &dyn Fn(Direction) -> &'a Histogram,
+) {
+    header(out, name, "histogram", help);
+
+    for direction in DIRECTIONS {
+        let histogram = histogram(direction);
+
+        for bound in BUCKETS_SECS {
+            _ = writeln!(
+                out,
+                "{name}_bucket{{direction=\"{direction}\",le=\"{bound}\"}} {}",
+                histogram.count_at_most(Duration::from_secs_f64(bound))
+            );
+        }
+
+        _ = writeln!(
+            out,
+            "{name}_bucket{{direction=\"{direction}\",le=\"+Inf\"}} {}",
+            histogram.count()
+        );
+        _ = writeln!(
+            out,
+            "{name}_sum{{direction=\"{direction}\"}} {}",
+            histogram.sum().as_secs_f64()
+        );
+        _ = writeln!(
+            out,
+            "{name}_count{{direction=\"{direction}\"}} {}",
+            histogram.count()
+        );
+    }
+}
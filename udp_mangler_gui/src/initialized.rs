@@ -1,7 +1,12 @@
 //! UI State for when the mangler is initialized
 
-use eframe::egui::{self, DragValue, Label, Slider, Vec2, Widget};
-use udp_mangler::{Mangler, ManglerConfig};
+use core::time::Duration;
+
+use eframe::egui::{self, Checkbox, ComboBox, DragValue, Label, Slider, Vec2, Widget};
+use udp_mangler::{
+    Direction, ImpairmentConfig, JitterDistribution, LossModel, Mangler, ManglerConfig,
+    OversizeMode, RateMode, ReorderMode,
+};
 
 use crate::AppState;
 use crate::uninitialized::Uninitialized;
@@ -26,6 +31,25 @@ impl Initialized {
             self.config = new_config;
         }
 
+        ui.add_space(16.0);
+
+        ui.label(format!("Random seed: {}", self.mangler.seed()));
+
+        for direction in [Direction::Upstream, Direction::Downstream] {
+            let stats = self.mangler.reorder_stats(direction);
+
+            ui.label(format!(
+                "Reordered {direction}: {} of {} packets, max distance {}, mean distance {:.2}",
+                stats.reordered,
+                stats.packets,
+                stats.max_distance,
+                stats.mean_distance()
+            ));
+        }
+
+        // Keep the statistics up to date, even without any user input
+        ui.ctx().request_repaint_after(Duration::from_millis(500));
+
         ui.add_space(30.0);
 
         if ui.button("Reset").clicked() {
@@ -38,16 +62,6 @@ impl Initialized {
 }
 
 fn mangler_ui(ui: &mut egui::Ui, config: &ManglerConfig) -> Option<ManglerConfig> {
-    const LABEL_SIZE: Vec2 = Vec2::new(125.0, 32.0);
-
-    fn add_input_field(ui: &mut egui::Ui, label: &str, field: impl Widget) -> egui::Response {
-        ui.horizontal(|ui| {
-            ui.add_sized(LABEL_SIZE, Label::new(label));
-            ui.add(field)
-        })
-        .inner
-    }
-
     let mut new_config = config.clone();
     let mut any_changed = false;
 
@@ -58,29 +72,350 @@ fn mangler_ui(ui: &mut egui::Ui, config: &ManglerConfig) -> Option<ManglerConfig
     )
     .changed();
 
+    any_changed |= add_input_field(
+        ui,
+        "Session timeout (s)",
+        DragValue::new(&mut new_config.session_timeout_secs).range(1.0..=f64::MAX),
+    )
+    .changed();
+
+    ui.add_space(16.0);
+
+    ui.columns(2, |columns| {
+        for (ui, direction) in columns
+            .iter_mut()
+            .zip([Direction::Upstream, Direction::Downstream])
+        {
+            ui.push_id(direction, |ui| {
+                ui.heading(match direction {
+                    Direction::Upstream => "Upstream",
+                    Direction::Downstream => "Downstream",
+                });
+
+                any_changed |= impairment_ui(ui, new_config.impairments_mut(direction));
+            });
+        }
+    });
+
+    if any_changed { Some(new_config) } else { None }
+}
+
+/// Shows the input fields for the impairments of a single direction.
+/// Returns whether any of them were changed
+fn impairment_ui(ui: &mut egui::Ui, config: &mut ImpairmentConfig) -> bool {
+    let mut any_changed = false;
+
     any_changed |= add_input_field(
         ui,
         "Maximum payload size",
-        DragValue::new(&mut new_config.max_payload_size),
+        DragValue::new(&mut config.max_payload_size),
     )
     .changed();
 
+    let mut fragment = config.oversize_mode == OversizeMode::Fragment;
     any_changed |= add_input_field(
         ui,
-        "Loss factor",
-        Slider::new(&mut new_config.loss_factor, 0.0..=1.0),
+        "Fragment oversized",
+        Checkbox::without_text(&mut fragment),
     )
     .changed();
 
-    let mut ping_ms = (new_config.ping_secs * 1000.0) as usize;
+    config.oversize_mode = if fragment {
+        OversizeMode::Fragment
+    } else {
+        OversizeMode::Drop
+    };
+
+    any_changed |= loss_ui(ui, &mut config.loss);
+
+    let mut ping_ms = (config.ping_secs * 1000.0) as usize;
     any_changed |= add_input_field(ui, "Ping (ms)", DragValue::new(&mut ping_ms)).changed();
 
-    new_config.ping_secs = (ping_ms as f64) / 1000.0;
+    config.ping_secs = (ping_ms as f64) / 1000.0;
 
-    let mut jitter_ms = (new_config.jitter_secs * 1000.0) as usize;
+    let mut jitter_ms = (config.jitter_secs * 1000.0) as usize;
     any_changed |= add_input_field(ui, "Jitter (ms)", DragValue::new(&mut jitter_ms)).changed();
 
-    new_config.jitter_secs = (jitter_ms as f64) / 1000.0;
+    config.jitter_secs = (jitter_ms as f64) / 1000.0;
 
-    if any_changed { Some(new_config) } else { None }
+    any_changed |= jitter_distribution_ui(ui, &mut config.jitter_distribution);
+
+    any_changed |= add_input_field(
+        ui,
+        "Preserve order",
+        Checkbox::without_text(&mut config.preserve_order),
+    )
+    .changed();
+
+    any_changed |= add_input_field(
+        ui,
+        "Duplicate factor",
+        Slider::new(&mut config.duplicate_factor, 0.0..=1.0),
+    )
+    .changed();
+
+    any_changed |= add_input_field(
+        ui,
+        "Duplicate copies",
+        DragValue::new(&mut config.duplicate_copies).range(1..=usize::MAX),
+    )
+    .changed();
+
+    let mut duplicate_delay_ms = (config.duplicate_delay_secs * 1000.0) as usize;
+    any_changed |= add_input_field(
+        ui,
+        "Duplicate delay (ms)",
+        DragValue::new(&mut duplicate_delay_ms),
+    )
+    .changed();
+
+    config.duplicate_delay_secs = (duplicate_delay_ms as f64) / 1000.0;
+
+    any_changed |= add_input_field(
+        ui,
+        "Reorder factor",
+        Slider::new(&mut config.reorder_factor, 0.0..=1.0),
+    )
+    .changed();
+
+    any_changed |= reorder_mode_ui(ui, &mut config.reorder_mode);
+
+    any_changed |= add_input_field(
+        ui,
+        "Bit error rate",
+        Slider::new(&mut config.bit_error_rate, 0.0..=0.01).logarithmic(true),
+    )
+    .changed();
+
+    any_changed |= add_input_field(
+        ui,
+        "Corrupt offset",
+        DragValue::new(&mut config.corrupt_offset),
+    )
+    .changed();
+
+    any_changed |= add_input_field(
+        ui,
+        "Corrupt burst bits",
+        DragValue::new(&mut config.corrupt_burst_bits).range(1..=usize::MAX),
+    )
+    .changed();
+
+    any_changed |= rate_ui(ui, config);
+
+    any_changed
+}
+
+/// Shows the input fields for the rate limiter.
+/// Returns whether any of them were changed
+fn rate_ui(ui: &mut egui::Ui, config: &mut ImpairmentConfig) -> bool {
+    let mut any_changed = false;
+
+    let mut limited = config.rate_bits_per_sec.is_some();
+    any_changed |=
+        add_input_field(ui, "Limit rate", Checkbox::without_text(&mut limited)).changed();
+
+    if !limited {
+        config.rate_bits_per_sec = None;
+        return any_changed;
+    }
+
+    let mut rate_kbit = config.rate_bits_per_sec.unwrap_or(1_000_000.0) / 1000.0;
+    any_changed |= add_input_field(
+        ui,
+        "Rate (kbit/s)",
+        DragValue::new(&mut rate_kbit).range(1.0..=f64::MAX),
+    )
+    .changed();
+
+    config.rate_bits_per_sec = Some(rate_kbit * 1000.0);
+
+    any_changed |= add_input_field(
+        ui,
+        "Burst (bytes)",
+        DragValue::new(&mut config.rate_burst_bytes),
+    )
+    .changed();
+
+    let mut queue_limit = config.rate_queue_limit_bytes.unwrap_or(0);
+    any_changed |= add_input_field(
+        ui,
+        "Queue limit (bytes, 0 = none)",
+        DragValue::new(&mut queue_limit),
+    )
+    .changed();
+
+    config.rate_queue_limit_bytes = (queue_limit != 0).then_some(queue_limit);
+
+    let mut police = config.rate_mode == RateMode::Police;
+    any_changed |= add_input_field(ui, "Police", Checkbox::without_text(&mut police)).changed();
+
+    config.rate_mode = if police {
+        RateMode::Police
+    } else {
+        RateMode::Shape
+    };
+
+    any_changed
+}
+
+/// Shows the input fields for the way reordered packets are held back.
+/// Returns whether any of them were changed
+fn reorder_mode_ui(ui: &mut egui::Ui, mode: &mut ReorderMode) -> bool {
+    let mut any_changed = false;
+
+    let was_delay = matches!(mode, ReorderMode::DelaySecs(_));
+    let mut is_delay = was_delay;
+
+    add_input_field(ui, "Reorder by", |ui: &mut egui::Ui| {
+        ComboBox::from_id_salt("reorder_mode")
+            .selected_text(if is_delay { "Delay" } else { "Positions" })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut is_delay, false, "Positions");
+                ui.selectable_value(&mut is_delay, true, "Delay");
+            })
+            .response
+    });
+
+    any_changed |= is_delay != was_delay;
+
+    match (is_delay, &*mode) {
+        (false, ReorderMode::DelaySecs(_)) => *mode = ReorderMode::default(),
+        (true, ReorderMode::Positions(_)) => *mode = ReorderMode::DelaySecs(0.0),
+        _ => (),
+    }
+
+    match mode {
+        ReorderMode::Positions(positions) => {
+            any_changed |= add_input_field(
+                ui,
+                "Reorder positions",
+                DragValue::new(positions).range(1..=usize::MAX),
+            )
+            .changed();
+        }
+        ReorderMode::DelaySecs(delay) => {
+            let mut delay_ms = (*delay * 1000.0) as usize;
+            any_changed |=
+                add_input_field(ui, "Reorder delay (ms)", DragValue::new(&mut delay_ms)).changed();
+
+            *delay = (delay_ms as f64) / 1000.0;
+        }
+    }
+
+    any_changed
+}
+
+/// Shows the input fields for the jitter distribution, including a selector for the kind of distribution.
+/// Returns whether any of them were changed
+fn jitter_distribution_ui(ui: &mut egui::Ui, distribution: &mut JitterDistribution) -> bool {
+    /// The shape of newly selected Pareto distributions
+    const DEFAULT_SHAPE: f64 = 3.0;
+
+    let name = |distribution: &JitterDistribution| match distribution {
+        JitterDistribution::Uniform => "Uniform",
+        JitterDistribution::Normal => "Normal",
+        JitterDistribution::Pareto { .. } => "Pareto",
+        JitterDistribution::ParetoNormal { .. } => "Pareto normal",
+        JitterDistribution::Exponential => "Exponential",
+        JitterDistribution::Empirical(_) => "Measured",
+    };
+
+    let mut any_changed = false;
+    let was = name(distribution);
+
+    add_input_field(ui, "Jitter distribution", |ui: &mut egui::Ui| {
+        ComboBox::from_id_salt("jitter_distribution")
+            .selected_text(was)
+            .show_ui(ui, |ui| {
+                for selectable in [
+                    JitterDistribution::Uniform,
+                    JitterDistribution::Normal,
+                    JitterDistribution::Pareto {
+                        shape: DEFAULT_SHAPE,
+                    },
+                    JitterDistribution::ParetoNormal {
+                        shape: DEFAULT_SHAPE,
+                    },
+                    JitterDistribution::Exponential,
+                ] {
+                    let label = name(&selectable);
+                    if ui.selectable_label(was == label, label).clicked() && was != label {
+                        *distribution = selectable;
+                        any_changed = true;
+                    }
+                }
+            })
+            .response
+    });
+
+    if let JitterDistribution::Pareto { shape } | JitterDistribution::ParetoNormal { shape } =
+        distribution
+    {
+        any_changed |= add_input_field(
+            ui,
+            "Pareto shape",
+            DragValue::new(shape).range(1.01..=100.0).speed(0.05),
+        )
+        .changed();
+    }
+
+    any_changed
+}
+
+/// Shows the input fields for a loss model, including a selector for the kind of model.
+/// Returns whether any of them were changed
+fn loss_ui(ui: &mut egui::Ui, loss: &mut LossModel) -> bool {
+    let mut any_changed = false;
+
+    let was_burst = matches!(loss, LossModel::GilbertElliott(_));
+    let mut is_burst = was_burst;
+
+    add_input_field(ui, "Loss model", |ui: &mut egui::Ui| {
+        ComboBox::from_id_salt("loss_model")
+            .selected_text(if is_burst { "Burst" } else { "Uniform" })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut is_burst, false, "Uniform");
+                ui.selectable_value(&mut is_burst, true, "Burst");
+            })
+            .response
+    });
+
+    any_changed |= is_burst != was_burst;
+
+    match (is_burst, &*loss) {
+        (false, LossModel::GilbertElliott(_)) => *loss = LossModel::default(),
+        (true, LossModel::Uniform(_)) => *loss = LossModel::GilbertElliott(Default::default()),
+        _ => (),
+    }
+
+    match loss {
+        LossModel::Uniform(factor) => {
+            any_changed |=
+                add_input_field(ui, "Loss factor", Slider::new(factor, 0.0..=1.0)).changed();
+        }
+        LossModel::GilbertElliott(ge) => {
+            for (label, value) in [
+                ("Good to bad", &mut ge.good_to_bad),
+                ("Bad to good", &mut ge.bad_to_good),
+                ("Loss when good", &mut ge.loss_good),
+                ("Loss when bad", &mut ge.loss_bad),
+            ] {
+                any_changed |= add_input_field(ui, label, Slider::new(value, 0.0..=1.0)).changed();
+            }
+        }
+    }
+
+    any_changed
+}
+
+/// Adds a single labeled input field
+fn add_input_field(ui: &mut egui::Ui, label: &str, field: impl Widget) -> egui::Response {
+    const LABEL_SIZE: Vec2 = Vec2::new(125.0, 32.0);
+
+    ui.horizontal(|ui| {
+        ui.add_sized(LABEL_SIZE, Label::new(label));
+        ui.add(field)
+    })
+    .inner
 }
@@ -29,7 +29,7 @@ enum AppState {
     Uninitialized(Uninitialized),
 
     /// Mangler initialized
-    Initialized(Initialized),
+    Initialized(Box<Initialized>),
 }
 
 impl AppState {
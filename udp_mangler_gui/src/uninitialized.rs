@@ -39,7 +39,9 @@ impl Uninitialized {
         if ui.button("Start").clicked() {
             match try_start_mangler(&self.listen_addr_string, &self.forward_addr_string) {
                 Ok((mangler, config)) => {
-                    return Some(AppState::Initialized(Initialized::new(mangler, config)));
+                    return Some(AppState::Initialized(Box::new(Initialized::new(
+                        mangler, config,
+                    ))));
                 }
                 Err(e) => {
                     self.error_string = Some(e.to_string());